@@ -0,0 +1,77 @@
+// The cost of mixing one host block from many clients at once, through an offline receiver so no
+// sockets are involved. Run with `cargo bench --bench mix`. Handing the packets over takes a good
+// part of each run, so the `queue` benches time that alone to take away from the rest.
+
+#![feature(test)]
+
+extern crate feedback;
+extern crate test;
+
+use feedback::{FeedbackReceiver, Packet};
+use feedback::config::Config;
+use std::sync::mpsc::sync_channel;
+use test::Bencher;
+
+fn mix(b: &mut Bencher, clients: u64, block: usize) {
+    let mut config = Config::default();
+    config.receive_queue = clients as usize * 2;
+    config.max_clients = clients as usize;
+    let (mut receiver, packets) = FeedbackReceiver::offline(config);
+    let samples: Vec<f32> = (0..block).map(|i| (i as f32 * 0.01).sin()).collect();
+    let mut left = vec![0.0; block];
+    let mut right = vec![0.0; block];
+    let mut time = 0;
+    b.bytes = clients * block as u64 * 8;
+    b.iter(|| {
+        let packet = Packet::new(&samples, &samples, time);
+        for client in 0..clients {
+            packets.send((client, packet.clone())).unwrap();
+        }
+        time += block as u64;
+        assert!(receiver.pull(&mut left, &mut right));
+    });
+}
+
+#[bench]
+fn bench_mix_16_clients_1024(b: &mut Bencher) {
+    mix(b, 16, 1024);
+}
+
+#[bench]
+fn bench_mix_16_clients_8192(b: &mut Bencher) {
+    mix(b, 16, 8192);
+}
+
+#[bench]
+fn bench_mix_32_clients_4096(b: &mut Bencher) {
+    mix(b, 32, 4096);
+}
+
+#[bench]
+fn bench_mix_64_clients_8192(b: &mut Bencher) {
+    mix(b, 64, 8192);
+}
+
+fn queue(b: &mut Bencher, clients: u64, block: usize) {
+    let (packets, received) = sync_channel(clients as usize * 2);
+    let samples: Vec<f32> = (0..block).map(|i| (i as f32 * 0.01).sin()).collect();
+    b.iter(|| {
+        let packet = Packet::new(&samples, &samples, 0);
+        for client in 0..clients {
+            packets.send((client, packet.clone())).unwrap();
+        }
+        while let Ok(packet) = received.try_recv() {
+            test::black_box(packet);
+        }
+    });
+}
+
+#[bench]
+fn bench_queue_16_clients_8192(b: &mut Bencher) {
+    queue(b, 16, 8192);
+}
+
+#[bench]
+fn bench_queue_64_clients_8192(b: &mut Bencher) {
+    queue(b, 64, 8192);
+}
@@ -0,0 +1,70 @@
+// The per-block cost of putting audio on the wire and taking it off again, for a block of the
+// default size. Run with `cargo bench --bench packet`. The bincode benchmarks are how packets were
+// sent before they had a fixed layout, kept for comparison.
+
+#![feature(test)]
+
+extern crate bincode;
+extern crate feedback;
+extern crate test;
+
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, decode};
+use feedback::Packet;
+use test::Bencher;
+
+const BLOCK: usize = 1024;
+
+fn block() -> Packet {
+    let left: Vec<f32> = (0..BLOCK).map(|i| (i as f32 * 0.01).sin()).collect();
+    let right: Vec<f32> = (0..BLOCK).map(|i| (i as f32 * 0.02).cos()).collect();
+    Packet::new(&left, &right, 1 << 20)
+}
+
+#[bench]
+fn bench_as_bytes(b: &mut Bencher) {
+    let packet = block();
+    b.bytes = (BLOCK * 8) as u64;
+    b.iter(|| packet.as_bytes());
+}
+
+#[bench]
+fn bench_parse(b: &mut Bencher) {
+    let bytes = block().as_bytes();
+    b.bytes = (BLOCK * 8) as u64;
+    b.iter(|| Packet::parse(&bytes));
+}
+
+#[bench]
+fn bench_write_to(b: &mut Bencher) {
+    let packet = block();
+    let mut out = Vec::new();
+    b.bytes = (BLOCK * 8) as u64;
+    b.iter(|| {
+        out.clear();
+        packet.write_to(&mut out);
+        out.len()
+    });
+}
+
+#[bench]
+fn bench_parse_into(b: &mut Bencher) {
+    let bytes = block().as_bytes();
+    let mut packet = Packet::zeroed(0, BLOCK);
+    b.bytes = (BLOCK * 8) as u64;
+    b.iter(|| packet.parse_into(&bytes).unwrap());
+}
+
+#[bench]
+fn bench_bincode_encode(b: &mut Bencher) {
+    let packet = block();
+    b.bytes = (BLOCK * 8) as u64;
+    b.iter(|| encode(&packet, SizeLimit::Infinite).unwrap());
+}
+
+#[bench]
+fn bench_bincode_decode(b: &mut Bencher) {
+    let bytes = encode(&block(), SizeLimit::Infinite).unwrap();
+    b.bytes = (BLOCK * 8) as u64;
+    b.iter(|| decode::<Packet>(&bytes).unwrap());
+}
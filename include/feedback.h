@@ -0,0 +1,93 @@
+#ifndef FEEDBACK_H
+#define FEEDBACK_H
+
+/* Generated by cbindgen from src/capi.rs. Don't edit by hand. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+// Senders and receivers are opaque. Each must only be used from one thread at a time, and closed
+// exactly once. Functions that can fail return a negative number when they do: -1 for a null
+// pointer, and -2 if the library panicked. Functions returning a pointer return null instead.
+typedef struct FeedbackSender FeedbackSender;
+typedef struct FeedbackReceiver FeedbackReceiver;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Opens a sender on `channel` of `session` for audio at `sample_rate`, with the settings from
+// the config file and environment, and connects it. Returns null if the channel or session is
+// out of range.
+struct FeedbackSender *feedback_sender_open(uint16_t channel,
+                                            uint16_t session,
+                                            uint64_t sample_rate);
+
+// Hangs up and frees a sender. Null is ignored.
+void feedback_sender_close(struct FeedbackSender *sender);
+
+// Sends `frames` samples from each of `left` and `right`. Returns 0, or -1 if a pointer is
+// null.
+int feedback_sender_send(struct FeedbackSender *sender,
+                         const float *left,
+                         const float *right,
+                         size_t frames);
+
+// Sends `frames` stereo frames from `samples`, left first. Returns 0, or -1 if a pointer is
+// null or there are too many frames to address.
+int feedback_sender_send_interleaved(struct FeedbackSender *sender,
+                                     const float *samples,
+                                     size_t frames);
+
+// Whether a sender has got through to a receiver, so what it sends arrives: 1 if it has, 0 if
+// it is still trying, or -1 if it's null.
+int feedback_sender_connected(const struct FeedbackSender *sender);
+
+// How many samples behind a sender the receivers on its channel are playing, or -1 if it's
+// null. See `FeedbackSender::receiver_latency`.
+int64_t feedback_sender_receiver_latency(const struct FeedbackSender *sender);
+
+// Opens a receiver on `channel` of `session` for audio at `sample_rate`, with the settings from
+// the config file and environment, and starts listening. Returns null if the channel or session
+// is out of range.
+struct FeedbackReceiver *feedback_receiver_open(uint16_t channel,
+                                                uint16_t session,
+                                                uint64_t sample_rate);
+
+// Stops listening and frees a receiver. Null is ignored.
+void feedback_receiver_close(struct FeedbackReceiver *receiver);
+
+// Plays the next `frames` samples into each of `left` and `right`. Returns 1 if there was
+// audio, 0 if not enough had arrived and the buffers were filled with silence, or -1 if a
+// pointer is null.
+int feedback_receiver_receive(struct FeedbackReceiver *receiver,
+                              float *left,
+                              float *right,
+                              size_t frames);
+
+// Plays the next `frames` stereo frames into `samples`, left first. Returns like
+// `feedback_receiver_receive`, or -1 if there are too many frames to address.
+int feedback_receiver_receive_interleaved(struct FeedbackReceiver *receiver,
+                                          float *samples,
+                                          size_t frames);
+
+// How many transmitters are connected to a receiver, or -1 if it's null.
+int feedback_receiver_clients(const struct FeedbackReceiver *receiver);
+
+// How many blocks a receiver has played as silence while someone was connected, or -1 if it's
+// null.
+int64_t feedback_receiver_underruns(const struct FeedbackReceiver *receiver);
+
+// How many samples behind its transmitters a receiver is playing, or -1 if it's null. See
+// `FeedbackReceiver::latency`.
+int64_t feedback_receiver_latency(const struct FeedbackReceiver *receiver);
+
+// How many transmitters a receiver has hung up on for sending something it couldn't read, or
+// -1 if it's null.
+int64_t feedback_receiver_rejected(const struct FeedbackReceiver *receiver);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* FEEDBACK_H */
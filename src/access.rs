@@ -0,0 +1,399 @@
+// Decides which clients a receiver will talk to. Every client costs the host a socket and a share
+// of the reactor's time, so both who may connect and how many may be connected at once are
+// limited.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use mio::tcp::{TcpStream, Shutdown};
+
+use super::net::unmap;
+
+/// How many clients can be shaking hands with one receiver at once. They don't count against
+/// `max_clients` until they're done, and can't push anyone out, so a stranger who can reach the
+/// port but doesn't hold the key can't take the place of a transmitter that does.
+pub const MAX_PENDING: usize = 8;
+
+/// A network in CIDR notation, like `192.168.1.0/24`. A bare address matches only itself.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+fn octets(addr: &IpAddr) -> Vec<u8> {
+    match *addr {
+        IpAddr::V4(ref addr) => addr.octets().to_vec(),
+        IpAddr::V6(ref addr) => addr.octets().to_vec(),
+    }
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Result<Cidr, String> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr: IpAddr = try!(parts.next()
+                                     .unwrap()
+                                     .parse()
+                                     .map_err(|_| format!("bad address in {}", s)));
+        let max = octets(&addr).len() as u32 * 8;
+        let prefix = match parts.next() {
+            Some(prefix) => {
+                try!(prefix.parse::<u32>()
+                           .ok()
+                           .and_then(|p| if p <= max { Some(p) } else { None })
+                           .ok_or(format!("bad prefix length in {}", s)))
+            }
+            None => max,
+        };
+        Ok(Cidr {
+            addr: addr,
+            prefix: prefix,
+        })
+    }
+
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let (net, addr) = (octets(&self.addr), octets(addr));
+        if net.len() != addr.len() {
+            return false;
+        }
+        let mut bits = self.prefix;
+        for (a, b) in net.iter().zip(addr.iter()) {
+            if bits == 0 {
+                break;
+            }
+            let mask = if bits >= 8 {
+                0xff
+            } else {
+                !(0xff_u8 >> bits)
+            };
+            if a & mask != b & mask {
+                return false;
+            }
+            bits = bits.saturating_sub(8);
+        }
+        true
+    }
+}
+
+/// What a receiver does with a new client once it's already at its limit.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LimitPolicy {
+    /// Turn the newcomer away.
+    Refuse,
+    /// Hang up on the longest-connected client to make room, so a transmitter that reconnects
+    /// isn't locked out by its own stale connection.
+    Replace,
+}
+
+/// Hangs up on a client.
+pub type Hangup = Box<FnMut() + Send>;
+
+struct Client {
+    peer: SocketAddr,
+    hangup: Hangup,
+}
+
+struct Clients {
+    connected: BTreeMap<u64, Client>,
+    // how many are still shaking hands
+    pending: usize,
+    // told the id of each client that leaves
+    departures: Option<Sender<u64>>,
+}
+
+impl Clients {
+    fn remove(&mut self, client_id: u64, count: &AtomicUsize) -> Option<Client> {
+        let client = self.connected.remove(&client_id);
+        if client.is_some() {
+            count.store(self.connected.len(), Ordering::Relaxed);
+            if let Some(ref departures) = self.departures {
+                let _ = departures.send(client_id);
+            }
+        }
+        client
+    }
+}
+
+/// Tracks the clients connected to one receiver. Clones share the same set of clients.
+#[derive(Clone)]
+pub struct Gate {
+    allow: Option<Vec<Cidr>>,
+    max_clients: usize,
+    policy: LimitPolicy,
+    clients: Arc<Mutex<Clients>>,
+    // how many are connected, readable without the lock
+    count: Arc<AtomicUsize>,
+}
+
+impl Gate {
+    pub fn new(allow: Option<Vec<Cidr>>, max_clients: usize, policy: LimitPolicy) -> Gate {
+        Gate {
+            allow: allow,
+            max_clients: max_clients,
+            policy: policy,
+            clients: Arc::new(Mutex::new(Clients {
+                connected: BTreeMap::new(),
+                pending: 0,
+                departures: None,
+            })),
+            count: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Hands over the id of every client that leaves from now on, so the audio thread can find
+    /// out without taking the lock. Only the last receiver asked for is told.
+    pub fn departures(&self) -> Receiver<u64> {
+        let (departures_tx, departures_rx) = channel();
+        self.clients.lock().unwrap().departures = Some(departures_tx);
+        departures_rx
+    }
+
+    /// Decides whether to start a handshake with a new client, giving the reason if not. Without
+    /// an allowlist, anyone who can reach the bind address is let in, as long as no more than
+    /// `MAX_PENDING` clients are shaking hands. The client only takes a slot once its handshake is
+    /// done and `Pass::enter` is called, and keeps it until the returned pass is dropped.
+    pub fn admit(&self,
+                 client_id: u64,
+                 peer: &SocketAddr,
+                 socket: &TcpStream)
+                 -> Result<Pass, String> {
+        let handle = try!(socket.try_clone().map_err(|e| e.to_string()));
+        self.admit_with(client_id,
+                        peer,
+                        Box::new(move || {
+                            let _ = handle.shutdown(Shutdown::Both);
+                        }))
+    }
+
+    /// Like `admit`, for clients that aren't on a socket. `hangup` is called if the client is
+    /// pushed out to make room for another.
+    pub fn admit_with(&self,
+                      client_id: u64,
+                      peer: &SocketAddr,
+                      hangup: Hangup)
+                      -> Result<Pass, String> {
+        if let Some(ref allow) = self.allow {
+            let ip = unmap(peer.ip());
+            if !allow.iter().any(|net| net.contains(&ip)) {
+                return Err(format!("{} is not in the allowlist", ip));
+            }
+        }
+        let mut clients = self.clients.lock().unwrap();
+        if clients.pending >= MAX_PENDING {
+            return Err(format!("already shaking hands with {} clients", clients.pending));
+        }
+        clients.pending += 1;
+        Ok(Pass {
+            gate: self.clone(),
+            client_id: client_id,
+            pending: Some(Client {
+                peer: *peer,
+                hangup: hangup,
+            }),
+        })
+    }
+
+    /// How many clients have finished their handshake and are connected.
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The clients connected right now and where they are connecting from.
+    pub fn peers(&self) -> Vec<(u64, Option<SocketAddr>)> {
+        self.clients
+            .lock()
+            .unwrap()
+            .connected
+            .iter()
+            .map(|(&client_id, client)| (client_id, Some(client.peer)))
+            .collect()
+    }
+}
+
+/// Held by a client's connection for as long as it is connected.
+pub struct Pass {
+    gate: Gate,
+    client_id: u64,
+    // the client, until it has entered
+    pending: Option<Client>,
+}
+
+impl Pass {
+    /// Counts the client against the gate's limit, once it has finished its handshake. If the
+    /// gate is full, this gives the reason the client is turned away, or makes room for it by
+    /// hanging up on the longest-connected client.
+    pub fn enter(&mut self) -> Result<(), String> {
+        let gate = &self.gate;
+        let mut clients = gate.clients.lock().unwrap();
+        let client = match self.pending.take() {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+        clients.pending -= 1;
+        if clients.connected.len() >= gate.max_clients {
+            match gate.policy {
+                LimitPolicy::Refuse => {
+                    return Err(format!("already serving {} clients", clients.connected.len()));
+                }
+                LimitPolicy::Replace => {
+                    let oldest = *clients.connected.keys().next().unwrap();
+                    log!(Info, "dropping client {} to make room", oldest);
+                    (clients.remove(oldest, &gate.count).unwrap().hangup)();
+                }
+            }
+        }
+        clients.connected.insert(self.client_id, client);
+        gate.count.store(clients.connected.len(), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for Pass {
+    fn drop(&mut self) {
+        let mut clients = self.gate.clients.lock().unwrap();
+        if self.pending.is_some() {
+            clients.pending -= 1;
+        } else {
+            clients.remove(self.client_id, &self.gate.count);
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_client(listener: &::std::net::TcpListener) -> (SocketAddr, TcpStream) {
+    let stream = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+    let (_, peer) = listener.accept().unwrap();
+    (peer, stream)
+}
+
+// lets a client in as if it had finished its handshake
+#[cfg(test)]
+fn test_enter(gate: &Gate,
+              client_id: u64,
+              client: &(SocketAddr, TcpStream))
+              -> Result<Pass, String> {
+    let mut pass = try!(gate.admit(client_id, &client.0, &client.1));
+    try!(pass.enter());
+    Ok(pass)
+}
+
+#[test]
+fn test_cidr() {
+    let net = Cidr::parse("192.168.1.0/24").unwrap();
+    assert!(net.contains(&"192.168.1.77".parse().unwrap()));
+    assert!(!net.contains(&"192.168.2.1".parse().unwrap()));
+    assert!(!net.contains(&"::1".parse().unwrap()));
+
+    let net = Cidr::parse("10.128.0.0/9").unwrap();
+    assert!(net.contains(&"10.200.0.1".parse().unwrap()));
+    assert!(!net.contains(&"10.100.0.1".parse().unwrap()));
+
+    let host = Cidr::parse("127.0.0.1").unwrap();
+    assert!(host.contains(&"127.0.0.1".parse().unwrap()));
+    assert!(!host.contains(&"127.0.0.2".parse().unwrap()));
+    assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&"8.8.8.8".parse().unwrap()));
+
+    assert!(Cidr::parse("10.0.0.0/33").is_err());
+    assert!(Cidr::parse("10.0.0/8").is_err());
+    assert!(Cidr::parse("10.0.0.0/x").is_err());
+}
+
+#[test]
+fn test_gate_allowlist() {
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let (peer, stream) = test_client(&listener);
+    let gate = Gate::new(Some(vec![Cidr::parse("10.0.0.0/8").unwrap()]), 4, LimitPolicy::Refuse);
+    assert!(gate.admit(0, &peer, &stream).is_err());
+    let gate = Gate::new(Some(vec![Cidr::parse("127.0.0.0/8").unwrap()]), 4, LimitPolicy::Refuse);
+    let mut pass = gate.admit(0, &peer, &stream).unwrap();
+    assert_eq!(gate.len(), 0);
+    pass.enter().unwrap();
+    assert_eq!(gate.len(), 1);
+    drop(pass);
+    assert_eq!(gate.len(), 0);
+    // IPv4 clients of a dual-stack receiver show up as mapped IPv6 addresses
+    let mapped = "[::ffff:127.0.0.1]:5000".parse().unwrap();
+    assert!(gate.admit(0, &mapped, &stream).is_ok());
+    // an allowlist with nothing in it lets nobody in
+    let gate = Gate::new(Some(Vec::new()), 4, LimitPolicy::Refuse);
+    assert!(gate.admit(0, &peer, &stream).is_err());
+}
+
+#[test]
+fn test_gate_limit() {
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let clients: Vec<_> = (0..3).map(|_| test_client(&listener)).collect();
+
+    let gate = Gate::new(None, 2, LimitPolicy::Refuse);
+    let first = test_enter(&gate, 0, &clients[0]).unwrap();
+    let _second = test_enter(&gate, 1, &clients[1]).unwrap();
+    assert!(test_enter(&gate, 2, &clients[2]).is_err());
+    drop(first);
+    let _third = test_enter(&gate, 2, &clients[2]).unwrap();
+    assert_eq!(gate.len(), 2);
+
+    let gate = Gate::new(None, 2, LimitPolicy::Replace);
+    let first = test_enter(&gate, 0, &clients[0]).unwrap();
+    let _second = test_enter(&gate, 1, &clients[1]).unwrap();
+    let _third = test_enter(&gate, 2, &clients[2]).unwrap();
+    assert_eq!(gate.len(), 2);
+    // the oldest client was already pushed out, so its connection closing frees nothing
+    drop(first);
+    assert_eq!(gate.len(), 2);
+}
+
+#[test]
+fn test_gate_pending() {
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let clients: Vec<_> = (0..2).map(|_| test_client(&listener)).collect();
+    let (peer, stream) = test_client(&listener);
+    let gate = Gate::new(None, 2, LimitPolicy::Replace);
+    let departures = gate.departures();
+    let _first = test_enter(&gate, 0, &clients[0]).unwrap();
+    let _second = test_enter(&gate, 1, &clients[1]).unwrap();
+
+    // clients still shaking hands push nobody out, and only so many are let in at once
+    let mut pending: Vec<_> = (0..MAX_PENDING as u64)
+                                  .map(|i| gate.admit(2 + i, &peer, &stream).unwrap())
+                                  .collect();
+    assert!(gate.admit(100, &peer, &stream).is_err());
+    assert_eq!(gate.len(), 2);
+    assert!(departures.try_recv().is_err());
+    // one that gives up makes room for another
+    pending.pop();
+    let _another = gate.admit(100, &peer, &stream).unwrap();
+
+    // one that finishes its handshake takes the oldest client's place
+    pending[0].enter().unwrap();
+    assert_eq!(gate.len(), 2);
+    assert_eq!(departures.try_recv(), Ok(0));
+    assert_eq!(gate.peers().iter().map(|&(id, _)| id).collect::<Vec<_>>(), [1, 2]);
+
+    // and when full clients are refused, it's turned away once it's done
+    let gate = Gate::new(None, 1, LimitPolicy::Refuse);
+    let _first = test_enter(&gate, 0, &clients[0]).unwrap();
+    let mut pass = gate.admit(1, &peer, &stream).unwrap();
+    assert!(pass.enter().is_err());
+    drop(pass);
+    assert_eq!(gate.len(), 1);
+}
+
+#[test]
+fn test_gate_departures() {
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let clients: Vec<_> = (0..3).map(|_| test_client(&listener)).collect();
+    let gate = Gate::new(None, 2, LimitPolicy::Replace);
+    let departures = gate.departures();
+    let first = test_enter(&gate, 0, &clients[0]).unwrap();
+    let second = test_enter(&gate, 1, &clients[1]).unwrap();
+    let _third = test_enter(&gate, 2, &clients[2]).unwrap();
+    drop(second);
+    // each is told once, including one pushed out to make room
+    drop(first);
+    assert_eq!(departures.try_recv(), Ok(0));
+    assert_eq!(departures.try_recv(), Ok(1));
+    assert!(departures.try_recv().is_err());
+}
@@ -0,0 +1,109 @@
+// Runs chains of LADSPA plugins without a DAW, as described in a spec file. See the host module
+// for what goes in a spec.
+
+extern crate feedback;
+
+use std::env;
+use std::path::Path;
+use std::process;
+
+use feedback::host::{self, Library, Spec};
+
+const USAGE: &'static str = "usage:
+    feedback-host [--library PATH] [--output FILE.wav] SPEC.toml
+                              run the chain in a spec, and check it against the expected output
+    feedback-host [--library PATH] --list
+                              list the plugins in a library";
+
+// the library to use if none is given: one on LADSPA_PATH, or else the one built with this program
+fn default_library() -> Result<Library, String> {
+    Library::find("libfeedback.so").or_else(|e| {
+        env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(|dir| dir.join("libfeedback.so")))
+            .ok_or(e)
+            .and_then(|path| Library::open(&path))
+    })
+}
+
+fn list(library: &Library) {
+    for plugin in library.plugins() {
+        println!("{} ({})", plugin.label, plugin.name);
+        for port in &plugin.ports {
+            println!("    {:<24} {:?}", port.name, port.kind);
+        }
+    }
+}
+
+fn run(library: &Library, spec_path: &str, output: Option<&str>) -> Result<bool, String> {
+    let spec = try!(Spec::load(Path::new(spec_path)));
+    let actual = try!(spec.run(library));
+    if let Some(output) = output {
+        try!(host::write_wav(Path::new(output), spec.sample_rate as u32, &actual));
+    }
+    let expect = match spec.expect {
+        Some(ref expect) => expect,
+        None => {
+            println!("ran {} frames, nothing to compare",
+                     actual.first().map(|c| c.len()).unwrap_or(0));
+            return Ok(true);
+        }
+    };
+    let (_, expected) = try!(host::read_wav(expect));
+    match host::compare(&expected, &actual, spec.tolerance) {
+        Ok(()) => {
+            println!("ok");
+            Ok(true)
+        }
+        Err(e) => {
+            println!("mismatch: {}", e);
+            Ok(false)
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut library_path = None;
+    let mut output = None;
+    let mut listing = false;
+    let mut specs = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match &arg[..] {
+            "--library" => library_path = iter.next(),
+            "--output" => output = iter.next(),
+            "--list" => listing = true,
+            _ if arg.starts_with("--") => {
+                println!("unknown option {}\n{}", arg, USAGE);
+                process::exit(2);
+            }
+            _ => specs.push(arg),
+        }
+    }
+    if !(listing && specs.is_empty()) && !(!listing && specs.len() == 1) {
+        println!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let library = match library_path.map(|path| Library::open(Path::new(path)))
+                                    .unwrap_or_else(default_library) {
+        Ok(library) => library,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+    if listing {
+        list(&library);
+        return;
+    }
+    match run(&library, specs[0], output.map(|o| &o[..])) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+// Forwards one channel to another through a proxy that injects faults on a schedule. A
+// transmitter sending to the first channel reaches a receiver listening on the second.
+
+extern crate feedback;
+
+use std::env;
+use std::net::SocketAddr;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use feedback::config::Config;
+use feedback::proxy::{Fault, Proxy};
+use feedback::registry::{self, Registry};
+
+const USAGE: &'static str = "usage:
+    feedback-proxy [--session N] [--delay MS] [--bandwidth BYTES] [--at MS:FAULT]... FROM TO
+                              forward channel FROM to channel TO, damaging what is sent
+faults:
+    delay=MS  bandwidth=BYTES  stall=MS  truncate=BYTES  corrupt=BYTES  reset
+                              times given with --at count from when the proxy starts";
+
+struct Options {
+    session: Option<u16>,
+    faults: Vec<(Duration, Fault)>,
+    channels: Vec<u16>,
+}
+
+fn parse_options(config: &Config, args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        session: None,
+        faults: Vec::new(),
+        channels: Vec::new(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            let registry = Registry::open(config);
+            let channel = try!(registry::resolve_channel(config, registry.as_ref(), arg));
+            options.channels.push(channel);
+            continue;
+        }
+        let value = try!(args.next().ok_or(format!("{} needs a value", arg)));
+        let bad = || format!("bad value for {}: {}", arg, value);
+        match &arg[..] {
+            "--session" => options.session = Some(try!(value.parse().map_err(|_| bad()))),
+            "--delay" => {
+                let fault = try!(Fault::parse(&format!("delay={}", value)));
+                options.faults.push((Duration::from_millis(0), fault));
+            }
+            "--bandwidth" => {
+                let fault = try!(Fault::parse(&format!("bandwidth={}", value)));
+                options.faults.push((Duration::from_millis(0), fault));
+            }
+            "--at" => {
+                let mut parts = value.splitn(2, ':');
+                let at = try!(parts.next().unwrap().parse().map_err(|_| bad()));
+                let fault = try!(Fault::parse(try!(parts.next().ok_or(bad()))));
+                options.faults.push((Duration::from_millis(at), fault));
+            }
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    if options.channels.len() != 2 {
+        return Err("expected a channel to listen on and one to forward to".to_string());
+    }
+    Ok(options)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = Config::load();
+    let options = match parse_options(&config, &args) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let session = config.resolve_session(options.session);
+    let from = config.port(session, options.channels[0]);
+    let to = config.port(session, options.channels[1]);
+    let proxy = match Proxy::start(&SocketAddr::new(config.bind, from),
+                                   &SocketAddr::new(config.host, to)) {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            println!("can't listen on port {}: {}", from, e);
+            process::exit(1);
+        }
+    };
+    println!("forwarding channel {} (port {}) to channel {} (port {})",
+             options.channels[0],
+             from,
+             options.channels[1],
+             to);
+    proxy.schedule(options.faults);
+    let mut connections = 0;
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        if proxy.connections() != connections {
+            connections = proxy.connections();
+            println!("{} connections so far, {} bytes forwarded",
+                     connections,
+                     proxy.forwarded());
+        }
+    }
+}
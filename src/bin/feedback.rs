@@ -0,0 +1,470 @@
+// Command line tools for working with feedback channels.
+
+extern crate feedback;
+extern crate hound;
+extern crate libc;
+extern crate rustc_serialize;
+
+use std::env;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use feedback::capture::{self, Capture};
+use feedback::config::Config;
+use feedback::host::{Instance, Library};
+use feedback::monitor::{Stats, TapReader};
+use feedback::registry::{self, Entry, Registry, Role};
+
+use rustc_serialize::json;
+
+const USAGE: &'static str = "usage:
+    feedback channels         list named channels and running plugins
+    feedback receivers        list receivers that are listening on this machine
+    feedback channel NAME     print the number of a named channel
+    feedback send [--channel N] [--session N] FILE.wav
+                              play a WAV file into a channel, like a transmitter
+    feedback record [--channel N] [--session N] [--rate HZ] [--seconds S] FILE.wav
+                              record a channel to a WAV file, like a receiver, until interrupted
+    feedback monitor [--channel N] [--session N] [--interval MS] [--json]
+                              show levels and network stats of a channel with a local receiver
+    feedback replay [--channel N] [--session N] [--speed X] FILE.capture
+                              send a capture to a receiver, on its own channel unless told";
+
+static INTERRUPTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+fn print_header() {
+    println!("{:<20} {:>7} {:>7} {:<11} {:>6} {:>7} {:>7}  {}",
+             "NAME",
+             "CHANNEL",
+             "SESSION",
+             "ROLE",
+             "RATE",
+             "CLIENTS",
+             "PID",
+             "ADDRESS");
+}
+
+fn print_entry(entry: &Entry) {
+    let role = match entry.role {
+        Role::Transmitter => "transmitter",
+        Role::Receiver => "receiver",
+    };
+    println!("{:<20} {:>7} {:>7} {:<11} {:>6} {:>7} {:>7}  {}",
+             entry.name.as_ref().map(|name| &name[..]).unwrap_or("-"),
+             entry.channel,
+             entry.session,
+             role,
+             entry.sample_rate,
+             entry.clients.map(|n| n.to_string()).unwrap_or("-".to_string()),
+             entry.pid,
+             entry.address);
+}
+
+fn channels(config: &Config) {
+    print_header();
+    let entries = Registry::open(config).map(|r| r.entries()).unwrap_or(Vec::new());
+    for entry in &entries {
+        print_entry(entry);
+    }
+    // names nobody is using right now
+    for (name, &channel) in &config.channels {
+        if !entries.iter().any(|entry| entry.channel == channel) {
+            println!("{:<20} {:>7} {:>7} {:<11} {:>6} {:>7} {:>7}  {}",
+                     name,
+                     channel,
+                     "-",
+                     "-",
+                     "-",
+                     "-",
+                     "-",
+                     "-");
+        }
+    }
+}
+
+fn receivers(config: &Config) {
+    print_header();
+    for entry in Registry::open(config).map(|r| r.entries()).unwrap_or(Vec::new()) {
+        if entry.role == Role::Receiver {
+            print_entry(&entry);
+        }
+    }
+}
+
+/// One of our plugins, run through the same C interface a host would use, and started on a
+/// channel.
+fn start(label: &str,
+         sample_rate: u64,
+         block: usize,
+         channel: u16,
+         session: u16)
+         -> Result<Instance, String> {
+    let mut instance = try!(try!(Library::linked().plugin(label)).instantiate(sample_rate));
+    instance.reserve(block);
+    try!(instance.set_control("Channel", channel as f32));
+    try!(instance.set_control("Session", session as f32));
+    instance.activate();
+    Ok(instance)
+}
+
+struct Options {
+    channel: Option<u16>,
+    session: Option<u16>,
+    rate: Option<u32>,
+    seconds: Option<f64>,
+    speed: f64,
+    interval: u64,
+    json: bool,
+    files: Vec<String>,
+}
+
+fn parse_options(config: &Config, args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        channel: None,
+        session: None,
+        rate: None,
+        seconds: None,
+        speed: 1.0,
+        interval: 500,
+        json: false,
+        files: Vec::new(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            options.files.push(arg.clone());
+            continue;
+        }
+        if arg == "--json" {
+            options.json = true;
+            continue;
+        }
+        let value = try!(args.next().ok_or(format!("{} needs a value", arg)));
+        let bad = || format!("bad value for {}: {}", arg, value);
+        match &arg[..] {
+            "--channel" => {
+                let registry = Registry::open(config);
+                options.channel = Some(try!(registry::resolve_channel(config,
+                                                                      registry.as_ref(),
+                                                                      value)))
+            }
+            "--session" => options.session = Some(try!(value.parse().map_err(|_| bad()))),
+            "--rate" => options.rate = Some(try!(value.parse().map_err(|_| bad()))),
+            "--seconds" => options.seconds = Some(try!(value.parse().map_err(|_| bad()))),
+            "--speed" => options.speed = try!(value.parse().map_err(|_| bad())),
+            "--interval" => options.interval = try!(value.parse().map_err(|_| bad())),
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+fn one_file(options: &Options) -> Result<&str, String> {
+    match options.files.len() {
+        1 => Ok(&options.files[0]),
+        _ => Err("expected one file".to_string()),
+    }
+}
+
+/// Keeps a loop running in real time. Returns true if it has fallen more than a block behind.
+fn pace(start: Instant, frames: u64, block: u64, sample_rate: u64) -> bool {
+    let due = Duration::from_millis(frames * 1000 / sample_rate);
+    let elapsed = start.elapsed();
+    if elapsed < due {
+        thread::sleep(due - elapsed);
+        false
+    } else {
+        elapsed - due > Duration::from_millis(block * 1000 / sample_rate)
+    }
+}
+
+fn send(config: &Config, options: &Options) -> Result<(), String> {
+    let channel = options.channel.unwrap_or(0);
+    let file = try!(one_file(options));
+    let mut reader = try!(hound::WavReader::open(file).map_err(|e| e.to_string()));
+    let spec = reader.spec();
+    if spec.channels > 2 {
+        return Err(format!("{} has {} channels, only mono and stereo are supported",
+                           file,
+                           spec.channels));
+    }
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => {
+            try!(reader.samples::<f32>().collect::<Result<_, _>>().map_err(|e| e.to_string()))
+        }
+        hound::SampleFormat::Int => {
+            let scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+            try!(reader.samples::<i32>()
+                       .map(|s| s.map(|s| s as f32 / scale))
+                       .collect::<Result<_, _>>()
+                       .map_err(|e| e.to_string()))
+        }
+    };
+    let channels = spec.channels as usize;
+    let frames = samples.len() / channels;
+    let sample_rate = spec.sample_rate as u64;
+
+    let session = config.resolve_session(options.session);
+    let receivers = Registry::open(config)
+                        .map(|r| r.receivers(session, channel))
+                        .unwrap_or(Vec::new());
+    if receivers.is_empty() {
+        println!("no receiver on channel {} session {} on this machine",
+                 channel,
+                 session);
+    }
+
+    let block = config.block_size;
+    let mut tx = try!(start("feedback_tx", sample_rate, block, channel, session));
+    let inputs = tx.audio_inputs();
+
+    let mut underruns = 0;
+    let start = Instant::now();
+    let mut sent = 0;
+    while sent < frames && !INTERRUPTED.load(Ordering::SeqCst) {
+        let n = block.min(frames - sent);
+        for (c, &port) in inputs.iter().enumerate() {
+            // mono files go to both sides
+            let c = c.min(channels - 1);
+            let input = tx.buffer_mut(port);
+            for i in 0..n {
+                input[i] = samples[(sent + i) * channels + c];
+            }
+        }
+        tx.run(n);
+        sent += n;
+        if pace(start, sent as u64, block as u64, sample_rate) {
+            underruns += 1;
+        }
+    }
+    // the network thread sends what is left once the plugin is gone
+    drop(tx);
+    thread::sleep(Duration::from_millis(100));
+
+    println!("sent {} frames ({:.1} s), {} underruns",
+             sent,
+             sent as f64 / sample_rate as f64,
+             underruns);
+    Ok(())
+}
+
+fn record(config: &Config, options: &Options) -> Result<(), String> {
+    let channel = options.channel.unwrap_or(0);
+    let file = try!(one_file(options));
+    let session = config.resolve_session(options.session);
+    // follow the transmitter's rate unless told otherwise
+    let sample_rate = match options.rate {
+        Some(rate) => rate,
+        None => {
+            Registry::open(config)
+                .and_then(|r| {
+                    r.entries()
+                     .into_iter()
+                     .find(|e| {
+                         e.role == Role::Transmitter && e.session == session &&
+                         e.channel == channel
+                     })
+                })
+                .map(|e| e.sample_rate as u32)
+                .unwrap_or(44100)
+        }
+    };
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = try!(hound::WavWriter::create(file, spec)
+                              .map_err(|e| e.to_string()));
+    let limit = options.seconds.map(|s| (s * sample_rate as f64) as u64);
+
+    let block = config.block_size;
+    let mut rx = try!(start("feedback_rx", sample_rate as u64, block, channel, session));
+    let outputs = rx.audio_outputs();
+    println!("recording channel {} session {} at {} Hz",
+             channel,
+             session,
+             sample_rate);
+
+    let start = Instant::now();
+    let mut recorded = 0;
+    while !INTERRUPTED.load(Ordering::SeqCst) && limit.map(|l| recorded < l).unwrap_or(true) {
+        let n = limit.map(|l| (l - recorded) as usize).unwrap_or(block).min(block);
+        rx.run(n);
+        for i in 0..n {
+            let l = rx.buffer(outputs[0])[i];
+            let r = rx.buffer(outputs[1])[i];
+            try!(writer.write_sample(l).map_err(|e| e.to_string()));
+            try!(writer.write_sample(r).map_err(|e| e.to_string()));
+        }
+        recorded += n as u64;
+        pace(start, recorded, block as u64, sample_rate as u64);
+    }
+    let underruns = rx.control("Underruns").unwrap_or(0.0);
+    drop(rx);
+    try!(writer.finalize().map_err(|e| e.to_string()));
+
+    println!("recorded {} frames ({:.1} s), {} underruns",
+             recorded,
+             recorded as f64 / sample_rate as f64,
+             underruns);
+    Ok(())
+}
+
+fn decibels(level: f32) -> String {
+    if level > 0.0 {
+        format!("{:.1}", 20.0 * level.log10())
+    } else {
+        "-inf".to_string()
+    }
+}
+
+fn monitor(config: &Config, options: &Options) -> Result<(), String> {
+    let channel = options.channel.unwrap_or(0);
+    if !options.files.is_empty() {
+        return Err("monitor doesn't take a file".to_string());
+    }
+    let session = config.resolve_session(options.session);
+    let receiver = Registry::open(config)
+                       .map(|r| r.receivers(session, channel))
+                       .unwrap_or(Vec::new())
+                       .into_iter()
+                       .find(|e| e.tap.is_some());
+    let receiver = try!(receiver.ok_or(format!("no receiver on channel {} session {} on this \
+                                                machine to tap",
+                                               channel,
+                                               session)));
+    let addr = try!(receiver.tap.as_ref().unwrap().parse().map_err(|_| "bad tap address"));
+    let mut tap = try!(TapReader::connect(&addr, config).map_err(|e| e.to_string()));
+    let mut stats = Stats::new(receiver.sample_rate);
+    if !options.json {
+        println!("tapping receiver {} on channel {} session {}",
+                 receiver.pid,
+                 channel,
+                 session);
+        println!("{:>7} {:>13} {:>13} {:>8} {:>9} {:>10} {:>5}",
+                 "CLIENTS",
+                 "PEAK L/R dB",
+                 "RMS L/R dB",
+                 "PACKET/S",
+                 "JITTER ms",
+                 "LATENCY ms",
+                 "GAPS");
+    }
+
+    let interval = Duration::from_millis(options.interval);
+    let mut last = Instant::now();
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        let elapsed = last.elapsed();
+        if elapsed >= interval {
+            last = Instant::now();
+            let report = stats.report(elapsed);
+            if options.json {
+                println!("{}", json::encode(&report).unwrap());
+                continue;
+            }
+            for event in &report.events {
+                println!("{}", event);
+            }
+            println!("{:>7} {:>6}/{:<6} {:>6}/{:<6} {:>8.1} {:>9.2} {:>10.2} {:>5}",
+                     report.clients,
+                     decibels(report.peak[0]),
+                     decibels(report.peak[1]),
+                     decibels(report.rms[0]),
+                     decibels(report.rms[1]),
+                     report.packet_rate,
+                     report.jitter_ms,
+                     report.latency_ms,
+                     report.gaps);
+            continue;
+        }
+        match tap.next_event(interval - elapsed) {
+            Ok(Some(event)) => stats.observe(&event),
+            Ok(None) => {}
+            Err(e) => return Err(format!("tap closed: {}", e)),
+        }
+    }
+    Ok(())
+}
+
+fn replay(config: &Config, options: &Options) -> Result<(), String> {
+    let file = try!(one_file(options));
+    let capture = try!(Capture::open(Path::new(file)).map_err(|e| e.to_string()));
+    if capture.truncated {
+        println!("{} ends early, replaying the {} frames before that",
+                 file,
+                 capture.records.len());
+    }
+    let channel = options.channel.unwrap_or(capture.header.channel);
+    let session = options.session.unwrap_or(capture.header.session);
+    if options.speed <= 0.0 {
+        return Err("speed must be above 0".to_string());
+    }
+    let addr = SocketAddr::new(config.host, config.port(session, channel));
+    println!("replaying {} frames to {} at {}x",
+             capture.records.len(),
+             addr,
+             options.speed);
+    let sent = try!(capture::send_capture(&capture, &addr, config, options.speed)
+                        .map_err(|e| e.to_string()));
+    println!("sent {} frames", sent);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = Config::load();
+    let handler = interrupt as extern "C" fn(libc::c_int);
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+    match args.first().map(|arg| &arg[..]) {
+        Some("channels") if args.len() == 1 => channels(&config),
+        Some("receivers") if args.len() == 1 => receivers(&config),
+        Some("channel") if args.len() == 2 => {
+            let registry = Registry::open(&config);
+            match registry::resolve_channel(&config, registry.as_ref(), &args[1]) {
+                Ok(channel) => println!("{}", channel),
+                Err(e) => {
+                    println!("{}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        Some(command @ "send") |
+        Some(command @ "record") |
+        Some(command @ "monitor") |
+        Some(command @ "replay") => {
+            let options = match parse_options(&config, &args[1..]) {
+                Ok(options) => options,
+                Err(e) => {
+                    println!("{}\n{}", e, USAGE);
+                    process::exit(2);
+                }
+            };
+            let res = match command {
+                "send" => send(&config, &options),
+                "record" => record(&config, &options),
+                "monitor" => monitor(&config, &options),
+                _ => replay(&config, &options),
+            };
+            if let Err(e) = res {
+                println!("{}", e);
+                process::exit(1);
+            }
+        }
+        _ => {
+            println!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+// The C API, for hosts and tools that aren't written in Rust. It's a thin layer over
+// `FeedbackSender` and `FeedbackReceiver`, so it talks to the plugins over the same sockets and
+// frames. `include/feedback.h` is generated from this file with cbindgen; see the README.
+//
+// Senders and receivers are opaque pointers, which must only be used from one thread at a time
+// and closed exactly once. Functions that can fail return a negative number when they do: -1 for
+// a null pointer, and -2 if the library panicked. A panic never unwinds into the caller, which
+// would be undefined behaviour; functions returning a pointer return null instead.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use libc::{c_int, size_t};
+
+use super::{CHANNELS, SESSIONS};
+use super::config::Config;
+use super::receive::FeedbackReceiver;
+use super::transmit::FeedbackSender;
+
+/// Opens a sender on `channel` of `session` for audio at `sample_rate`, with the settings from
+/// the config file and environment, and connects it. Returns null if the channel or session is
+/// out of range.
+#[no_mangle]
+pub extern "C" fn feedback_sender_open(channel: u16,
+                                       session: u16,
+                                       sample_rate: u64)
+                                       -> *mut FeedbackSender {
+    guard(ptr::null_mut(), || {
+        if !in_range(channel, session) {
+            return ptr::null_mut();
+        }
+        let mut sender = FeedbackSender::new(Config::load(), sample_rate);
+        sender.set_channel(channel, session);
+        sender.start();
+        Box::into_raw(Box::new(sender))
+    })
+}
+
+/// Hangs up and frees a sender. Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn feedback_sender_close(sender: *mut FeedbackSender) {
+    guard((), || {
+        if !sender.is_null() {
+            drop(Box::from_raw(sender));
+        }
+    })
+}
+
+/// Sends `frames` samples from each of `left` and `right`. Returns 0, or -1 if a pointer is
+/// null.
+#[no_mangle]
+pub unsafe extern "C" fn feedback_sender_send(sender: *mut FeedbackSender,
+                                              left: *const f32,
+                                              right: *const f32,
+                                              frames: size_t)
+                                              -> c_int {
+    guard(-2, || {
+        if sender.is_null() || left.is_null() || right.is_null() {
+            return -1;
+        }
+        (*sender).push(slice::from_raw_parts(left, frames), slice::from_raw_parts(right, frames));
+        0
+    })
+}
+
+/// Sends `frames` stereo frames from `samples`, left first. Returns 0, or -1 if a pointer is
+/// null or there are too many frames to address.
+#[no_mangle]
+pub unsafe extern "C" fn feedback_sender_send_interleaved(sender: *mut FeedbackSender,
+                                                          samples: *const f32,
+                                                          frames: size_t)
+                                                          -> c_int {
+    guard(-2, || {
+        if sender.is_null() || samples.is_null() {
+            return -1;
+        }
+        let len = match frames.checked_mul(2) {
+            Some(len) => len,
+            None => return -1,
+        };
+        (*sender).push_interleaved(slice::from_raw_parts(samples, len));
+        0
+    })
+}
+
+/// Whether a sender has got through to a receiver, so what it sends arrives: 1 if it has, 0 if
+/// it is still trying, or -1 if it's null.
+#[no_mangle]
+pub unsafe extern "C" fn feedback_sender_connected(sender: *const FeedbackSender) -> c_int {
+    guard(-2, || {
+        if sender.is_null() {
+            return -1;
+        }
+        (*sender).connected() as c_int
+    })
+}
+
+/// How many samples behind a sender the receivers on its channel are playing, or -1 if it's
+/// null. See `FeedbackSender::receiver_latency`.
+#[no_mangle]
+pub unsafe extern "C" fn feedback_sender_receiver_latency(sender: *const FeedbackSender) -> i64 {
+    guard(-2, || {
+        if sender.is_null() {
+            return -1;
+        }
+        (*sender).receiver_latency() as i64
+    })
+}
+
+/// Opens a receiver on `channel` of `session` for audio at `sample_rate`, with the settings from
+/// the config file and environment, and starts listening. Returns null if the channel or session
+/// is out of range.
+#[no_mangle]
+pub extern "C" fn feedback_receiver_open(channel: u16,
+                                         session: u16,
+                                         sample_rate: u64)
+                                         -> *mut FeedbackReceiver {
+    guard(ptr::null_mut(), || {
+        if !in_range(channel, session) {
+            return ptr::null_mut();
+        }
+        let mut receiver = FeedbackReceiver::new(Config::load(), sample_rate);
+        receiver.set_channel(channel, session);
+        receiver.start();
+        Box::into_raw(Box::new(receiver))
+    })
+}
+
+/// Stops listening and frees a receiver. Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn feedback_receiver_close(receiver: *mut FeedbackReceiver) {
+    guard((), || {
+        if !receiver.is_null() {
+            drop(Box::from_raw(receiver));
+        }
+    })
+}
+
+/// Plays the next `frames` samples into each of `left` and `right`. Returns 1 if there was
+/// audio, 0 if not enough had arrived and the buffers were filled with silence, or -1 if a
+/// pointer is null.
+#[no_mangle]
+pub unsafe extern "C" fn feedback_receiver_receive(receiver: *mut FeedbackReceiver,
+                                                   left: *mut f32,
+                                                   right: *mut f32,
+                                                   frames: size_t)
+                                                   -> c_int {
+    guard(-2, || {
+        if receiver.is_null() || left.is_null() || right.is_null() {
+            return -1;
+        }
+        (*receiver).pull(slice::from_raw_parts_mut(left, frames),
+                         slice::from_raw_parts_mut(right, frames)) as c_int
+    })
+}
+
+/// Plays the next `frames` stereo frames into `samples`, left first. Returns like
+/// `feedback_receiver_receive`, or -1 if there are too many frames to address.
+#[no_mangle]
+pub unsafe extern "C" fn feedback_receiver_receive_interleaved(receiver: *mut FeedbackReceiver,
+                                                               samples: *mut f32,
+                                                               frames: size_t)
+                                                               -> c_int {
+    guard(-2, || {
+        if receiver.is_null() || samples.is_null() {
+            return -1;
+        }
+        let len = match frames.checked_mul(2) {
+            Some(len) => len,
+            None => return -1,
+        };
+        (*receiver).pull_interleaved(slice::from_raw_parts_mut(samples, len)) as c_int
+    })
+}
+
+/// How many transmitters are connected to a receiver, or -1 if it's null.
+#[no_mangle]
+pub unsafe extern "C" fn feedback_receiver_clients(receiver: *const FeedbackReceiver) -> c_int {
+    guard(-2, || {
+        if receiver.is_null() {
+            return -1;
+        }
+        (*receiver).clients() as c_int
+    })
+}
+
+/// How many blocks a receiver has played as silence while someone was connected, or -1 if it's
+/// null.
+#[no_mangle]
+pub unsafe extern "C" fn feedback_receiver_underruns(receiver: *const FeedbackReceiver) -> i64 {
+    guard(-2, || {
+        if receiver.is_null() {
+            return -1;
+        }
+        (*receiver).underruns() as i64
+    })
+}
+
+/// How many samples behind its transmitters a receiver is playing, or -1 if it's null. See
+/// `FeedbackReceiver::latency`.
+#[no_mangle]
+pub unsafe extern "C" fn feedback_receiver_latency(receiver: *const FeedbackReceiver) -> i64 {
+    guard(-2, || {
+        if receiver.is_null() {
+            return -1;
+        }
+        (*receiver).latency() as i64
+    })
+}
+
+/// How many transmitters a receiver has hung up on for sending something it couldn't read, or
+/// -1 if it's null.
+#[no_mangle]
+pub unsafe extern "C" fn feedback_receiver_rejected(receiver: *const FeedbackReceiver) -> i64 {
+    guard(-2, || {
+        if receiver.is_null() {
+            return -1;
+        }
+        (*receiver).rejected() as i64
+    })
+}
+
+// Runs `f`, giving `failed` if it panics rather than unwinding into C.
+fn guard<T, F: FnOnce() -> T>(failed: T, f: F) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(_) => {
+            log!(Error, "panicked in a call from C");
+            failed
+        }
+    }
+}
+
+fn in_range(channel: u16, session: u16) -> bool {
+    channel < CHANNELS && session < SESSIONS
+}
+
+#[test]
+fn test_capi_null() {
+    unsafe {
+        assert_eq!(feedback_sender_send(ptr::null_mut(), ptr::null(), ptr::null(), 0), -1);
+        assert_eq!(feedback_receiver_clients(ptr::null()), -1);
+        assert_eq!(feedback_sender_connected(ptr::null()), -1);
+        feedback_receiver_close(ptr::null_mut());
+    }
+    assert!(feedback_receiver_open(256, 0, 44100).is_null());
+    assert!(feedback_sender_open(0, 64, 44100).is_null());
+}
+
+#[test]
+fn test_capi_too_many_frames() {
+    use std::usize;
+
+    let mut sender = FeedbackSender::new(Config::default(), 44100);
+    let mut receiver = FeedbackReceiver::new(Config::default(), 44100);
+    let mut samples = [0.0; 2];
+    let frames = usize::MAX / 2 + 1;
+    unsafe {
+        assert_eq!(feedback_sender_send_interleaved(&mut sender, samples.as_ptr(), frames), -1);
+        assert_eq!(feedback_receiver_receive_interleaved(&mut receiver,
+                                                         samples.as_mut_ptr(),
+                                                         frames),
+                   -1);
+    }
+}
+
+#[test]
+fn test_capi_panic() {
+    assert_eq!(guard(-2, || -> c_int { panic!("a bug") }), -2);
+    assert!(guard(ptr::null_mut(), || -> *mut FeedbackSender { panic!("a bug") }).is_null());
+    assert_eq!(guard(-2, || 0), 0);
+}
@@ -0,0 +1,424 @@
+// Recordings of everything a receiver was sent, for reproducing timing problems. A capture holds
+// each frame as it came off the wire, which client sent it and when it arrived. It can be played
+// into an offline receiver on a simulated clock, which turns out the same every time, or sent back
+// to a live receiver in real time.
+//
+// A capture file starts with `MAGIC`, followed by a header and then one record per frame. The
+// header and records are each a little endian u32 length followed by that much bincode.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, decode_from};
+use rustc_serialize::{Decodable, Encodable};
+
+use super::codec::Decoder;
+use super::config::Config;
+use super::packet::{Frame, FrameWriter, Protection, MAX_FRAME_SIZE};
+use super::receive::{self, ClientPacket, FeedbackReceiver};
+use super::registry::Entry;
+use super::tap;
+
+const MAGIC: &'static [u8] = b"feedback capture 1\n";
+
+/// What was captured.
+#[derive(RustcEncodable, RustcDecodable, Clone, PartialEq, Debug)]
+pub struct Header {
+    pub sample_rate: u64,
+    pub channel: u16,
+    pub session: u16,
+}
+
+#[derive(RustcEncodable, RustcDecodable, Clone)]
+pub struct Record {
+    pub client: u64,
+    /// When the frame arrived, in microseconds since the Unix epoch.
+    pub arrival: u64,
+    pub frame: Frame,
+}
+
+// a record is a frame with a few fields around it, so it's not much bigger than the largest
+// frame. Anything claiming more is garbage, and isn't read.
+const MAX_RECORD_SIZE: usize = MAX_FRAME_SIZE + 64;
+
+fn record_bytes<T: Encodable>(value: &T) -> io::Result<Vec<u8>> {
+    let payload = try!(encode(value, SizeLimit::Bounded(MAX_RECORD_SIZE as u64))
+                           .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string())));
+    let len = payload.len();
+    let mut bytes = vec![len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8];
+    bytes.extend(payload);
+    Ok(bytes)
+}
+
+fn read_record<T: Decodable>(bytes: &[u8]) -> Option<(T, usize)> {
+    if bytes.len() < 4 {
+        return None;
+    }
+    let len = bytes[0] as usize | (bytes[1] as usize) << 8 | (bytes[2] as usize) << 16 |
+              (bytes[3] as usize) << 24;
+    if len > MAX_RECORD_SIZE || bytes.len() < 4 + len {
+        return None;
+    }
+    // nothing in the record can claim more than the record holds
+    decode_from(&mut &bytes[4..4 + len], SizeLimit::Bounded(len as u64))
+        .ok()
+        .map(|value| (value, 4 + len))
+}
+
+struct CaptureFile {
+    out: BufWriter<File>,
+    size: u64,
+    limit: u64,
+    full: bool,
+    // when what's been written was last handed to the OS
+    flushed: Instant,
+}
+
+impl Drop for CaptureFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.out.flush() {
+            log!(Warn, "capture failed: {}", e);
+        }
+    }
+}
+
+/// Writes a capture. Clones add to the same file. Records are written out at least once a
+/// second, and when the last clone is dropped, so a host that crashes loses little of it.
+#[derive(Clone)]
+pub struct CaptureWriter {
+    file: Arc<Mutex<CaptureFile>>,
+}
+
+impl CaptureWriter {
+    /// Starts a capture that stops once it would grow past `limit` bytes.
+    pub fn create(path: &Path, header: &Header, limit: u64) -> io::Result<CaptureWriter> {
+        let mut out = BufWriter::new(try!(File::create(path)));
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(try!(record_bytes(header)));
+        try!(out.write_all(&bytes).and_then(|()| out.flush()));
+        Ok(CaptureWriter {
+            file: Arc::new(Mutex::new(CaptureFile {
+                out: out,
+                size: bytes.len() as u64,
+                limit: limit,
+                full: false,
+                flushed: Instant::now(),
+            })),
+        })
+    }
+
+    /// Starts a capture for a receiver in `dir`, named after its session, channel and the time.
+    pub fn create_in(dir: &str, limit: u64, entry: &Entry) -> io::Result<CaptureWriter> {
+        try!(fs::create_dir_all(dir));
+        let path = Path::new(dir).join(format!("{}-{}-{}.capture",
+                                               entry.session,
+                                               entry.channel,
+                                               tap::now() / 1000));
+        log!(Info, "capturing to {}", path.display());
+        CaptureWriter::create(&path,
+                              &Header {
+                                  sample_rate: entry.sample_rate,
+                                  channel: entry.channel,
+                                  session: entry.session,
+                              },
+                              limit)
+    }
+
+    /// Adds a frame, unless the capture is full.
+    pub fn write(&self, client: u64, arrival: u64, frame: &Frame) -> io::Result<()> {
+        let record = Record {
+            client: client,
+            arrival: arrival,
+            frame: frame.clone(),
+        };
+        let bytes = try!(record_bytes(&record));
+        let mut file = self.file.lock().unwrap();
+        if file.full {
+            return Ok(());
+        }
+        if file.size + bytes.len() as u64 > file.limit {
+            log!(Warn, "capture is {} bytes, as big as it may be, stopping", file.size);
+            file.full = true;
+            return file.out.flush();
+        }
+        // one write while locked, so records from different clients don't interleave
+        try!(file.out.write_all(&bytes));
+        file.size += bytes.len() as u64;
+        if file.flushed.elapsed() >= Duration::from_secs(1) {
+            file.flushed = Instant::now();
+            try!(file.out.flush());
+        }
+        Ok(())
+    }
+}
+
+pub struct Capture {
+    pub header: Header,
+    /// In the order they were written, which is the order they arrived in.
+    pub records: Vec<Record>,
+    /// Whether the file went on past the last record that could be read, like when the host died
+    /// partway through writing one. Anything after it is lost.
+    pub truncated: bool,
+}
+
+impl Capture {
+    /// Reads a capture. It ends before the first record that is cut short or can't be decoded.
+    pub fn open(path: &Path) -> io::Result<Capture> {
+        let mut bytes = Vec::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)));
+        let invalid = |msg: &str| io::Error::new(ErrorKind::InvalidData, msg.to_string());
+        if !bytes.starts_with(MAGIC) {
+            return Err(invalid("not a capture file"));
+        }
+        let mut pos = MAGIC.len();
+        let (header, len) = try!(read_record::<Header>(&bytes[pos..])
+                                     .ok_or(invalid("bad capture header")));
+        if header.sample_rate == 0 {
+            return Err(invalid("capture has a sample rate of 0"));
+        }
+        pos += len;
+        let mut records = Vec::new();
+        while let Some((record, len)) = read_record(&bytes[pos..]) {
+            records.push(record);
+            pos += len;
+        }
+        if pos < bytes.len() {
+            log!(Warn,
+                 "{} ends early, {} bytes after record {} can't be read",
+                 path.display(),
+                 bytes.len() - pos,
+                 records.len());
+        }
+        Ok(Capture {
+            header: header,
+            records: records,
+            truncated: pos < bytes.len(),
+        })
+    }
+
+    /// When a record arrived relative to the first one, in microseconds, at the given speed.
+    fn offset(&self, record: &Record, speed: f64) -> u64 {
+        let start = self.records.first().map(|r| r.arrival).unwrap_or(0);
+        (record.arrival.saturating_sub(start) as f64 / speed) as u64
+    }
+}
+
+/// Plays a capture into a receiver that isn't connected to anything. The clock is simulated:
+/// each block is run at the time it would be due, after handing over every frame that had arrived
+/// by then. The same capture, speed and block sizes always give the same output.
+pub struct Replay {
+    capture: Capture,
+    speed: f64,
+    next: usize,
+    receiver: FeedbackReceiver,
+    data_tx: SyncSender<ClientPacket>,
+    decoders: HashMap<u64, Option<Decoder>>,
+    // samples run so far, which is the simulated clock
+    time: u64,
+    dropped: usize,
+}
+
+impl Replay {
+    /// Replays at `speed` times the original pace, so 2 has frames arriving twice as fast. The
+    /// config sets how the receiver behaves, like the length of its queue.
+    pub fn new(capture: Capture, config: Config, speed: f64) -> Replay {
+        let (receiver, data_tx) = FeedbackReceiver::offline(config);
+        Replay {
+            capture: capture,
+            speed: speed,
+            next: 0,
+            receiver: receiver,
+            data_tx: data_tx,
+            decoders: HashMap::new(),
+            time: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Runs the receiver for one block. Returns the left and right output, or None if the
+    /// receiver didn't have enough audio and would have played silence.
+    pub fn run(&mut self, sample_count: usize) -> Option<(Vec<f32>, Vec<f32>)> {
+        let now = self.time * 1000000 / self.capture.header.sample_rate;
+        while self.next < self.capture.records.len() {
+            let mut record = self.capture.records[self.next].clone();
+            if self.capture.offset(&record, self.speed) > now {
+                break;
+            }
+            self.next += 1;
+            let decoder = self.decoders.entry(record.client).or_insert(None);
+            if let Some(packet) = receive::decode_frame(&mut record.frame, decoder) {
+                // the queue overflows just as it would for a slow host
+                if let Err(TrySendError::Full(_)) = self.data_tx.try_send((record.client, packet)) {
+                    self.dropped += 1;
+                }
+            }
+        }
+        self.time += sample_count as u64;
+
+        let mut outputl = vec![0.0; sample_count];
+        let mut outputr = vec![0.0; sample_count];
+        if self.receiver.mix(&mut outputl, &mut outputr) {
+            Some((outputl, outputr))
+        } else {
+            None
+        }
+    }
+
+    /// Whether every frame has been handed to the receiver.
+    pub fn finished(&self) -> bool {
+        self.next == self.capture.records.len()
+    }
+
+    /// Packets lost because the receiver's queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+/// Sends a capture to a live receiver in real time, at `speed` times the original pace. Each
+/// captured client gets its own connection. Returns how many frames were sent.
+pub fn send_capture(capture: &Capture,
+                    addr: &SocketAddr,
+                    config: &Config,
+                    speed: f64)
+                    -> io::Result<usize> {
+    let mut connections = HashMap::new();
+    let start = Instant::now();
+    for record in &capture.records {
+        let due = Duration::from_millis(capture.offset(record, speed) / 1000);
+        let elapsed = start.elapsed();
+        if elapsed < due {
+            thread::sleep(due - elapsed);
+        }
+        if !connections.contains_key(&record.client) {
+            let mut socket = try!(TcpStream::connect(addr));
+            try!(socket.set_nodelay(true));
+            let protection = try!(Protection::initiate(&mut socket, config));
+            connections.insert(record.client, (socket, FrameWriter::new(protection)));
+        }
+        let &mut (ref mut socket, ref mut writer) = connections.get_mut(&record.client).unwrap();
+        try!(socket.write_all(&writer.write(&record.frame)));
+    }
+    Ok(capture.records.len())
+}
+
+#[cfg(test)]
+fn test_capture(arrivals: &[u64]) -> Capture {
+    use super::packet::Packet;
+
+    // at 1 kHz, each 100 sample packet lasts 100 ms
+    let records = arrivals.iter()
+                          .enumerate()
+                          .map(|(i, &arrival)| {
+                              let level = (i + 1) as f32;
+                              Record {
+                                  client: 7,
+                                  arrival: 1000000 + arrival * 1000,
+                                  frame: Frame::Raw(Packet::new(&[level; 100],
+                                                                &[-level; 100],
+                                                                i as u64 * 100)),
+                              }
+                          })
+                          .collect();
+    Capture {
+        header: Header {
+            sample_rate: 1000,
+            channel: 3,
+            session: 1,
+        },
+        records: records,
+        truncated: false,
+    }
+}
+
+#[test]
+fn test_capture_file() {
+    use std::env;
+
+    let path = env::temp_dir().join(format!("feedback-test-{}.capture", tap::now()));
+    let capture = test_capture(&[0, 100]);
+    let writer = CaptureWriter::create(&path, &capture.header, 1 << 20).unwrap();
+    for record in &capture.records {
+        writer.write(record.client, record.arrival, &record.frame).unwrap();
+    }
+    drop(writer);
+    let read = Capture::open(&path).unwrap();
+    assert!(!read.truncated);
+
+    // a record cut short by a crash
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[200, 0, 0, 0, 1, 2]).unwrap();
+
+    let read = Capture::open(&path).unwrap();
+    assert_eq!(read.header, capture.header);
+    assert_eq!(read.records.len(), 2);
+    assert_eq!(read.records[1].arrival, capture.records[1].arrival);
+    assert_eq!(read.records[1].frame.as_bytes(), capture.records[1].frame.as_bytes());
+    assert!(read.truncated);
+
+    // a capture stops growing at its limit, leaving whole records
+    let record = record_bytes(&capture.records[0]).unwrap().len() as u64;
+    let header = (MAGIC.len() + record_bytes(&capture.header).unwrap().len()) as u64;
+    let writer = CaptureWriter::create(&path, &capture.header, header + record * 3 / 2).unwrap();
+    for record in &capture.records {
+        writer.write(record.client, record.arrival, &record.frame).unwrap();
+    }
+    drop(writer);
+    let read = Capture::open(&path).unwrap();
+    assert_eq!(read.records.len(), 1);
+    assert!(!read.truncated);
+
+    // a record longer than any frame could make is garbage, even if it's all there
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    let len = MAX_RECORD_SIZE + 1;
+    file.write_all(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]).unwrap();
+    file.write_all(&vec![0; len]).unwrap();
+    let read = Capture::open(&path).unwrap();
+    assert_eq!(read.records.len(), 1);
+    assert!(read.truncated);
+
+    // a sample rate of 0 would leave replays with no clock
+    let mut header = capture.header.clone();
+    header.sample_rate = 0;
+    drop(CaptureWriter::create(&path, &header, 1 << 20).unwrap());
+    assert!(Capture::open(&path).is_err());
+
+    fs::remove_file(&path).unwrap();
+    assert!(Capture::open(&path).is_err());
+}
+
+#[test]
+fn test_capture_replay() {
+    // the third packet arrives 50 ms late
+    let arrivals = [0, 100, 250];
+    let levels = |replay: &mut Replay| {
+        (0..4)
+            .map(|_| replay.run(100).map(|(l, r)| (l[99], r[0])))
+            .collect::<Vec<_>>()
+    };
+
+    // at the original pace the third block comes up short, and the packet plays a block late
+    let mut replay = Replay::new(test_capture(&arrivals), Config::default(), 1.0);
+    assert_eq!(levels(&mut replay),
+               vec![Some((1.0, -1.0)), Some((2.0, -2.0)), None, Some((3.0, -3.0))]);
+    assert!(replay.finished());
+
+    // it's the same every time
+    let mut replay = Replay::new(test_capture(&arrivals), Config::default(), 1.0);
+    assert_eq!(levels(&mut replay),
+               vec![Some((1.0, -1.0)), Some((2.0, -2.0)), None, Some((3.0, -3.0))]);
+
+    // at double speed it arrives in time
+    let mut replay = Replay::new(test_capture(&arrivals), Config::default(), 2.0);
+    assert_eq!(levels(&mut replay),
+               vec![Some((1.0, -1.0)), Some((2.0, -2.0)), Some((3.0, -3.0)), None]);
+    assert_eq!(replay.dropped(), 0);
+}
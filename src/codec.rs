@@ -0,0 +1,684 @@
+// A small lossy transform codec for sending feedback over links that can't take raw f32 audio.
+//
+// Each channel is cut into 50% overlapping sine-windowed frames which are run through an MDCT.
+// The coefficients of each frame are split into equal width bands, and bits are handed out
+// greedily to the loudest bands until the frame's share of the bitrate is used up. Each coded
+// band stores a 3dB-step scale factor followed by uniformly quantized coefficients.
+//
+// Decoding needs the next frame to cancel the time domain aliasing of the current one, so the
+// end of every block waits in the encoder for the block after it. The decoder drops the priming
+// samples and re-stamps its output, so packets coming out of it line up with the transmitter's
+// clock, but they come out a block or so later than the audio went in.
+
+use std::f32::consts::PI;
+
+use super::packet::{read_u32, read_u64, write_u32, write_u64, Packet, BUFFER_SIZE, MAX_BLOCK};
+
+/// Number of new samples per MDCT frame.
+pub const HOP_SIZE: usize = 128;
+const FRAME_SIZE: usize = HOP_SIZE * 2;
+const BANDS: usize = 8;
+const BAND_WIDTH: usize = HOP_SIZE / BANDS;
+
+const ALLOC_BITS: usize = 4;
+const SCALE_BITS: usize = 6;
+const MIN_SCALE: i32 = -40;
+const MAX_SCALE: i32 = MIN_SCALE + (1 << SCALE_BITS) - 1;
+const MAX_ALLOC: u32 = 15;
+// every frame has at least its bands' allocations for both channels
+const MIN_FRAME_BYTES: usize = 2 * BANDS * ALLOC_BITS / 8;
+// the most frames a packet can hold, for a block of MAX_BLOCK that doesn't start on a hop
+const MAX_FRAMES: usize = MAX_BLOCK / HOP_SIZE + 1;
+// a longer break in the stream than this isn't filled with silence, the decoder starts over
+const MAX_GAP: u64 = MAX_BLOCK as u64;
+
+pub const MIN_BITRATE: u32 = 32;
+pub const MAX_BITRATE: u32 = 1024;
+
+#[derive(RustcEncodable, RustcDecodable, Clone)]
+pub struct CodedPacket {
+    timestamp: u64,
+    frames: u32,
+    data: Vec<u8>,
+}
+
+impl CodedPacket {
+    pub fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames as usize * HOP_SIZE
+    }
+
+    /// Appends the packet to `out`: its timestamp as a u64, its number of frames as a u32, then
+    /// the coded data, all little endian.
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        write_u64(out, self.timestamp);
+        write_u32(out, self.frames);
+        out.extend(&self.data);
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<CodedPacket, String> {
+        let mut coded = CodedPacket {
+            timestamp: 0,
+            frames: 0,
+            data: Vec::new(),
+        };
+        try!(coded.parse_into(bytes));
+        Ok(coded)
+    }
+
+    /// Reads a packet written by `write_to` into this one, reusing its buffer.
+    pub fn parse_into(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if bytes.len() < 12 {
+            return Err(format!("coded packet of {} bytes is too short", bytes.len()));
+        }
+        let frames = read_u32(&bytes[8..]) as usize;
+        if frames > MAX_FRAMES || frames * MIN_FRAME_BYTES > bytes.len() - 12 {
+            return Err(format!("coded packet of {} bytes can't hold {} frames",
+                               bytes.len(),
+                               frames));
+        }
+        self.timestamp = read_u64(bytes);
+        self.frames = frames as u32;
+        self.data.clear();
+        self.data.extend(&bytes[12..]);
+        Ok(())
+    }
+}
+
+struct Mdct {
+    window: Vec<f32>,
+    basis: Vec<f32>,
+}
+
+impl Mdct {
+    fn new() -> Mdct {
+        let window = (0..FRAME_SIZE)
+                         .map(|n| (PI * (n as f32 + 0.5) / FRAME_SIZE as f32).sin())
+                         .collect();
+        let mut basis = Vec::with_capacity(FRAME_SIZE * HOP_SIZE);
+        for n in 0..FRAME_SIZE {
+            for k in 0..HOP_SIZE {
+                let phase = PI / HOP_SIZE as f32 * (n as f32 + 0.5 + HOP_SIZE as f32 / 2.0) *
+                            (k as f32 + 0.5);
+                basis.push(phase.cos());
+            }
+        }
+        Mdct {
+            window: window,
+            basis: basis,
+        }
+    }
+
+    fn forward(&self, input: &[f32], output: &mut [f32]) {
+        for x in output.iter_mut() {
+            *x = 0.0;
+        }
+        for n in 0..FRAME_SIZE {
+            let sample = input[n] * self.window[n];
+            let row = &self.basis[n * HOP_SIZE..(n + 1) * HOP_SIZE];
+            for k in 0..HOP_SIZE {
+                output[k] += sample * row[k];
+            }
+        }
+    }
+
+    fn inverse(&self, input: &[f32], output: &mut [f32]) {
+        let gain = 2.0 / HOP_SIZE as f32;
+        for n in 0..FRAME_SIZE {
+            let row = &self.basis[n * HOP_SIZE..(n + 1) * HOP_SIZE];
+            let mut sum = 0.0;
+            for k in 0..HOP_SIZE {
+                sum += input[k] * row[k];
+            }
+            output[n] = sum * gain * self.window[n];
+        }
+    }
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: usize,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            used: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, bits: usize) {
+        for i in (0..bits).rev() {
+            if self.used % 8 == 0 {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= bit << (7 - self.used % 8);
+            self.used += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    used: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            bytes: bytes,
+            used: 0,
+        }
+    }
+
+    // Reads past the end of the stream come back as zeros, which decode to silence.
+    fn read(&mut self, bits: usize) -> u32 {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.bytes.get(self.used / 8).map(|x| *x).unwrap_or(0);
+            let bit = (byte >> (7 - self.used % 8)) & 1;
+            value = (value << 1) | bit as u32;
+            self.used += 1;
+        }
+        value
+    }
+}
+
+fn scale_of(exponent: i32) -> f32 {
+    (2f32).powf(exponent as f32 / 2.0)
+}
+
+fn quant_max(alloc: u32) -> i32 {
+    (1 << (alloc - 1)) - 1
+}
+
+fn encode_frame(coefs: &[f32], budget: usize, out: &mut BitWriter) {
+    let mut exponents = [MIN_SCALE; BANDS];
+    let mut allocs = [0u32; BANDS];
+    for band in 0..BANDS {
+        let peak = coefs[band * BAND_WIDTH..(band + 1) * BAND_WIDTH]
+                       .iter()
+                       .fold(0f32, |peak, x| peak.max(x.abs()));
+        if peak > 0.0 {
+            let exponent = (2.0 * peak.log2()).ceil() as i32;
+            exponents[band] = exponent.max(MIN_SCALE).min(MAX_SCALE);
+        }
+    }
+
+    // hand out bits to whichever band currently has the most audible quantization noise
+    let mut used = BANDS * ALLOC_BITS;
+    loop {
+        let mut best = None;
+        let mut best_noise = 0.0;
+        for band in 0..BANDS {
+            let cost = match allocs[band] {
+                0 => SCALE_BITS + 2 * BAND_WIDTH,
+                MAX_ALLOC => continue,
+                _ => BAND_WIDTH,
+            };
+            if exponents[band] == MIN_SCALE || used + cost > budget {
+                continue;
+            }
+            let noise = exponents[band] as f32 * 3.01 - allocs[band] as f32 * 6.02;
+            if best.is_none() || noise > best_noise {
+                best = Some((band, cost));
+                best_noise = noise;
+            }
+        }
+        match best {
+            Some((band, cost)) => {
+                allocs[band] = if allocs[band] == 0 { 2 } else { allocs[band] + 1 };
+                used += cost;
+            }
+            None => break,
+        }
+    }
+
+    for band in 0..BANDS {
+        out.write(allocs[band], ALLOC_BITS);
+    }
+    for band in 0..BANDS {
+        let alloc = allocs[band];
+        if alloc == 0 {
+            continue;
+        }
+        out.write((exponents[band] - MIN_SCALE) as u32, SCALE_BITS);
+        let scale = scale_of(exponents[band]);
+        let qmax = quant_max(alloc);
+        for &coef in &coefs[band * BAND_WIDTH..(band + 1) * BAND_WIDTH] {
+            let q = (coef / scale * qmax as f32).round() as i32;
+            let q = q.max(-qmax).min(qmax);
+            out.write((q + qmax) as u32, alloc as usize);
+        }
+    }
+}
+
+fn decode_frame(input: &mut BitReader, coefs: &mut [f32]) {
+    let mut allocs = [0u32; BANDS];
+    for band in 0..BANDS {
+        allocs[band] = input.read(ALLOC_BITS).min(MAX_ALLOC);
+    }
+    for band in 0..BANDS {
+        let alloc = allocs[band];
+        let coefs = &mut coefs[band * BAND_WIDTH..(band + 1) * BAND_WIDTH];
+        if alloc < 2 {
+            for coef in coefs.iter_mut() {
+                *coef = 0.0;
+            }
+            continue;
+        }
+        let scale = scale_of(input.read(SCALE_BITS) as i32 + MIN_SCALE);
+        let qmax = quant_max(alloc);
+        for coef in coefs.iter_mut() {
+            let q = input.read(alloc as usize) as i32 - qmax;
+            *coef = q as f32 / qmax as f32 * scale;
+        }
+    }
+}
+
+pub struct Encoder {
+    mdct: Mdct,
+    sample_rate: u64,
+    bitrate: u32,
+    lpending: Vec<f32>,
+    rpending: Vec<f32>,
+    next_time: u64,
+    coefs: Vec<f32>,
+    // length of the last packet encoded
+    block: usize,
+}
+
+impl Encoder {
+    /// Bitrate is the total for both channels, in kbit/s.
+    pub fn new(sample_rate: u64, bitrate: u32) -> Encoder {
+        let mut encoder = Encoder {
+            mdct: Mdct::new(),
+            sample_rate: sample_rate,
+            bitrate: 0,
+            lpending: Vec::new(),
+            rpending: Vec::new(),
+            next_time: 0,
+            coefs: vec![0.0; HOP_SIZE],
+            block: BUFFER_SIZE,
+        };
+        encoder.set_bitrate(bitrate);
+        encoder.reset();
+        encoder
+    }
+
+    pub fn set_bitrate(&mut self, bitrate: u32) {
+        self.bitrate = bitrate.max(MIN_BITRATE).min(MAX_BITRATE);
+    }
+
+    pub fn get_bitrate(&self) -> u32 {
+        self.bitrate
+    }
+
+    /// Samples of delay added by the codec, for blocks the size of the last one encoded. The
+    /// timestamps coming out of the decoder make up for it, but a receiver can't play a block
+    /// until all of it has been decoded, which is this much later than it could without the codec.
+    pub fn delay(&self) -> usize {
+        // between one and two hops wait for the next frame, depending on how the blocks have
+        // fallen across frames, and they can only go out with a later block
+        let block = self.block;
+        let held = 2 * HOP_SIZE - gcd(block, HOP_SIZE);
+        (held + block - 1) / block * block
+    }
+
+    /// The time up to which everything given to the encoder has gone out in a coded packet.
+    pub fn finished(&self) -> u64 {
+        self.next_time.saturating_sub(HOP_SIZE as u64)
+    }
+
+    /// Forget the signal history, as if the codec had only ever seen silence.
+    pub fn reset(&mut self) {
+        self.lpending.clear();
+        self.rpending.clear();
+        self.lpending.extend((0..HOP_SIZE).map(|_| 0.0));
+        self.rpending.extend((0..HOP_SIZE).map(|_| 0.0));
+    }
+
+    pub fn encode(&mut self, packet: &Packet) -> CodedPacket {
+        if self.lpending.len() == HOP_SIZE {
+            self.next_time = packet.get_timestamp();
+        }
+        if packet.len() > 0 {
+            self.block = packet.len();
+        }
+        self.lpending.extend(packet.get_ldata().iter().cloned());
+        self.rpending.extend(packet.get_rdata().iter().cloned());
+
+        let bits_per_second = self.bitrate as u64 * 1000 / 2;
+        let budget = (bits_per_second * HOP_SIZE as u64 / self.sample_rate) as usize;
+
+        let mut out = BitWriter::new();
+        let mut frames = 0;
+        while self.lpending.len() >= FRAME_SIZE {
+            self.mdct.forward(&self.lpending[..FRAME_SIZE], &mut self.coefs);
+            encode_frame(&self.coefs, budget, &mut out);
+            self.mdct.forward(&self.rpending[..FRAME_SIZE], &mut self.coefs);
+            encode_frame(&self.coefs, budget, &mut out);
+            self.lpending.drain(..HOP_SIZE);
+            self.rpending.drain(..HOP_SIZE);
+            frames += 1;
+        }
+
+        let coded = CodedPacket {
+            timestamp: self.next_time,
+            frames: frames,
+            data: out.bytes,
+        };
+        self.next_time += (frames as usize * HOP_SIZE) as u64;
+        coded
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+fn flush_overlap(overlap: &mut Vec<f32>, queue: &mut Vec<f32>, count: usize) {
+    let shift = count.min(HOP_SIZE);
+    queue.extend(overlap.iter().cloned().chain((0..).map(|_| 0.0)).take(count));
+    overlap.drain(..shift);
+    overlap.extend((0..shift).map(|_| 0.0));
+}
+
+pub struct Decoder {
+    mdct: Mdct,
+    loverlap: Vec<f32>,
+    roverlap: Vec<f32>,
+    lqueue: Vec<f32>,
+    rqueue: Vec<f32>,
+    // time of the first sample that isn't finished yet, which is where the overlap starts
+    end: Option<u64>,
+    coefs: Vec<f32>,
+    frame: Vec<f32>,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            mdct: Mdct::new(),
+            loverlap: vec![0.0; HOP_SIZE],
+            roverlap: vec![0.0; HOP_SIZE],
+            lqueue: Vec::new(),
+            rqueue: Vec::new(),
+            end: None,
+            coefs: vec![0.0; HOP_SIZE],
+            frame: vec![0.0; FRAME_SIZE],
+        }
+    }
+
+    fn restart(&mut self) {
+        self.lqueue.clear();
+        self.rqueue.clear();
+        for x in self.loverlap.iter_mut().chain(self.roverlap.iter_mut()) {
+            *x = 0.0;
+        }
+        self.end = None;
+    }
+
+    fn decode_channel(&mut self, input: &mut BitReader, left: bool) {
+        decode_frame(input, &mut self.coefs);
+        self.mdct.inverse(&self.coefs, &mut self.frame);
+        let (overlap, queue) = if left {
+            (&mut self.loverlap, &mut self.lqueue)
+        } else {
+            (&mut self.roverlap, &mut self.rqueue)
+        };
+        for n in 0..HOP_SIZE {
+            queue.push(overlap[n] + self.frame[n]);
+            overlap[n] = self.frame[n + HOP_SIZE];
+        }
+    }
+
+    // Finishes every sample before `time` as if nothing more will be overlapped with it. A gap
+    // too long to be a break in the audio starts the decoder over instead.
+    fn fill_until(&mut self, time: u64) {
+        let end = match self.end {
+            Some(end) if end < time => end,
+            Some(_) => return,
+            None => {
+                self.end = Some(time);
+                return;
+            }
+        };
+        if time - end > MAX_GAP {
+            log!(Warn, "codec stream jumped {} samples ahead, restarting decoder", time - end);
+            self.restart();
+            return;
+        }
+        let count = (time - end) as usize;
+        flush_overlap(&mut self.loverlap, &mut self.lqueue, count);
+        flush_overlap(&mut self.roverlap, &mut self.rqueue, count);
+        self.end = Some(time);
+    }
+
+    /// Decodes a packet, returning whatever audio is now finished. Output packets are stamped
+    /// with the time their audio was fed to the encoder, and are not necessarily the same length
+    /// as the packets that went in.
+    pub fn decode(&mut self, coded: &CodedPacket) -> Option<Packet> {
+        let mut input = BitReader::new(&coded.data);
+        for frame in 0..coded.frames as u64 {
+            let start = coded.timestamp + frame * HOP_SIZE as u64;
+            match self.end {
+                Some(end) if end + HOP_SIZE as u64 == start => {}
+                Some(end) if end + (HOP_SIZE as u64) < start => {
+                    // we missed some audio, presumably silence
+                    self.fill_until(start - HOP_SIZE as u64);
+                }
+                Some(_) => {
+                    log!(Warn, "codec stream went backwards, restarting decoder");
+                    self.restart();
+                }
+                None => {}
+            }
+            let primed = self.end.is_some();
+            self.decode_channel(&mut input, true);
+            self.decode_channel(&mut input, false);
+            if !primed {
+                // the first hop of output has nothing to cancel its aliasing against
+                self.lqueue.clear();
+                self.rqueue.clear();
+            }
+            self.end = Some(start);
+        }
+        self.take_packet()
+    }
+
+    /// Stands in for a silent block that was sent without going through the codec. The encoder
+    /// must have been reset, so the next coded frame overlaps with silence.
+    pub fn decode_silence(&mut self, timestamp: u64, length: usize) -> Option<Packet> {
+        if self.end.is_none() {
+            self.end = Some(timestamp);
+        }
+        let end = timestamp + length as u64;
+        self.fill_until(end - end.min(HOP_SIZE as u64));
+        self.take_packet()
+    }
+
+    fn take_packet(&mut self) -> Option<Packet> {
+        if self.lqueue.is_empty() {
+            return None;
+        }
+        let time = self.end.unwrap() - self.lqueue.len() as u64;
+        let packet = Packet::new(&self.lqueue, &self.rqueue, time);
+        self.lqueue.clear();
+        self.rqueue.clear();
+        Some(packet)
+    }
+}
+
+#[cfg(test)]
+fn sine_packet(time: u64, len: usize, sample_rate: u64) -> Packet {
+    let data: Vec<f32> = (0..len)
+                              .map(|i| {
+                                  let t = (time + i as u64) as f32 / sample_rate as f32;
+                                  0.5 * (2.0 * PI * 440.0 * t).sin()
+                              })
+                              .collect();
+    Packet::new(&data, &data, time)
+}
+
+// Flattens decoded packets back into one signal, checking that they are contiguous.
+#[cfg(test)]
+fn join_packets(packets: Vec<Packet>) -> Vec<f32> {
+    let mut out = Vec::new();
+    for packet in packets {
+        assert_eq!(packet.get_timestamp(), out.len() as u64);
+        out.extend(packet.get_ldata().iter().cloned());
+    }
+    out
+}
+
+#[cfg(test)]
+fn codec_snr(bitrate: u32, block_size: usize) -> f32 {
+    let sample_rate = 44100;
+    let mut encoder = Encoder::new(sample_rate, bitrate);
+    let mut decoder = Decoder::new();
+    let mut decoded = Vec::new();
+    for i in 0..8 {
+        let packet = sine_packet((i * block_size) as u64, block_size, sample_rate);
+        decoded.extend(decoder.decode(&encoder.encode(&packet)));
+    }
+    let decoded = join_packets(decoded);
+    assert_eq!(decoded.len(), (8 * block_size) / HOP_SIZE * HOP_SIZE - HOP_SIZE);
+
+    let original = sine_packet(0, decoded.len(), sample_rate);
+    let mut signal = 0.0;
+    let mut noise = 0.0;
+    for (a, b) in original.get_ldata().iter().zip(decoded.iter()) {
+        signal += a * a;
+        noise += (a - b) * (a - b);
+    }
+    10.0 * (signal / noise).log10()
+}
+
+#[test]
+fn test_codec_round_trip() {
+    assert!(codec_snr(256, BUFFER_SIZE) > 40.0);
+}
+
+#[test]
+fn test_codec_small_blocks() {
+    assert!(codec_snr(256, 100) > 40.0);
+}
+
+#[test]
+fn test_codec_bitrate() {
+    let sample_rate = 44100;
+    let packet = sine_packet(0, BUFFER_SIZE, sample_rate);
+    let low = Encoder::new(sample_rate, 64).encode(&packet).data.len();
+    let high = Encoder::new(sample_rate, 512).encode(&packet).data.len();
+    assert!(low < high);
+    // raw stereo f32 would be 8 bytes per sample
+    assert!(high * 8 < BUFFER_SIZE * 8 * 2);
+    assert!(codec_snr(512, BUFFER_SIZE) > codec_snr(64, BUFFER_SIZE));
+}
+
+#[test]
+fn test_codec_silent_blocks() {
+    // sound, silence sent through the codec, silence skipped entirely, then sound again
+    let sample_rate = 44100;
+    let mut encoder = Encoder::new(sample_rate, 512);
+    let mut decoder = Decoder::new();
+    let zeros = vec![0.0; BUFFER_SIZE];
+    let mut decoded = Vec::new();
+    decoded.extend(decoder.decode(&encoder.encode(&sine_packet(0, BUFFER_SIZE, sample_rate))));
+    decoded.extend(decoder.decode(&encoder.encode(&Packet::new(&zeros, &zeros, 1024))));
+    encoder.reset();
+    decoded.extend(decoder.decode_silence(2048, BUFFER_SIZE));
+    decoded.extend(decoder.decode(&encoder.encode(&sine_packet(3072,
+                                                                BUFFER_SIZE,
+                                                                sample_rate))));
+    decoded.extend(decoder.decode(&encoder.encode(&sine_packet(4096,
+                                                                BUFFER_SIZE,
+                                                                sample_rate))));
+
+    let decoded = join_packets(decoded);
+    assert_eq!(decoded.len(), 5 * BUFFER_SIZE - HOP_SIZE);
+    let peak = |data: &[f32]| data.iter().fold(0_f32, |peak, x| peak.max(x.abs()));
+    assert!(peak(&decoded[1024..2048]) < 0.01);
+    // allow for a little pre-echo from the frame where the sound starts again
+    assert!(peak(&decoded[2048..3072]) < 0.01);
+    let original = sine_packet(3072, BUFFER_SIZE, sample_rate);
+    for (a, b) in original.get_ldata().iter().zip(&decoded[3072..4096]) {
+        assert!((a - b).abs() < 0.01);
+    }
+}
+
+#[test]
+fn test_codec_delay() {
+    // how much later than without the codec each sample can be played, at worst
+    let worst = |block: usize| {
+        let mut encoder = Encoder::new(44100, 256);
+        let mut decoder = Decoder::new();
+        let mut worst = 0;
+        for i in 1..40 {
+            let packet = sine_packet(((i - 1) * block) as u64, block, 44100);
+            if let Some(packet) = decoder.decode(&encoder.encode(&packet)) {
+                // the first sample out was sent with this block, and could have been played then
+                let sent = packet.get_timestamp() as usize / block + 1;
+                worst = worst.max((i - sent) * block);
+            }
+        }
+        assert_eq!(worst, encoder.delay());
+    };
+    worst(BUFFER_SIZE);
+    worst(256);
+    worst(100);
+    worst(64);
+    assert_eq!(Encoder::new(44100, 256).delay(), BUFFER_SIZE);
+}
+
+#[test]
+fn test_codec_silence() {
+    let mut encoder = Encoder::new(44100, 256);
+    let mut decoder = Decoder::new();
+    let zeros = vec![0.0; BUFFER_SIZE];
+    for i in 0..3 {
+        let packet = Packet::new(&zeros, &zeros, i * BUFFER_SIZE as u64);
+        if let Some(decoded) = decoder.decode(&encoder.encode(&packet)) {
+            assert_eq!(decoded.peak(), 0.0);
+        }
+    }
+}
+
+#[test]
+fn test_codec_forged_frames() {
+    let mut encoder = Encoder::new(44100, 256);
+    let coded = encoder.encode(&sine_packet(0, BUFFER_SIZE, 44100));
+    let mut bytes = Vec::new();
+    coded.write_to(&mut bytes);
+    assert_eq!(CodedPacket::parse(&bytes).unwrap().len(), coded.len());
+
+    // more frames than the data could hold
+    bytes[8..12].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+    assert!(CodedPacket::parse(&bytes).is_err());
+    let frames = (bytes.len() - 12) / MIN_FRAME_BYTES + 1;
+    bytes[8..12].copy_from_slice(&[frames as u8, (frames >> 8) as u8, 0, 0]);
+    assert!(CodedPacket::parse(&bytes).is_err());
+}
+
+#[test]
+fn test_codec_forged_jump() {
+    let mut encoder = Encoder::new(44100, 256);
+    let mut decoder = Decoder::new();
+    decoder.decode(&encoder.encode(&sine_packet(0, BUFFER_SIZE, 44100)));
+
+    // a jump far ahead starts the decoder over rather than filling the gap with silence
+    let mut coded = encoder.encode(&sine_packet(BUFFER_SIZE as u64, BUFFER_SIZE, 44100));
+    coded.timestamp += 1 << 40;
+    let decoded = decoder.decode(&coded).unwrap();
+    assert!(decoded.len() < BUFFER_SIZE);
+    assert!(decoded.get_timestamp() > 1 << 40);
+    assert!(decoder.decode_silence(1 << 50, BUFFER_SIZE).is_none());
+    assert!(decoder.decode_silence((1 << 50) + BUFFER_SIZE as u64, BUFFER_SIZE).is_some());
+}
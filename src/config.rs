@@ -0,0 +1,432 @@
+// Settings that aren't exposed as LADSPA control ports. These are read once when a plugin is
+// instantiated, so changing them needs the plugin to be reloaded.
+//
+// Settings come from a TOML file, `$XDG_CONFIG_HOME/feedback/config.toml` unless
+// `FEEDBACK_CONFIG` names another one, and then from environment variables, which win. Every
+// setting has a name in the file and a variable, listed in `SETTINGS`.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Once, ONCE_INIT};
+
+use toml;
+
+use super::{BASE_PORT, CHANNELS, SESSIONS};
+use super::access::{Cidr, LimitPolicy};
+use super::net::parse_ip;
+use super::packet::BUFFER_SIZE;
+use super::logging::{self, Level};
+
+static LOGGING: Once = ONCE_INIT;
+
+const SETTINGS: &'static [(&'static str, &'static str)] = &[("transport.host", "FEEDBACK_HOST"),
+                                                            ("transport.bind", "FEEDBACK_BIND"),
+                                                            ("transport.base_port",
+                                                             "FEEDBACK_BASE_PORT"),
+                                                            ("transport.session",
+                                                             "FEEDBACK_SESSION"),
+                                                            ("transport.key", "FEEDBACK_KEY"),
+                                                            ("transport.key_file",
+                                                             "FEEDBACK_KEY_FILE"),
+                                                            ("transport.security",
+                                                             "FEEDBACK_SECURITY"),
+                                                            ("access.allow", "FEEDBACK_ALLOW"),
+                                                            ("access.max_clients",
+                                                             "FEEDBACK_MAX_CLIENTS"),
+                                                            ("access.limit_policy",
+                                                             "FEEDBACK_LIMIT_POLICY"),
+                                                            ("stream.block_size",
+                                                             "FEEDBACK_BLOCK_SIZE"),
+                                                            ("stream.send_queue",
+                                                             "FEEDBACK_SEND_QUEUE"),
+                                                            ("stream.receive_queue",
+                                                             "FEEDBACK_RECEIVE_QUEUE"),
+                                                            ("channels", "FEEDBACK_CHANNELS"),
+                                                            ("logging.level", "FEEDBACK_LOG_LEVEL"),
+                                                            ("logging.file", "FEEDBACK_LOG_FILE"),
+                                                            ("capture.dir",
+                                                             "FEEDBACK_CAPTURE_DIR"),
+                                                            ("capture.limit",
+                                                             "FEEDBACK_CAPTURE_LIMIT"),
+                                                            ("registry.dir",
+                                                             "FEEDBACK_REGISTRY_DIR")];
+
+/// What the pre-shared key is used for.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Security {
+    /// Sign every frame, so forged audio is rejected.
+    Sign,
+    /// Encrypt every frame as well, so the audio can't be listened in on.
+    Encrypt,
+}
+
+#[derive(Clone)]
+pub struct Config {
+    /// Pre-shared key used to protect every frame. Both ends of a channel must agree on it.
+    pub key: Option<Vec<u8>>,
+    pub security: Security,
+    /// Address transmitters send to.
+    pub host: IpAddr,
+    /// Address receivers listen on. The IPv6 any address `::` takes IPv4 clients as well.
+    pub bind: IpAddr,
+    /// Networks receivers accept clients from. None means anyone who can reach `bind`.
+    pub allow: Option<Vec<Cidr>>,
+    /// Most clients a receiver serves at once.
+    pub max_clients: usize,
+    pub limit_policy: LimitPolicy,
+    /// First port used. Session 0 channel 0 is on this port.
+    pub base_port: u16,
+    /// Session used when a plugin's Session port is left at -1, or none is given.
+    pub session: u16,
+    /// Largest block a transmitter sends, however high its Block Size control is set.
+    pub block_size: usize,
+    /// Frames a transmitter holds while its connection catches up.
+    pub send_queue: usize,
+    /// Packets a receiver holds for the host. Past this, packets are dropped.
+    pub receive_queue: usize,
+    pub log_level: Level,
+    /// Log to this file instead of stdout.
+    pub log_file: Option<String>,
+    /// Names for channels, so they can be referred to without remembering numbers.
+    pub channels: BTreeMap<String, u16>,
+    /// Receivers write everything they receive to a capture file in this directory.
+    pub capture_dir: Option<String>,
+    /// Largest a capture file grows, in bytes. Set in megabytes. Past this, capturing stops.
+    pub capture_limit: u64,
+    /// Where plugins publish themselves. None means the user's runtime directory.
+    pub registry_dir: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            key: None,
+            security: Security::Sign,
+            host: "127.0.0.1".parse().unwrap(),
+            bind: "127.0.0.1".parse().unwrap(),
+            allow: None,
+            max_clients: 16,
+            limit_policy: LimitPolicy::Refuse,
+            base_port: BASE_PORT,
+            session: 0,
+            block_size: BUFFER_SIZE,
+            send_queue: 16,
+            receive_queue: 1024,
+            log_level: Level::Info,
+            log_file: None,
+            channels: BTreeMap::new(),
+            capture_dir: None,
+            capture_limit: 1 << 30,
+            registry_dir: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration file and the environment. Problems are reported and the offending
+    /// setting is left at its default, because there is no good way to fail inside a host.
+    pub fn load() -> Config {
+        let mut config = Config::default();
+        let mut errors = Vec::new();
+        if let Some((path, explicit)) = config_path() {
+            let mut text = String::new();
+            match File::open(&path).and_then(|mut f| f.read_to_string(&mut text)) {
+                Ok(_) => config.apply_toml(&text, &mut errors),
+                // only complain about a missing file if we were told to use it
+                Err(e) => {
+                    if explicit {
+                        errors.push(format!("can't read {}: {}", path.display(), e))
+                    }
+                }
+            }
+        }
+        for &(name, var) in SETTINGS {
+            if let Ok(value) = env::var(var) {
+                if let Err(e) = config.set(name, &value) {
+                    errors.push(format!("{}: {}", var, e));
+                }
+            }
+        }
+        config.check(&mut errors);
+
+        // every plugin in the process shares the log, so only the first to load sets it up
+        LOGGING.call_once(|| {
+            if let Err(e) = logging::init(config.log_level,
+                                          config.log_file.as_ref().map(|path| &path[..])) {
+                logging::init(config.log_level, None).unwrap();
+                errors.push(format!("can't open log file: {}", e));
+            }
+        });
+        for e in errors {
+            log!(Error, "config: {}", e);
+        }
+        config
+    }
+
+    /// Applies settings from a TOML document, where each section is a table of settings.
+    fn apply_toml(&mut self, text: &str, errors: &mut Vec<String>) {
+        let mut parser = toml::Parser::new(text);
+        let table = match parser.parse() {
+            Some(table) => table,
+            None => {
+                for e in &parser.errors {
+                    let (line, col) = parser.to_linecol(e.lo);
+                    errors.push(format!("line {} column {}: {}", line + 1, col + 1, e.desc));
+                }
+                return;
+            }
+        };
+        for (section, settings) in &table {
+            let settings = match settings.as_table() {
+                Some(settings) => settings,
+                None => {
+                    errors.push(format!("{} should be a section", section));
+                    continue;
+                }
+            };
+            for (key, value) in settings {
+                let name = format!("{}.{}", section, key);
+                let value = match *value {
+                    toml::Value::String(ref s) => s.clone(),
+                    toml::Value::Integer(n) => n.to_string(),
+                    toml::Value::Array(ref items) => {
+                        let items: Vec<_> = items.iter().filter_map(|item| item.as_str()).collect();
+                        items.join(",")
+                    }
+                    _ => {
+                        errors.push(format!("{} has the wrong type", name));
+                        continue;
+                    }
+                };
+                if let Err(e) = self.set(&name, &value) {
+                    errors.push(format!("{}: {}", name, e));
+                }
+            }
+        }
+    }
+
+    /// Changes one setting, named as in the file, from its text form.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "transport.host" => self.host = try!(parse_ip(value).ok_or("bad address")),
+            "transport.bind" => self.bind = try!(parse_ip(value).ok_or("bad address")),
+            "transport.base_port" => {
+                // every session and channel needs a port above the base
+                let max = 65536 - (SESSIONS * CHANNELS) as u32;
+                self.base_port = try!(parse_number(value, 1, max)) as u16;
+            }
+            "transport.session" => {
+                self.session = try!(parse_number(value, 0, SESSIONS as u32 - 1)) as u16
+            }
+            "transport.key" => self.key = Some(value.as_bytes().to_vec()),
+            "transport.key_file" => {
+                let key = try!(read_key_file(value)
+                                   .map_err(|e| format!("can't read key file {}: {}", value, e)));
+                self.key = Some(key);
+            }
+            "transport.security" => {
+                self.security = match value {
+                    "sign" => Security::Sign,
+                    "encrypt" => Security::Encrypt,
+                    _ => return Err(format!("unknown mode {}, expected sign or encrypt", value)),
+                }
+            }
+            "access.allow" => {
+                // a mistyped entry is left out rather than opening things up to everyone
+                let mut nets = Vec::new();
+                let mut res = Ok(());
+                for net in value.split(',').filter(|net| !net.trim().is_empty()) {
+                    match Cidr::parse(net) {
+                        Ok(net) => nets.push(net),
+                        Err(e) => res = Err(e),
+                    }
+                }
+                self.allow = Some(nets);
+                return res;
+            }
+            "access.max_clients" => {
+                self.max_clients = try!(parse_number(value, 1, 1024)) as usize
+            }
+            "access.limit_policy" => {
+                self.limit_policy = match value {
+                    "refuse" => LimitPolicy::Refuse,
+                    "replace" => LimitPolicy::Replace,
+                    _ => {
+                        return Err(format!("unknown policy {}, expected refuse or replace", value))
+                    }
+                }
+            }
+            "stream.block_size" => {
+                self.block_size = try!(parse_number(value, 1, BUFFER_SIZE as u32)) as usize
+            }
+            "stream.send_queue" => self.send_queue = try!(parse_number(value, 1, 65536)) as usize,
+            "stream.receive_queue" => {
+                self.receive_queue = try!(parse_number(value, 1, 1 << 20)) as usize
+            }
+            "logging.level" => self.log_level = try!(Level::parse(value)),
+            "logging.file" => self.log_file = Some(value.to_string()),
+            "capture.dir" => self.capture_dir = Some(value.to_string()),
+            "capture.limit" => {
+                self.capture_limit = try!(parse_number(value, 1, 1 << 20)) as u64 * (1 << 20)
+            }
+            "registry.dir" => self.registry_dir = Some(value.to_string()),
+            // the variable holds a list, like `vocals=3,drums=4`
+            "channels" => {
+                for pair in value.split(',').filter(|pair| !pair.trim().is_empty()) {
+                    let mut parts = pair.splitn(2, '=');
+                    let name = parts.next().unwrap().trim();
+                    let channel = try!(parts.next()
+                                            .ok_or(format!("expected name=channel, not {}", pair)));
+                    try!(self.set(&format!("channels.{}", name), channel));
+                }
+            }
+            _ if name.starts_with("channels.") => {
+                let channel = try!(parse_number(value, 0, CHANNELS as u32 - 1)) as u16;
+                self.channels.insert(name["channels.".len()..].to_string(), channel);
+            }
+            _ => return Err("unknown setting".to_string()),
+        }
+        Ok(())
+    }
+
+    /// Catches settings that are fine alone but make no sense together.
+    fn check(&mut self, errors: &mut Vec<String>) {
+        if self.key.as_ref().map(|key| key.is_empty()).unwrap_or(false) {
+            errors.push("ignoring empty key".to_string());
+            self.key = None;
+        }
+        if self.security != Security::Sign && self.key.is_none() {
+            errors.push("encryption has no effect without a key".to_string());
+        }
+    }
+
+    /// The session given, or the configured default session if there isn't one.
+    pub fn resolve_session(&self, session: Option<u16>) -> u16 {
+        session.unwrap_or(self.session).min(SESSIONS - 1)
+    }
+
+    /// The port a channel lives on.
+    pub fn port(&self, session: u16, channel: u16) -> u16 {
+        self.base_port + session.min(SESSIONS - 1) * CHANNELS + channel.min(CHANNELS - 1)
+    }
+
+    /// The name given to a channel, if any.
+    pub fn channel_name(&self, channel: u16) -> Option<&str> {
+        self.channels.iter().find(|&(_, &c)| c == channel).map(|(name, _)| &name[..])
+    }
+}
+
+/// Where to look for the config file, and whether it was asked for explicitly.
+fn config_path() -> Option<(PathBuf, bool)> {
+    if let Ok(path) = env::var("FEEDBACK_CONFIG") {
+        return Some((PathBuf::from(path), true));
+    }
+    let dir = match (env::var("XDG_CONFIG_HOME"), env::var("HOME")) {
+        (Ok(dir), _) => PathBuf::from(dir),
+        (_, Ok(home)) => PathBuf::from(home).join(".config"),
+        _ => return None,
+    };
+    Some((dir.join("feedback").join("config.toml"), false))
+}
+
+fn parse_number(value: &str, min: u32, max: u32) -> Result<u32, String> {
+    match value.trim().parse() {
+        Ok(n) if n >= min && n <= max => Ok(n),
+        _ => Err(format!("expected a number from {} to {}, not {}", min, max, value)),
+    }
+}
+
+fn read_key_file(path: &str) -> Result<Vec<u8>, String> {
+    let mut key = Vec::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_end(&mut key)).map_err(|e| e.to_string()));
+    // a trailing newline from an editor shouldn't change the key
+    while key.last().map(|&c| c == b'\n' || c == b'\r').unwrap_or(false) {
+        key.pop();
+    }
+    Ok(key)
+}
+
+#[test]
+fn test_config_port() {
+    let mut config = Config::default();
+    assert_eq!(config.port(0, 0), BASE_PORT);
+    assert_eq!(config.port(0, 5), BASE_PORT + 5);
+    assert_eq!(config.port(2, 5), BASE_PORT + 2 * CHANNELS + 5);
+    config.base_port = 40000;
+    config.session = 3;
+    // the default session only stands in when none is given, so session 0 can still be chosen
+    assert_eq!(config.port(0, 5), 40000 + 5);
+    assert_eq!(config.resolve_session(None), 3);
+    assert_eq!(config.resolve_session(Some(0)), 0);
+    assert!(config.port(SESSIONS - 1, CHANNELS - 1) > config.port(SESSIONS - 2, CHANNELS - 1));
+}
+
+#[test]
+fn test_config_file() {
+    let mut config = Config::default();
+    let mut errors = Vec::new();
+    config.apply_toml(r#"
+        [transport]
+        host = "::1"
+        base_port = 30000
+        security = "encrypt"
+        key = "secret"
+
+        [access]
+        allow = ["10.0.0.0/8", "192.168.1.5"]
+        limit_policy = "replace"
+
+        [stream]
+        block_size = 256
+        send_queue = 4
+
+        [logging]
+        level = "warn"
+    "#,
+                      &mut errors);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(config.host, "::1".parse::<IpAddr>().unwrap());
+    assert_eq!(config.base_port, 30000);
+    assert_eq!(config.security, Security::Encrypt);
+    assert_eq!(config.key, Some(b"secret".to_vec()));
+    assert_eq!(config.allow.as_ref().unwrap().len(), 2);
+    assert_eq!(config.limit_policy, LimitPolicy::Replace);
+    assert_eq!((config.block_size, config.send_queue), (256, 4));
+    assert_eq!(config.log_level, Level::Warn);
+}
+
+#[test]
+fn test_config_file_errors() {
+    let mut config = Config::default();
+    let mut errors = Vec::new();
+    config.apply_toml(r#"
+        [transport]
+        base_port = 60000
+        session = "two"
+        colour = "blue"
+
+        [stream]
+        block_size = 256
+        send_queue = true
+    "#,
+                      &mut errors);
+    // each bad setting is reported, and the rest still apply
+    assert_eq!(errors.len(), 4, "{:?}", errors);
+    assert_eq!(config.base_port, BASE_PORT);
+    assert_eq!(config.block_size, 256);
+
+    let mut errors = Vec::new();
+    config.apply_toml("[transport\nhost = ", &mut errors);
+    assert!(errors[0].starts_with("line 1"), "{:?}", errors);
+
+    let mut errors = Vec::new();
+    config.security = Security::Encrypt;
+    config.key = Some(Vec::new());
+    config.check(&mut errors);
+    assert_eq!(errors.len(), 2);
+    assert_eq!(config.key, None);
+}
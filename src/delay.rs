@@ -0,0 +1,160 @@
+// Holds a plugin's dry signal back by a whole number of samples, so it can be lined up with audio
+// that has been across the network and back.
+
+/// The longest the dry signal can be held back, about 3 seconds at 44.1 kHz.
+pub const MAX_DELAY: usize = 1 << 17;
+
+// how many samples a change of delay is faded over
+const FADE: usize = 1 << 10;
+
+/// A stereo delay line. It's run on the audio thread, so the buffers are allocated up front, with
+/// room for the longest delay.
+pub struct DelayLine {
+    left: Vec<f32>,
+    right: Vec<f32>,
+    // where the next sample goes in the buffers
+    pos: usize,
+    // the delay being played, and the one asked for. A change waits for the fade before it to
+    // finish, so the output never jumps.
+    delay: usize,
+    target: usize,
+    // the delay being faded out after a change, and how many samples of the fade are left
+    from: usize,
+    fade: usize,
+    // until something has been played, a change of delay takes hold straight away
+    running: bool,
+}
+
+impl DelayLine {
+    pub fn new() -> DelayLine {
+        DelayLine {
+            left: vec![0.0; MAX_DELAY + 1],
+            right: vec![0.0; MAX_DELAY + 1],
+            pos: 0,
+            delay: 0,
+            target: 0,
+            from: 0,
+            fade: 0,
+            running: false,
+        }
+    }
+
+    pub fn delay(&self) -> usize {
+        self.target
+    }
+
+    /// Changes the delay, up to `MAX_DELAY`. What has gone in already is kept, so a longer delay
+    /// replays some of it rather than starting from silence. Once the line is running, the old
+    /// delay is faded into the new one over `FADE` samples rather than jumping, after any fade
+    /// already under way has finished.
+    pub fn set_delay(&mut self, delay: usize) {
+        self.target = delay.min(MAX_DELAY);
+        if !self.running {
+            self.delay = self.target;
+        }
+    }
+
+    /// Empties the line, as if nothing had gone in yet.
+    pub fn clear(&mut self) {
+        for x in self.left.iter_mut().chain(self.right.iter_mut()) {
+            *x = 0.0;
+        }
+        self.fade = 0;
+        self.delay = self.target;
+        self.running = false;
+    }
+
+    /// Writes the input, `delay` samples late and scaled by `gain`, to the outputs.
+    pub fn process(&mut self,
+                   inputl: &[f32],
+                   inputr: &[f32],
+                   outputl: &mut [f32],
+                   outputr: &mut [f32],
+                   gain: f32) {
+        self.running = true;
+        for i in 0..inputl.len() {
+            self.left[self.pos] = inputl[i];
+            self.right[self.pos] = inputr[i];
+            if self.fade == 0 && self.delay != self.target {
+                self.from = self.delay;
+                self.delay = self.target;
+                self.fade = FADE;
+            }
+            let to = self.behind(self.delay);
+            let (mut l, mut r) = (self.left[to], self.right[to]);
+            if self.fade > 0 {
+                let from = self.behind(self.from);
+                let t = self.fade as f32 / FADE as f32;
+                l += (self.left[from] - l) * t;
+                r += (self.right[from] - r) * t;
+                self.fade -= 1;
+            }
+            outputl[i] = l * gain;
+            outputr[i] = r * gain;
+            self.pos = (self.pos + 1) % self.left.len();
+        }
+    }
+
+    // where the sample `delay` before the one just written is
+    fn behind(&self, delay: usize) -> usize {
+        (self.pos + self.left.len() - delay) % self.left.len()
+    }
+}
+
+#[test]
+fn test_delay_line() {
+    let mut delay = DelayLine::new();
+    let input: Vec<f32> = (1..9).map(|i| i as f32).collect();
+    let mut left = [0.0; 8];
+    let mut right = [0.0; 8];
+    // nothing has played yet, so the delay is there from the first sample
+    delay.set_delay(3);
+    delay.process(&input, &input, &mut left, &mut right, 0.5);
+    assert_eq!(left[..4], [0.0, 0.0, 0.0, 0.5]);
+    assert_eq!(right[7], 2.5);
+
+    // after that it fades from the old delay to the new one, replaying what went in before
+    delay.set_delay(5);
+    let ramp: Vec<f32> = (0..FADE * 2).map(|i| i as f32 + 100.0).collect();
+    let mut left = vec![0.0; FADE * 2];
+    let mut right = vec![0.0; FADE * 2];
+    delay.process(&ramp, &ramp, &mut left, &mut right, 1.0);
+    assert_eq!(left[0], 6.0);
+    assert_eq!(left[FADE / 2], ramp[FADE / 2 - 4]);
+    assert_eq!(right[FADE], ramp[FADE - 5]);
+    assert_eq!(left[FADE * 2 - 1], ramp[FADE * 2 - 6]);
+
+    delay.clear();
+    delay.set_delay(0);
+    delay.process(&input[..2], &input[..2], &mut left[..2], &mut right[..2], 1.0);
+    assert_eq!(left[..2], [1.0, 2.0]);
+    delay.set_delay(MAX_DELAY * 2);
+    assert_eq!(delay.delay(), MAX_DELAY);
+}
+
+#[test]
+fn test_delay_line_change_while_fading() {
+    let start = [1.0; 16];
+    let ramp: Vec<f32> = (0..FADE * 3).map(|i| i as f32 + 100.0).collect();
+    let mut faded = vec![0.0; FADE * 3];
+    let mut changed = vec![0.0; FADE * 3];
+    let mut right = vec![0.0; FADE * 3];
+    let mut lines = [DelayLine::new(), DelayLine::new()];
+    for line in lines.iter_mut() {
+        line.set_delay(3);
+        line.process(&start, &start, &mut right[..16], &mut faded[..16], 1.0);
+        line.set_delay(5);
+    }
+    lines[0].process(&ramp, &ramp, &mut faded, &mut right, 1.0);
+
+    // half way through the fade to 5 it's asked for 9, and the fade carries on as it was
+    let (before, after) = ramp.split_at(FADE / 2);
+    lines[1].process(before, before, &mut changed[..FADE / 2], &mut right[..FADE / 2], 1.0);
+    lines[1].set_delay(9);
+    assert_eq!(lines[1].delay(), 9);
+    lines[1].process(after, after, &mut changed[FADE / 2..], &mut right[FADE / 2..], 1.0);
+    assert_eq!(changed[..FADE], faded[..FADE]);
+    // then it fades from 5 to 9
+    assert_eq!(changed[FADE], ramp[FADE - 5]);
+    assert_eq!(changed[FADE * 3 - 1], ramp[FADE * 3 - 10]);
+}
@@ -0,0 +1,774 @@
+// A headless LADSPA host, for running plugins the way a DAW would but without one. Plugins are
+// loaded from a shared library, or taken from the ones linked into the program, through the C
+// interface, `ladspa_descriptor`, so they go through exactly the code a real host would. Chains
+// of plugins are described in a small TOML spec, run with any mix of block sizes and control
+// changes, and checked against an expected WAV file.
+//
+// A spec looks like this. Paths are relative to the spec file.
+//
+//     sample_rate = 44100
+//     block_sizes = [256, 17, 1024]   # used in turn
+//     input = "in.wav"                # silence if left out
+//     length = 44100                  # in frames, the input's length if left out
+//     expect = "expected.wav"
+//     tolerance = 0.0001
+//     realtime = false                # run blocks no faster than real time
+//
+//     [[plugin]]
+//     label = "feedback_tx"
+//     [plugin.controls]
+//     Channel = 9
+//
+//     [[automation]]
+//     plugin = 0
+//     control = "Dry"
+//     frame = 22050
+//     value = 0.5
+//
+// Each plugin's audio outputs feed the next one's audio inputs, in order. The last plugin's
+// outputs are the output of the chain.
+
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hound;
+use ladspa::{self, Data};
+use ladspa::ffi::ladspa_h::{self, Descriptor, Handle};
+use libc::{self, c_ulong, c_void};
+use toml;
+
+type DescriptorFn = unsafe extern "C" fn(c_ulong) -> *const Descriptor;
+
+/// A plugin library loaded into the process.
+pub struct Library {
+    descriptor_fn: DescriptorFn,
+}
+
+impl Library {
+    pub fn open(path: &Path) -> Result<Library, String> {
+        let c_path = try!(CString::new(path.to_string_lossy().into_owned())
+                              .map_err(|_| "bad library path".to_string()));
+        // never closed, since a plugin's threads may still be running its code
+        let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(format!("can't load {}: {}", path.display(), dl_error()));
+        }
+        let symbol = unsafe { libc::dlsym(handle, b"ladspa_descriptor\0".as_ptr() as *const _) };
+        if symbol.is_null() {
+            return Err(format!("{} is not a LADSPA library", path.display()));
+        }
+        let descriptor_fn = unsafe { mem::transmute::<*mut c_void, DescriptorFn>(symbol) };
+        Ok(Library { descriptor_fn: descriptor_fn })
+    }
+
+    /// The plugins linked into this program, for tools that run them without a host.
+    pub fn linked() -> Library {
+        Library { descriptor_fn: linked_descriptor }
+    }
+
+    /// Looks for a library in `LADSPA_PATH`, then where LADSPA plugins are usually installed.
+    pub fn find(name: &str) -> Result<Library, String> {
+        let mut dirs: Vec<PathBuf> = env::var("LADSPA_PATH")
+                                         .map(|path| env::split_paths(&path).collect())
+                                         .unwrap_or(Vec::new());
+        dirs.push(PathBuf::from("/usr/local/lib/ladspa"));
+        dirs.push(PathBuf::from("/usr/lib/ladspa"));
+        match dirs.iter().map(|dir| dir.join(name)).find(|path| path.exists()) {
+            Some(path) => Library::open(&path),
+            None => Err(format!("can't find {} in LADSPA_PATH", name)),
+        }
+    }
+
+    pub fn plugins(&self) -> Vec<PluginType> {
+        let mut plugins = Vec::new();
+        loop {
+            let descriptor = unsafe { (self.descriptor_fn)(plugins.len() as c_ulong) };
+            if descriptor.is_null() {
+                return plugins;
+            }
+            plugins.push(unsafe { PluginType::new(descriptor) });
+        }
+    }
+
+    pub fn plugin(&self, label: &str) -> Result<PluginType, String> {
+        self.plugins()
+            .into_iter()
+            .find(|plugin| plugin.label == label)
+            .ok_or(format!("no plugin is labelled {}", label))
+    }
+}
+
+unsafe extern "C" fn linked_descriptor(index: c_ulong) -> *const Descriptor {
+    ladspa::ladspa_descriptor(index)
+}
+
+fn dl_error() -> String {
+    let e = unsafe { libc::dlerror() };
+    if e.is_null() {
+        "unknown error".to_string()
+    } else {
+        unsafe { CStr::from_ptr(e) }.to_string_lossy().into_owned()
+    }
+}
+
+unsafe fn c_string(s: *const libc::c_char) -> String {
+    CStr::from_ptr(s).to_string_lossy().into_owned()
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PortKind {
+    AudioInput,
+    AudioOutput,
+    ControlInput,
+    ControlOutput,
+}
+
+#[derive(Clone)]
+pub struct PortInfo {
+    pub name: String,
+    pub kind: PortKind,
+    hint: ladspa_h::PortRangeHint,
+}
+
+impl PortInfo {
+    /// The value a host sets a control to before the user touches it, as the hints ask.
+    pub fn default_value(&self, sample_rate: u64) -> Data {
+        let hint = self.hint.hint_descriptor;
+        let scale = if hint & ladspa_h::HINT_SAMPLE_RATE != 0 {
+            sample_rate as Data
+        } else {
+            1.0
+        };
+        let lower = self.hint.lower_bound * scale;
+        let upper = self.hint.upper_bound * scale;
+        let between = |upper_weight: Data| {
+            if hint & ladspa_h::HINT_LOGARITHMIC != 0 && lower > 0.0 {
+                (lower.ln() * (1.0 - upper_weight) + upper.ln() * upper_weight).exp()
+            } else {
+                lower * (1.0 - upper_weight) + upper * upper_weight
+            }
+        };
+        match hint & 0x3c0 {
+            ladspa_h::HINT_DEFAULT_MINIMUM => lower,
+            ladspa_h::HINT_DEFAULT_LOW => between(0.25),
+            ladspa_h::HINT_DEFAULT_MIDDLE => between(0.5),
+            ladspa_h::HINT_DEFAULT_HIGH => between(0.75),
+            ladspa_h::HINT_DEFAULT_MAXIMUM => upper,
+            ladspa_h::HINT_DEFAULT_1 => 1.0,
+            ladspa_h::HINT_DEFAULT_100 => 100.0,
+            ladspa_h::HINT_DEFAULT_440 => 440.0,
+            _ => 0.0,
+        }
+    }
+}
+
+/// A plugin a library provides, which can be instantiated any number of times.
+#[derive(Clone)]
+pub struct PluginType {
+    descriptor: *const Descriptor,
+    pub label: String,
+    pub name: String,
+    pub ports: Vec<PortInfo>,
+}
+
+impl PluginType {
+    unsafe fn new(descriptor: *const Descriptor) -> PluginType {
+        let d = &*descriptor;
+        let ports = (0..d.port_count as isize)
+                        .map(|i| {
+                            let desc = *d.port_descriptors.offset(i);
+                            let audio = desc & ladspa_h::PORT_AUDIO != 0;
+                            let input = desc & ladspa_h::PORT_INPUT != 0;
+                            PortInfo {
+                                name: c_string(*d.port_names.offset(i)),
+                                kind: match (audio, input) {
+                                    (true, true) => PortKind::AudioInput,
+                                    (true, false) => PortKind::AudioOutput,
+                                    (false, true) => PortKind::ControlInput,
+                                    (false, false) => PortKind::ControlOutput,
+                                },
+                                hint: *d.port_range_hints.offset(i),
+                            }
+                        })
+                        .collect();
+        PluginType {
+            descriptor: descriptor,
+            label: c_string(d.label),
+            name: c_string(d.name),
+            ports: ports,
+        }
+    }
+
+    pub fn instantiate(&self, sample_rate: u64) -> Result<Instance, String> {
+        let d = unsafe { &*self.descriptor };
+        let handle = (d.instantiate)(self.descriptor, sample_rate as c_ulong);
+        if handle.is_null() {
+            return Err(format!("{} failed to instantiate", self.label));
+        }
+        let mut instance = Instance {
+            plugin: self.clone(),
+            handle: handle,
+            buffers: self.ports
+                         .iter()
+                         .map(|port| {
+                             match port.kind {
+                                 PortKind::ControlInput => vec![port.default_value(sample_rate)],
+                                 _ => vec![0.0],
+                             }
+                         })
+                         .collect(),
+            active: false,
+        };
+        instance.connect();
+        Ok(instance)
+    }
+}
+
+/// A running plugin, with a buffer for each of its ports.
+pub struct Instance {
+    plugin: PluginType,
+    handle: Handle,
+    // a single value for control ports
+    buffers: Vec<Vec<Data>>,
+    active: bool,
+}
+
+impl Instance {
+    fn descriptor(&self) -> &Descriptor {
+        unsafe { &*self.plugin.descriptor }
+    }
+
+    // tells the plugin where its buffers are
+    fn connect(&mut self) {
+        for i in 0..self.buffers.len() {
+            let location = self.buffers[i].as_mut_ptr();
+            (self.descriptor().connect_port)(self.handle, i as c_ulong, location);
+        }
+    }
+
+    /// Makes the audio buffers hold at least `sample_count` samples.
+    pub fn reserve(&mut self, sample_count: usize) {
+        let mut moved = false;
+        for i in 0..self.buffers.len() {
+            match self.plugin.ports[i].kind {
+                PortKind::AudioInput | PortKind::AudioOutput if self.buffers[i].len() <
+                                                                sample_count => {
+                    self.buffers[i].resize(sample_count, 0.0);
+                    moved = true;
+                }
+                _ => {}
+            }
+        }
+        if moved {
+            self.connect();
+        }
+    }
+
+    pub fn ports(&self) -> &[PortInfo] {
+        &self.plugin.ports
+    }
+
+    pub fn port(&self, name: &str) -> Option<usize> {
+        self.plugin.ports.iter().position(|port| port.name == name)
+    }
+
+    fn ports_of(&self, kind: PortKind) -> Vec<usize> {
+        (0..self.plugin.ports.len()).filter(|&i| self.plugin.ports[i].kind == kind).collect()
+    }
+
+    pub fn audio_inputs(&self) -> Vec<usize> {
+        self.ports_of(PortKind::AudioInput)
+    }
+
+    pub fn audio_outputs(&self) -> Vec<usize> {
+        self.ports_of(PortKind::AudioOutput)
+    }
+
+    pub fn buffer(&self, port: usize) -> &[Data] {
+        &self.buffers[port]
+    }
+
+    pub fn buffer_mut(&mut self, port: usize) -> &mut [Data] {
+        &mut self.buffers[port]
+    }
+
+    pub fn set_control(&mut self, name: &str, value: Data) -> Result<(), String> {
+        match self.port(name) {
+            Some(i) if self.plugin.ports[i].kind == PortKind::ControlInput => {
+                self.buffers[i][0] = value;
+                Ok(())
+            }
+            _ => Err(format!("{} has no control input named {}", self.plugin.label, name)),
+        }
+    }
+
+    pub fn control(&self, name: &str) -> Option<Data> {
+        self.port(name).and_then(|i| {
+            match self.plugin.ports[i].kind {
+                PortKind::ControlInput | PortKind::ControlOutput => Some(self.buffers[i][0]),
+                _ => None,
+            }
+        })
+    }
+
+    pub fn activate(&mut self) {
+        if let Some(activate) = self.descriptor().activate {
+            activate(self.handle);
+        }
+        self.active = true;
+    }
+
+    pub fn deactivate(&mut self) {
+        if let Some(deactivate) = self.descriptor().deactivate {
+            deactivate(self.handle);
+        }
+        self.active = false;
+    }
+
+    /// Runs the plugin over the first `sample_count` samples of its audio buffers, growing them
+    /// first if need be. The inputs should be filled in before.
+    pub fn run(&mut self, sample_count: usize) {
+        self.reserve(sample_count);
+        (self.descriptor().run)(self.handle, sample_count as c_ulong);
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        if self.active {
+            self.deactivate();
+        }
+        (self.descriptor().cleanup)(self.handle);
+    }
+}
+
+/// Plugins whose audio runs from one into the next.
+pub struct Chain {
+    instances: Vec<Instance>,
+}
+
+impl Chain {
+    pub fn new(instances: Vec<Instance>) -> Chain {
+        Chain { instances: instances }
+    }
+
+    pub fn instance(&mut self, index: usize) -> Option<&mut Instance> {
+        self.instances.get_mut(index)
+    }
+
+    pub fn activate(&mut self) {
+        for instance in &mut self.instances {
+            instance.activate();
+        }
+    }
+
+    /// Runs one block through the chain and returns the last plugin's outputs. Inputs that
+    /// nothing feeds get silence.
+    pub fn run(&mut self, inputs: &[&[Data]], sample_count: usize) -> Vec<Vec<Data>> {
+        let mut carry: Vec<Vec<Data>> = inputs.iter().map(|input| input.to_vec()).collect();
+        for instance in &mut self.instances {
+            instance.reserve(sample_count);
+            for (n, port) in instance.audio_inputs().into_iter().enumerate() {
+                let buffer = &mut instance.buffers[port];
+                for i in 0..sample_count {
+                    buffer[i] = carry.get(n).map(|c| c[i]).unwrap_or(0.0);
+                }
+            }
+            instance.run(sample_count);
+            carry = instance.audio_outputs()
+                            .into_iter()
+                            .map(|port| instance.buffers[port][..sample_count].to_vec())
+                            .collect();
+        }
+        carry
+    }
+}
+
+pub struct PluginSpec {
+    pub label: String,
+    pub controls: Vec<(String, Data)>,
+}
+
+/// Sets a control at a given frame.
+pub struct Automation {
+    pub plugin: usize,
+    pub control: String,
+    pub frame: usize,
+    pub value: Data,
+}
+
+/// A chain to run and what to expect from it.
+pub struct Spec {
+    pub sample_rate: u64,
+    pub block_sizes: Vec<usize>,
+    pub input: Option<PathBuf>,
+    pub length: Option<usize>,
+    pub expect: Option<PathBuf>,
+    pub tolerance: Data,
+    pub realtime: bool,
+    pub plugins: Vec<PluginSpec>,
+    pub automation: Vec<Automation>,
+}
+
+fn number(value: &toml::Value) -> Option<f64> {
+    match *value {
+        toml::Value::Integer(n) => Some(n as f64),
+        toml::Value::Float(x) => Some(x),
+        _ => None,
+    }
+}
+
+fn count(table: &toml::Table, name: &str) -> Result<Option<usize>, String> {
+    match table.get(name) {
+        Some(&toml::Value::Integer(n)) if n >= 0 => Ok(Some(n as usize)),
+        Some(_) => Err(format!("{} should be a whole number", name)),
+        None => Ok(None),
+    }
+}
+
+impl Spec {
+    pub fn load(path: &Path) -> Result<Spec, String> {
+        let mut text = String::new();
+        try!(File::open(path)
+                 .and_then(|mut f| f.read_to_string(&mut text))
+                 .map_err(|e| format!("can't read {}: {}", path.display(), e)));
+        Spec::parse(&text, path.parent().unwrap_or(Path::new(".")))
+    }
+
+    /// Reads a spec, with paths relative to `dir`.
+    pub fn parse(text: &str, dir: &Path) -> Result<Spec, String> {
+        let mut parser = toml::Parser::new(text);
+        let table = match parser.parse() {
+            Some(table) => table,
+            None => {
+                let e = &parser.errors[0];
+                let (line, col) = parser.to_linecol(e.lo);
+                return Err(format!("line {} column {}: {}", line + 1, col + 1, e.desc));
+            }
+        };
+        let path = |name: &str| -> Result<Option<PathBuf>, String> {
+            match table.get(name) {
+                Some(&toml::Value::String(ref s)) => Ok(Some(dir.join(s))),
+                Some(_) => Err(format!("{} should be a path", name)),
+                None => Ok(None),
+            }
+        };
+        let mut spec = Spec {
+            sample_rate: try!(count(&table, "sample_rate")).unwrap_or(44100) as u64,
+            block_sizes: vec![1024],
+            input: try!(path("input")),
+            length: try!(count(&table, "length")),
+            expect: try!(path("expect")),
+            tolerance: 0.0,
+            realtime: false,
+            plugins: Vec::new(),
+            automation: Vec::new(),
+        };
+        if spec.sample_rate == 0 {
+            return Err("sample_rate should be above 0".to_string());
+        }
+        if let Some(sizes) = table.get("block_sizes") {
+            spec.block_sizes = try!(sizes.as_slice()
+                                         .and_then(|sizes| {
+                                             sizes.iter()
+                                                  .map(|size| {
+                                                      size.as_integer()
+                                                          .and_then(|n| if n > 0 {
+                                                              Some(n as usize)
+                                                          } else {
+                                                              None
+                                                          })
+                                                  })
+                                                  .collect::<Option<Vec<_>>>()
+                                         })
+                                         .and_then(|sizes| if sizes.is_empty() {
+                                             None
+                                         } else {
+                                             Some(sizes)
+                                         })
+                                         .ok_or("block_sizes should be a list of sizes above 0"));
+        }
+        if let Some(tolerance) = table.get("tolerance") {
+            spec.tolerance = try!(number(tolerance).ok_or("tolerance should be a number")) as Data;
+        }
+        if let Some(realtime) = table.get("realtime") {
+            spec.realtime = try!(realtime.as_bool().ok_or("realtime should be true or false"));
+        }
+        for plugin in table.get("plugin").and_then(|p| p.as_slice()).unwrap_or(&[]) {
+            let plugin = try!(plugin.as_table().ok_or("plugin should be a table"));
+            let label = try!(plugin.get("label")
+                                   .and_then(|label| label.as_str())
+                                   .ok_or("every plugin needs a label"));
+            let mut controls = Vec::new();
+            if let Some(table) = plugin.get("controls").and_then(|c| c.as_table()) {
+                for (name, value) in table {
+                    let value = try!(number(value).ok_or(format!("control {} should be a number",
+                                                                 name)));
+                    controls.push((name.clone(), value as Data));
+                }
+            }
+            spec.plugins.push(PluginSpec {
+                label: label.to_string(),
+                controls: controls,
+            });
+        }
+        for change in table.get("automation").and_then(|a| a.as_slice()).unwrap_or(&[]) {
+            let change = try!(change.as_table().ok_or("automation should be a table"));
+            let automation = Automation {
+                plugin: try!(try!(count(change, "plugin")).ok_or("automation needs a plugin")),
+                control: try!(change.get("control")
+                                    .and_then(|c| c.as_str())
+                                    .ok_or("automation needs a control"))
+                             .to_string(),
+                frame: try!(try!(count(change, "frame")).ok_or("automation needs a frame")),
+                value: try!(change.get("value")
+                                  .and_then(number)
+                                  .ok_or("automation needs a value")) as Data,
+            };
+            if automation.plugin >= spec.plugins.len() {
+                return Err(format!("automation refers to plugin {}, but there are only {}",
+                                   automation.plugin,
+                                   spec.plugins.len()));
+            }
+            spec.automation.push(automation);
+        }
+        if spec.plugins.is_empty() {
+            return Err("the chain has no plugins".to_string());
+        }
+        spec.automation.sort_by(|a, b| a.frame.cmp(&b.frame));
+        Ok(spec)
+    }
+
+    /// Builds the chain, runs the input through it, and returns what came out, one buffer per
+    /// channel.
+    pub fn run(&self, library: &Library) -> Result<Vec<Vec<Data>>, String> {
+        let mut instances = Vec::new();
+        for plugin in &self.plugins {
+            let mut instance = try!(try!(library.plugin(&plugin.label))
+                                        .instantiate(self.sample_rate));
+            for &(ref name, value) in &plugin.controls {
+                try!(instance.set_control(name, value));
+            }
+            instances.push(instance);
+        }
+        let mut chain = Chain::new(instances);
+
+        let input = match self.input {
+            Some(ref path) => {
+                let (sample_rate, input) = try!(read_wav(path));
+                if sample_rate as u64 != self.sample_rate {
+                    return Err(format!("{} is at {} Hz, but the chain runs at {} Hz",
+                                       path.display(),
+                                       sample_rate,
+                                       self.sample_rate));
+                }
+                input
+            }
+            None => Vec::new(),
+        };
+        let length = try!(self.length
+                              .or(input.first().map(|channel| channel.len()))
+                              .ok_or("without an input, the spec needs a length"));
+
+        chain.activate();
+        let start = Instant::now();
+        let mut output: Vec<Vec<Data>> = Vec::new();
+        let mut changes = self.automation.iter().peekable();
+        let mut pos = 0;
+        let mut block = 0;
+        while pos < length {
+            while changes.peek().map(|c| c.frame <= pos).unwrap_or(false) {
+                let change = changes.next().unwrap();
+                try!(chain.instance(change.plugin).unwrap().set_control(&change.control,
+                                                                        change.value));
+            }
+            // blocks are split at control changes, so they land on the right frame
+            let mut n = self.block_sizes[block % self.block_sizes.len()].min(length - pos);
+            if let Some(change) = changes.peek() {
+                n = n.min(change.frame - pos);
+            }
+            let samples = |channel: &Vec<Data>| {
+                (pos..pos + n).map(|i| *channel.get(i).unwrap_or(&0.0)).collect()
+            };
+            let inputs: Vec<Vec<Data>> = input.iter().map(samples).collect();
+            let inputs: Vec<&[Data]> = inputs.iter().map(|input| &input[..]).collect();
+            let block_output = chain.run(&inputs, n);
+            output.resize(block_output.len(), Vec::new());
+            for (out, block_out) in output.iter_mut().zip(block_output) {
+                out.extend(block_out);
+            }
+            pos += n;
+            block += 1;
+            if self.realtime {
+                let due = Duration::from_millis(pos as u64 * 1000 / self.sample_rate);
+                let elapsed = start.elapsed();
+                if elapsed < due {
+                    thread::sleep(due - elapsed);
+                }
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// Reads a WAV file into one buffer per channel.
+pub fn read_wav(path: &Path) -> Result<(u32, Vec<Vec<Data>>), String> {
+    let mut reader = try!(hound::WavReader::open(path)
+                              .map_err(|e| format!("can't read {}: {}", path.display(), e)));
+    let spec = reader.spec();
+    let samples: Vec<Data> = match spec.sample_format {
+        hound::SampleFormat::Float => {
+            try!(reader.samples::<f32>().collect::<Result<_, _>>().map_err(|e| e.to_string()))
+        }
+        hound::SampleFormat::Int => {
+            let scale = (1_i64 << (spec.bits_per_sample - 1)) as Data;
+            try!(reader.samples::<i32>()
+                       .map(|s| s.map(|s| s as Data / scale))
+                       .collect::<Result<_, _>>()
+                       .map_err(|e| e.to_string()))
+        }
+    };
+    let channels = spec.channels as usize;
+    let buffers = (0..channels)
+                      .map(|c| {
+                          samples.iter()
+                                 .skip(c)
+                                 .enumerate()
+                                 .filter(|&(i, _)| i % channels == 0)
+                                 .map(|(_, &x)| x)
+                                 .collect()
+                      })
+                      .collect();
+    Ok((spec.sample_rate, buffers))
+}
+
+/// Writes one buffer per channel to a 32-bit float WAV file.
+pub fn write_wav(path: &Path, sample_rate: u32, buffers: &[Vec<Data>]) -> Result<(), String> {
+    let spec = hound::WavSpec {
+        channels: buffers.len() as u16,
+        sample_rate: sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = try!(hound::WavWriter::create(path, spec).map_err(|e| e.to_string()));
+    let frames = buffers.first().map(|b| b.len()).unwrap_or(0);
+    for i in 0..frames {
+        for buffer in buffers {
+            try!(writer.write_sample(buffer[i]).map_err(|e| e.to_string()));
+        }
+    }
+    writer.finalize().map_err(|e| e.to_string())
+}
+
+/// Checks output against what was expected, to within `tolerance`, and describes the first
+/// difference.
+pub fn compare(expected: &[Vec<Data>],
+               actual: &[Vec<Data>],
+               tolerance: Data)
+               -> Result<(), String> {
+    if expected.len() != actual.len() {
+        return Err(format!("expected {} channels, got {}", expected.len(), actual.len()));
+    }
+    for (c, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+        if expected.len() != actual.len() {
+            return Err(format!("expected {} frames, got {}", expected.len(), actual.len()));
+        }
+        for (i, (&e, &a)) in expected.iter().zip(actual).enumerate() {
+            if !((e - a).abs() <= tolerance) {
+                return Err(format!("channel {} frame {}: expected {}, got {}", c, i, e, a));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_spec(text: &str) -> Spec {
+    Spec::parse(text, Path::new(".")).unwrap()
+}
+
+#[test]
+fn test_host_ports() {
+    let library = Library::linked();
+    let labels: Vec<_> = library.plugins().into_iter().map(|p| p.label).collect();
+    assert_eq!(labels, vec!["feedback_tx".to_string(), "feedback_rx".to_string()]);
+
+    let rx = library.plugin("feedback_rx").unwrap().instantiate(44100).unwrap();
+    assert_eq!((rx.audio_inputs(), rx.audio_outputs()), (vec![0, 1], vec![2, 3]));
+    assert_eq!(rx.control("Dry"), Some(1.0));
+    assert_eq!(rx.control("Channel"), Some(0.0));
+    assert_eq!(rx.control("Left Audio In"), None);
+    assert!(library.plugin("reverb").is_err());
+}
+
+#[test]
+fn test_host_chain() {
+    use super::tap;
+
+    let library = Library::linked();
+    {
+        // a receiver nobody sends to passes its dry input through
+        let mut rx = library.plugin("feedback_rx").unwrap().instantiate(44100).unwrap();
+        rx.set_control("Channel", 201.0).unwrap();
+        let mut chain = Chain::new(vec![rx]);
+        chain.activate();
+        let out = chain.run(&[&[1.0; 10], &[-1.0; 10]], 10);
+        assert_eq!(out, vec![vec![1.0; 10], vec![-1.0; 10]]);
+    }
+
+    // the dry level changes partway through a block, and takes effect on the right frame
+    let input = env::temp_dir().join(format!("feedback-test-{}.wav", tap::now()));
+    write_wav(&input, 44100, &[vec![1.0; 200], vec![-1.0; 200]]).unwrap();
+    let spec = test_spec(&format!("
+        block_sizes = [7, 64]
+        input = \"{}\"
+
+        [[plugin]]
+        label = \"feedback_rx\"
+        [plugin.controls]
+        Channel = 201
+        Dry = 0.5
+
+        [[automation]]
+        plugin = 0
+        control = \"Dry\"
+        frame = 100
+        value = 0.25
+    ",
+                                  input.display()));
+    let out = spec.run(&library).unwrap();
+    ::std::fs::remove_file(&input).unwrap();
+    let mut expected = vec![vec![0.5; 200], vec![-0.5; 200]];
+    for i in 100..200 {
+        expected[0][i] = 0.25;
+        expected[1][i] = -0.25;
+    }
+    assert_eq!(compare(&expected, &out, 0.0), Ok(()));
+
+    assert!(compare(&out, &[vec![0.5; 199], vec![-0.5; 200]], 0.0).is_err());
+    expected[1][150] = -0.2;
+    assert_eq!(compare(&expected, &out, 0.01),
+               Err("channel 1 frame 150: expected -0.2, got -0.25".to_string()));
+    assert!(compare(&expected, &out, 0.1).is_ok());
+}
+
+#[test]
+fn test_host_spec_errors() {
+    let dir = Path::new(".");
+    assert!(Spec::parse("length = 10", dir).is_err());
+    assert!(Spec::parse("block_sizes = [0]\n[[plugin]]\nlabel = \"a\"", dir).is_err());
+    assert!(Spec::parse("sample_rate = 0\n[[plugin]]\nlabel = \"a\"", dir).is_err());
+    assert!(Spec::parse("[[plugin]]\nlabel = \"a\"\n[[automation]]\nplugin = 1\ncontrol = \
+                         \"Dry\"\nframe = 0\nvalue = 1",
+                        dir)
+                .is_err());
+    let spec = test_spec("input = \"in.wav\"\n[[plugin]]\nlabel = \"a\"");
+    assert_eq!(spec.input, Some(PathBuf::from("./in.wav")));
+    assert_eq!((spec.sample_rate, spec.block_sizes.clone()), (44100, vec![1024]));
+}
@@ -1,25 +1,76 @@
 #![feature(clone_from_slice)]
 #![cfg_attr(test, feature(convert))]
 
+#[cfg(feature = "ladspa")]
 extern crate ladspa;
 extern crate mio;
 extern crate rustc_serialize;
 extern crate bincode;
+extern crate crypto;
+extern crate rand;
+extern crate libc;
+extern crate toml;
+extern crate hound;
+#[macro_use]
+extern crate lazy_static;
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "async")]
+extern crate bytes;
+
+#[macro_use]
+mod logging;
 
 mod receive;
+mod mixer;
+#[cfg(feature = "ladspa")]
+mod delay;
 mod transmit;
 mod packet;
+mod codec;
+mod auth;
+mod secure;
+pub mod config;
+mod access;
+mod net;
+pub mod registry;
+mod tap;
+pub mod monitor;
+pub mod capture;
+#[cfg(feature = "ladspa")]
+pub mod host;
+mod transport;
+mod reactor;
+pub mod proxy;
+#[cfg(feature = "ladspa")]
+mod plugin;
+mod capi;
+#[cfg(feature = "async")]
+pub mod stream;
+#[cfg(test)]
+mod sim;
 
 #[cfg(test)]
 mod test;
 
+#[cfg(feature = "ladspa")]
 use ladspa::PluginDescriptor;
 
-use receive::Receiver;
-use transmit::Transmitter;
+#[cfg(feature = "ladspa")]
+use plugin::{Receiver, Transmitter};
+
+pub use packet::Packet;
+pub use receive::FeedbackReceiver;
+pub use transmit::FeedbackSender;
 
 const BASE_PORT: u16 = 21300;
+// each session gets its own block of ports, one per channel
+const CHANNELS: u16 = 256;
+const SESSIONS: u16 = 64;
 
+#[cfg(feature = "ladspa")]
 #[no_mangle]
 pub extern "C" fn get_ladspa_descriptor(index: u64) -> Option<PluginDescriptor> {
     match index {
@@ -0,0 +1,73 @@
+// Leveled log output. Messages go to stdout unless a log file is configured.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Result<Level, String> {
+        match s {
+            "off" => Ok(Level::Off),
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(format!("unknown log level {}, expected off, error, warn, info or debug", s)),
+        }
+    }
+}
+
+struct Sink {
+    level: Level,
+    file: Option<File>,
+}
+
+lazy_static! {
+    static ref SINK: Mutex<Sink> = Mutex::new(Sink {
+        level: Level::Info,
+        file: None,
+    });
+}
+
+/// Sets what gets logged and where. The settings are shared by every plugin in the process.
+pub fn init(level: Level, path: Option<&str>) -> io::Result<()> {
+    let file = match path {
+        Some(path) => Some(try!(OpenOptions::new().append(true).create(true).open(path))),
+        None => None,
+    };
+    let mut sink = SINK.lock().unwrap();
+    sink.level = level;
+    sink.file = file;
+    Ok(())
+}
+
+pub fn write(level: Level, args: fmt::Arguments) {
+    let mut sink = SINK.lock().unwrap();
+    if level > sink.level {
+        return;
+    }
+    match sink.file {
+        // there's nowhere left to complain to if this fails
+        Some(ref mut file) => {
+            let _ = writeln!(file, "{}", args);
+        }
+        None => println!("{}", args),
+    }
+}
+
+/// Logs a message at the given level, like `log!(Warn, "lost {} frames", n)`.
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::$level, format_args!($($arg)*))
+    }
+}
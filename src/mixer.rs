@@ -0,0 +1,469 @@
+// Lines up the audio from each client of a receiver and mixes it together. Every client has a
+// ring buffer its packets are added into where their timestamps say they belong, so a block is
+// mixed by adding up contiguous runs of samples rather than looking each one up. Anything lost
+// along the way leaves a gap of silence.
+//
+// The mixer is run on the audio thread, so rings are allocated by the network thread as clients
+// arrive, and handed from one client to the next once they've left. A ring only uses as much of
+// itself as its client needs, which keeps what's being mixed in the cache. Packets that don't fit
+// are dropped and counted.
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+use super::packet::Packet;
+
+// a client's ring starts out using this many samples, and doubles when it needs to
+const MIN_RING: usize = 1 << 12;
+// and has room for this many, about 6 seconds at 44.1 kHz. Packets further ahead than this are
+// dropped, and blocks longer than it can't be mixed.
+const MAX_RING: usize = 1 << 18;
+// packets that follow on from each other share a span, so this is only reached by a client whose
+// packets keep getting lost
+const MAX_SPANS: usize = 1024;
+
+/// A client's place in the mix, with its rings.
+pub struct Track {
+    client: u64,
+    // the timestamp of the next sample to play. Until the client's first block has been mixed,
+    // it's the earliest it has sent.
+    time: u64,
+    playing: bool,
+    // the client has left, so once what's left of it won't fill a block it's forgotten
+    departed: bool,
+    left: Vec<f32>,
+    right: Vec<f32>,
+    // where `time` falls in the ring
+    head: usize,
+    // the stretch of time each packet written into the ring covers, until it has been played
+    spans: Vec<(u64, u64)>,
+}
+
+impl Track {
+    fn new() -> Track {
+        Track {
+            client: 0,
+            time: 0,
+            playing: false,
+            departed: false,
+            left: ring(),
+            right: ring(),
+            head: 0,
+            spans: Vec::with_capacity(MAX_SPANS),
+        }
+    }
+
+    fn has_audio(&self) -> bool {
+        !self.spans.is_empty()
+    }
+
+    // samples that have arrived at or after where the client is up to
+    fn available(&self) -> usize {
+        let time = self.time;
+        self.spans.iter().map(|&(start, end)| (end - start.max(time)) as usize).sum()
+    }
+
+    // Returns false if there was no room for the packet.
+    fn write(&mut self, packet: &Packet) -> bool {
+        let timestamp = packet.get_timestamp();
+        let end = timestamp + packet.len() as u64;
+        if !self.playing && (self.spans.is_empty() || timestamp < self.time) {
+            // the first block starts with the earliest packet, so the ring is moved back to it
+            if let Some(furthest) = self.spans.iter().map(|&(_, end)| end).max() {
+                if furthest - timestamp > MAX_RING as u64 {
+                    return false;
+                }
+                self.reserve((furthest - timestamp) as usize);
+                let back = (self.time - timestamp) as usize;
+                self.head = (self.head + self.left.len() - back) % self.left.len();
+            }
+            self.time = timestamp;
+        }
+        let time = self.time;
+        if end <= time {
+            // too late to play any of it
+            return true;
+        }
+        if end - time > MAX_RING as u64 {
+            return false;
+        }
+        self.reserve((end - time) as usize);
+        let start = timestamp.max(time);
+        let follows = self.spans.last().map(|&(_, last)| last == start).unwrap_or(false);
+        if !follows && self.spans.len() == MAX_SPANS {
+            return false;
+        }
+        let skip = (start - timestamp) as usize;
+        let at = self.head + (start - time) as usize;
+        add_to_ring(&mut self.left, at, &packet.get_ldata()[skip..]);
+        add_to_ring(&mut self.right, at, &packet.get_rdata()[skip..]);
+        if follows {
+            self.spans.last_mut().unwrap().1 = end;
+        } else {
+            self.spans.push((start, end));
+        }
+        true
+    }
+
+    // makes room for `len` samples from `time` on, within what was allocated
+    fn reserve(&mut self, len: usize) {
+        if len <= self.left.len() {
+            return;
+        }
+        let size = len.max(MIN_RING).next_power_of_two();
+        unroll(&mut self.left, self.head, size);
+        unroll(&mut self.right, self.head, size);
+        self.head = 0;
+    }
+
+    fn play(&mut self, outputl: &mut [f32], outputr: &mut [f32], gain: f32) {
+        let len = outputl.len();
+        self.playing = true;
+        self.reserve(len);
+        take_from_ring(&mut self.left, self.head, outputl, gain);
+        take_from_ring(&mut self.right, self.head, outputr, gain);
+        self.head = (self.head + len) % self.left.len();
+        self.time += len as u64;
+        let time = self.time;
+        self.spans.retain(|&(_, end)| end > time);
+    }
+
+    // clears what's still to be played, ready for another client
+    fn reset(&mut self) {
+        for &(start, end) in &self.spans {
+            let start = start.max(self.time);
+            let at = self.head + (start - self.time) as usize;
+            clear_ring(&mut self.left, at, (end - start) as usize);
+            clear_ring(&mut self.right, at, (end - start) as usize);
+        }
+        self.spans.clear();
+        self.left.truncate(MIN_RING);
+        self.right.truncate(MIN_RING);
+        self.head = 0;
+        self.playing = false;
+        self.departed = false;
+    }
+}
+
+/// Makes tracks for a mixer as its clients arrive, so rings are only allocated for clients that
+/// have turned up, and never on the audio thread. Clones share the count of tracks made.
+#[derive(Clone)]
+pub struct Tracks {
+    tx: SyncSender<Track>,
+    made: Arc<Mutex<usize>>,
+    max: usize,
+}
+
+impl Tracks {
+    /// Makes sure the mixer has a track for each of `clients` clients, and one more for a client
+    /// that has left but is still being played, up to the mixer's limit. Tracks are kept once
+    /// they're made.
+    pub fn provide(&self, clients: usize) {
+        let mut made = self.made.lock().unwrap();
+        while *made < (clients + 1).min(self.max) {
+            // the mixer may be gone, and then nobody needs the track
+            let _ = self.tx.try_send(Track::new());
+            *made += 1;
+        }
+    }
+}
+
+/// The audio each client of a receiver has sent, waiting to be played.
+pub struct Mixer {
+    tracks: Vec<Track>,
+    // tracks no client is using, and ones made for clients that have just arrived
+    spare: Vec<Track>,
+    arrivals: Receiver<Track>,
+    dropped: usize,
+}
+
+impl Mixer {
+    /// A mixer with room for `clients` clients at once. It has no tracks until they're made with
+    /// the returned `Tracks`, and until then packets are dropped.
+    pub fn new(clients: usize) -> (Mixer, Tracks) {
+        let (tx, rx) = sync_channel(clients);
+        let mixer = Mixer {
+            tracks: Vec::with_capacity(clients),
+            spare: Vec::with_capacity(clients),
+            arrivals: rx,
+            dropped: 0,
+        };
+        let tracks = Tracks {
+            tx: tx,
+            made: Arc::new(Mutex::new(0)),
+            max: clients,
+        };
+        (mixer, tracks)
+    }
+
+    /// Whether any client still in the mix has had some of its audio played.
+    pub fn started(&self) -> bool {
+        self.tracks.iter().any(|track| track.playing)
+    }
+
+    /// Forgets every client, to start over.
+    pub fn clear(&mut self) {
+        while let Some(mut track) = self.tracks.pop() {
+            track.reset();
+            self.spare.push(track);
+        }
+    }
+
+    pub fn push(&mut self, client: u64, packet: Packet) {
+        let written = match self.tracks.iter_mut().find(|track| track.client == client) {
+            Some(track) => track.write(&packet),
+            None => {
+                match self.spare.pop().or_else(|| self.arrivals.try_recv().ok()) {
+                    Some(mut track) => {
+                        track.client = client;
+                        let written = track.write(&packet);
+                        self.tracks.push(track);
+                        written
+                    }
+                    None => false,
+                }
+            }
+        };
+        if !written {
+            self.dropped += 1;
+        }
+    }
+
+    /// How many clients have sent anything since the mixer was cleared, and not been forgotten.
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    /// How many packets there was no room for, either because they were too far ahead or because
+    /// there was no track for their client.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// How many samples `client` has sent from where it's up to.
+    pub fn available(&self, client: u64) -> usize {
+        self.tracks
+            .iter()
+            .find(|track| track.client == client)
+            .map(|track| track.available())
+            .unwrap_or(0)
+    }
+
+    /// The most samples any client has waiting from where it's up to.
+    pub fn depth(&self) -> usize {
+        self.tracks.iter().map(|track| track.available()).max().unwrap_or(0)
+    }
+
+    /// Marks `client` as gone. What it sent is still played, until it won't fill a block.
+    pub fn depart(&mut self, client: u64) {
+        if let Some(track) = self.tracks.iter_mut().find(|track| track.client == client) {
+            track.departed = true;
+        }
+    }
+
+    /// Marks every client as gone.
+    pub fn depart_all(&mut self) {
+        for track in &mut self.tracks {
+            track.departed = true;
+        }
+    }
+
+    /// Forgets the clients that have gone once what's left of them won't fill a block of `len`.
+    /// A half-played packet from one would otherwise hold up everyone else for good.
+    pub fn forget_departed(&mut self, len: usize) {
+        let mut i = 0;
+        while i < self.tracks.len() {
+            if self.tracks[i].departed && self.tracks[i].available() < len {
+                self.recycle(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    fn recycle(&mut self, i: usize) {
+        let mut track = self.tracks.swap_remove(i);
+        track.reset();
+        self.spare.push(track);
+    }
+
+    /// Whether there's anything to play, and every client with audio waiting has sent at least
+    /// `len` samples of it.
+    pub fn ready(&self, len: usize) -> bool {
+        let mut playing = self.tracks.iter().filter(|track| track.has_audio()).peekable();
+        len <= MAX_RING && playing.peek().is_some() &&
+        playing.all(|track| track.available() >= len)
+    }
+
+    /// Adds the next `outputl.len()` samples from every client to the outputs, scaled by `gain`.
+    /// Returns false, leaving the outputs alone, if the mixer isn't `ready` for them.
+    pub fn mix(&mut self, outputl: &mut [f32], outputr: &mut [f32], gain: f32) -> bool {
+        if !self.ready(outputl.len()) {
+            return false;
+        }
+        for track in self.tracks.iter_mut().filter(|track| track.has_audio()) {
+            track.play(outputl, outputr, gain);
+        }
+        true
+    }
+}
+
+fn ring() -> Vec<f32> {
+    let mut ring = Vec::with_capacity(MAX_RING);
+    ring.resize(MIN_RING, 0.0);
+    ring
+}
+
+// lengthens a ring to `size`, starting from `head`
+fn unroll(ring: &mut Vec<f32>, head: usize, size: usize) {
+    ring.rotate_left(head);
+    ring.resize(size, 0.0);
+}
+
+// The loops below are over plain slices of the same length, which the compiler turns into SIMD.
+
+fn add_to_ring(ring: &mut [f32], at: usize, samples: &[f32]) {
+    let at = at % ring.len();
+    let (first, second) = samples.split_at(samples.len().min(ring.len() - at));
+    add(&mut ring[at..at + first.len()], first);
+    add(&mut ring[..second.len()], second);
+}
+
+// adds a block from the ring to the output and clears it, ready to be written again
+fn take_from_ring(ring: &mut [f32], at: usize, output: &mut [f32], gain: f32) {
+    let split = output.len().min(ring.len() - at);
+    let (first, second) = output.split_at_mut(split);
+    take_scaled(first, &mut ring[at..at + split], gain);
+    take_scaled(second, &mut ring[..second.len()], gain);
+}
+
+fn clear_ring(ring: &mut [f32], at: usize, len: usize) {
+    let at = at % ring.len();
+    let first = len.min(ring.len() - at);
+    for x in &mut ring[at..at + first] {
+        *x = 0.0;
+    }
+    for x in &mut ring[..len - first] {
+        *x = 0.0;
+    }
+}
+
+fn add(output: &mut [f32], samples: &[f32]) {
+    for (out, &x) in output.iter_mut().zip(samples) {
+        *out += x;
+    }
+}
+
+fn take_scaled(output: &mut [f32], samples: &mut [f32], gain: f32) {
+    for (out, x) in output.iter_mut().zip(samples) {
+        *out += *x * gain;
+        *x = 0.0;
+    }
+}
+
+#[cfg(test)]
+fn ramp(time: u64, len: usize) -> Packet {
+    let samples: Vec<f32> = (0..len).map(|i| (time + i as u64) as f32).collect();
+    Packet::new(&samples, &samples, time)
+}
+
+#[test]
+fn test_mixer_gaps_and_wrapping() {
+    let (mut mixer, tracks) = Mixer::new(1);
+    tracks.provide(1);
+    let mut left = [0.0; 3000];
+    let mut right = [0.0; 3000];
+    assert!(!mixer.mix(&mut left, &mut right, 1.0));
+
+    // the second packet is lost, so a later one has to stand in for the missing samples
+    mixer.push(7, ramp(1000, 1000));
+    mixer.push(7, ramp(3000, 1000));
+    mixer.push(7, ramp(4000, 1000));
+    assert_eq!(mixer.available(7), 3000);
+    assert_eq!(mixer.depth(), 3000);
+    assert!(mixer.mix(&mut left, &mut right, 0.5));
+    assert_eq!(left[0], 500.0);
+    assert_eq!(left[999], 999.5);
+    assert_eq!(left[1000], 0.0);
+    assert_eq!(right[2000], 1500.0);
+    assert_eq!(mixer.available(7), 1000);
+
+    // the ring wraps around as it's played and refilled
+    for block in 0..8 {
+        mixer.push(7, ramp(5000 + block * 1000, 1000));
+        let mut left = [0.0; 1000];
+        let mut right = [0.0; 1000];
+        assert!(mixer.mix(&mut left, &mut right, 1.0));
+        assert_eq!(left[0], (4000 + block * 1000) as f32);
+        assert_eq!(right[999], (4999 + block * 1000) as f32);
+    }
+}
+
+#[test]
+fn test_mixer_waits_for_everyone() {
+    let (mut mixer, tracks) = Mixer::new(2);
+    tracks.provide(1);
+    let mut left = [0.0; 100];
+    let mut right = [0.0; 100];
+    mixer.push(1, ramp(0, 100));
+    mixer.push(2, ramp(550, 50));
+    assert!(!mixer.mix(&mut left, &mut right, 1.0));
+    mixer.push(2, ramp(500, 50));
+    assert!(mixer.mix(&mut left, &mut right, 1.0));
+    // each client plays from its own first packet, even when it arrives late
+    assert_eq!(left[10], 10.0 + 510.0);
+    assert_eq!(left[60], 60.0 + 560.0);
+    assert_eq!(mixer.len(), 2);
+    assert!(mixer.started());
+    assert!(!mixer.ready(1));
+
+    // late packets are dropped, and a client that leaves takes its audio with it once it won't
+    // fill a block
+    mixer.push(2, ramp(0, 100));
+    assert_eq!(mixer.available(2), 0);
+    mixer.push(2, ramp(600, 10));
+    assert!(mixer.ready(10));
+    mixer.depart(2);
+    mixer.forget_departed(10);
+    assert_eq!(mixer.len(), 2);
+    mixer.forget_departed(11);
+    assert_eq!(mixer.len(), 1);
+    assert_eq!(mixer.available(2), 0);
+    assert!(!mixer.ready(1));
+    assert_eq!(mixer.dropped(), 0);
+}
+
+#[test]
+fn test_mixer_drops_what_wont_fit() {
+    let (mut mixer, tracks) = Mixer::new(2);
+    let mut left = [0.0; 100];
+    let mut right = [0.0; 100];
+    // there's no track until a client arrives
+    mixer.push(1, ramp(0, 100));
+    assert_eq!(mixer.dropped(), 1);
+    assert_eq!(mixer.len(), 0);
+    // and no more are made than the mixer has room for
+    tracks.provide(0);
+    tracks.provide(5);
+    assert_eq!(*tracks.made.lock().unwrap(), 2);
+
+    mixer.push(1, ramp(0, 100));
+    mixer.push(1, ramp(MAX_RING as u64, 100));
+    mixer.push(2, ramp(0, 100));
+    mixer.push(3, ramp(0, 100));
+    assert_eq!(mixer.dropped(), 3);
+    assert_eq!(mixer.len(), 2);
+    assert_eq!(mixer.depth(), 100);
+
+    // a ring is cleared before it's handed to someone else
+    mixer.push(2, ramp(100, 50));
+    mixer.depart_all();
+    mixer.forget_departed(1000);
+    assert_eq!(mixer.len(), 0);
+    mixer.push(3, ramp(1000, 100));
+    mixer.push(4, ramp(2000, 100));
+    assert!(mixer.mix(&mut left, &mut right, 1.0));
+    assert_eq!(left[0], 1000.0 + 2000.0);
+    assert_eq!(right[99], 1099.0 + 2099.0);
+    assert_eq!(mixer.dropped(), 3);
+}
@@ -0,0 +1,203 @@
+// Statistics on a live channel, worked out from the events on a receiver's tap.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+pub use super::tap::{Event, TapReader};
+
+#[derive(Default)]
+struct Client {
+    // arrival and timestamp of the last packet
+    last: Option<(u64, u64)>,
+    // the timestamp the next packet should have
+    next: u64,
+    // smoothed as in RFC 3550, in microseconds
+    jitter: f64,
+    // the earliest arrival relative to the stream's own clock, which latency is measured from
+    base: Option<i64>,
+}
+
+/// Follows a channel and summarizes it every so often.
+pub struct Stats {
+    sample_rate: u64,
+    clients: BTreeMap<u64, Client>,
+    peak: [f32; 2],
+    square_sum: [f64; 2],
+    samples: u64,
+    packets: u64,
+    gaps: u64,
+    lost_samples: u64,
+    latency: f64,
+    events: Vec<String>,
+}
+
+/// What happened on a channel since the last report.
+#[derive(RustcEncodable, Clone, PartialEq, Debug)]
+pub struct Report {
+    pub clients: usize,
+    /// Left and right peak levels, from 0 to 1.
+    pub peak: Vec<f32>,
+    /// Left and right RMS levels, from 0 to 1.
+    pub rms: Vec<f32>,
+    /// Packets per second.
+    pub packet_rate: f64,
+    /// The highest jitter among the clients.
+    pub jitter_ms: f64,
+    /// The most any packet was held up compared to the quickest one from its client. Clocks
+    /// aren't shared between machines, so this is delay added along the way, not transit time.
+    pub latency_ms: f64,
+    /// Breaks in the timestamps, where audio never arrived.
+    pub gaps: u64,
+    pub lost_samples: u64,
+    pub events: Vec<String>,
+}
+
+impl Stats {
+    pub fn new(sample_rate: u64) -> Stats {
+        Stats {
+            sample_rate: sample_rate,
+            clients: BTreeMap::new(),
+            peak: [0.0; 2],
+            square_sum: [0.0; 2],
+            samples: 0,
+            packets: 0,
+            gaps: 0,
+            lost_samples: 0,
+            latency: 0.0,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn observe(&mut self, event: &Event) {
+        match *event {
+            Event::Connect { client, ref peer } => {
+                self.clients.insert(client, Client::default());
+                self.events.push(format!("client {} connected from {}", client, peer));
+            }
+            Event::Disconnect { client } => {
+                self.clients.remove(&client);
+                self.events.push(format!("client {} disconnected", client));
+            }
+            Event::Packet { client, arrival, ref packet } => {
+                for (side, data) in [packet.get_ldata(), packet.get_rdata()].iter().enumerate() {
+                    for &x in data.iter() {
+                        self.peak[side] = self.peak[side].max(x.abs());
+                        self.square_sum[side] += x as f64 * x as f64;
+                    }
+                }
+                self.samples += packet.len() as u64;
+                self.packets += 1;
+
+                let rate = self.sample_rate as f64;
+                let timestamp = packet.get_timestamp();
+                let state = self.clients.entry(client).or_insert(Client::default());
+                if let Some((last_arrival, last_timestamp)) = state.last {
+                    if timestamp < last_timestamp {
+                        // the transmitter started over
+                        *state = Client::default();
+                    } else {
+                        if timestamp > state.next {
+                            self.gaps += 1;
+                            self.lost_samples += timestamp - state.next;
+                        }
+                        let transit = arrival as f64 - last_arrival as f64;
+                        let expected = (timestamp - last_timestamp) as f64 * 1e6 / rate;
+                        state.jitter += ((transit - expected).abs() - state.jitter) / 16.0;
+                    }
+                }
+                let offset = arrival as i64 - (timestamp as f64 * 1e6 / rate) as i64;
+                let base = state.base.map(|base| base.min(offset)).unwrap_or(offset);
+                state.base = Some(base);
+                self.latency = self.latency.max((offset - base) as f64 / 1000.0);
+                state.last = Some((arrival, timestamp));
+                state.next = timestamp + packet.len() as u64;
+            }
+        }
+    }
+
+    /// Sums up the `elapsed` time since the last report, and starts over.
+    pub fn report(&mut self, elapsed: Duration) -> Report {
+        let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let samples = self.samples.max(1) as f64;
+        let report = Report {
+            clients: self.clients.len(),
+            peak: self.peak.to_vec(),
+            rms: self.square_sum.iter().map(|&sum| (sum / samples).sqrt() as f32).collect(),
+            packet_rate: if seconds > 0.0 {
+                self.packets as f64 / seconds
+            } else {
+                0.0
+            },
+            jitter_ms: self.clients.values().map(|c| c.jitter / 1000.0).fold(0.0, f64::max),
+            latency_ms: self.latency,
+            gaps: self.gaps,
+            lost_samples: self.lost_samples,
+            events: self.events.clone(),
+        };
+        self.peak = [0.0; 2];
+        self.square_sum = [0.0; 2];
+        self.samples = 0;
+        self.packets = 0;
+        self.gaps = 0;
+        self.lost_samples = 0;
+        self.latency = 0.0;
+        self.events.clear();
+        report
+    }
+}
+
+#[cfg(test)]
+fn packet_event(client: u64, arrival: u64, timestamp: u64, level: f32) -> Event {
+    use super::packet::Packet;
+
+    Event::Packet {
+        client: client,
+        arrival: arrival,
+        packet: Packet::new(&[level; 100], &[-level / 2.0; 100], timestamp),
+    }
+}
+
+#[test]
+fn test_monitor_levels() {
+    let mut stats = Stats::new(100000);
+    stats.observe(&Event::Connect {
+        client: 0,
+        peer: "127.0.0.1:5000".to_string(),
+    });
+    stats.observe(&packet_event(0, 0, 0, 0.5));
+    stats.observe(&packet_event(0, 1000, 100, 0.5));
+    let report = stats.report(Duration::from_millis(500));
+    assert_eq!(report.clients, 1);
+    assert_eq!(report.peak, vec![0.5, 0.25]);
+    assert_eq!(report.rms, vec![0.5, 0.25]);
+    assert_eq!(report.packet_rate, 4.0);
+    assert_eq!(report.events, vec!["client 0 connected from 127.0.0.1:5000".to_string()]);
+
+    // everything but the clients starts over
+    stats.observe(&Event::Disconnect { client: 0 });
+    let report = stats.report(Duration::from_millis(500));
+    assert_eq!((report.clients, report.packet_rate), (0, 0.0));
+    assert_eq!(report.peak, vec![0.0, 0.0]);
+    assert_eq!(report.events, vec!["client 0 disconnected".to_string()]);
+}
+
+#[test]
+fn test_monitor_network() {
+    // at 100 kHz, a 100 sample packet lasts a millisecond
+    let mut stats = Stats::new(100000);
+    stats.observe(&packet_event(0, 0, 0, 0.1));
+    stats.observe(&packet_event(0, 1000, 100, 0.1));
+    let report = stats.report(Duration::from_secs(1));
+    assert_eq!((report.gaps, report.jitter_ms, report.latency_ms), (0, 0.0, 0.0));
+
+    // two packets went missing, and the next one was held up 2 ms
+    stats.observe(&packet_event(0, 6000, 400, 0.1));
+    let report = stats.report(Duration::from_secs(1));
+    assert_eq!((report.gaps, report.lost_samples), (1, 200));
+    assert_eq!(report.latency_ms, 2.0);
+    assert_eq!(report.jitter_ms, 2.0 / 16.0);
+
+    // a restarted transmitter isn't a gap
+    stats.observe(&packet_event(0, 7000, 0, 0.1));
+    assert_eq!(stats.report(Duration::from_secs(1)).gaps, 0);
+}
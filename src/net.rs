@@ -0,0 +1,158 @@
+// Socket setup shared by the transmitter and receiver.
+
+use std::io::{self, ErrorKind, Write};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+
+use libc;
+use mio::tcp::{TcpSocket, TcpListener};
+
+/// Parses an IPv4 or IPv6 literal. IPv6 addresses may be wrapped in brackets, as they are in URLs.
+pub fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    let s = if s.starts_with('[') && s.ends_with(']') {
+        &s[1..s.len() - 1]
+    } else {
+        s
+    };
+    s.parse().ok()
+}
+
+/// Turns an IPv4 address that arrived on a dual-stack socket, like `::ffff:10.0.0.1`, back into
+/// plain IPv4, so it can be matched against IPv4 networks.
+pub fn unmap(addr: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = addr {
+        let s = v6.segments();
+        if s[..5] == [0; 5] && s[5] == 0xffff {
+            return IpAddr::V4(v6.to_ipv4().unwrap());
+        }
+    }
+    addr
+}
+
+/// Listens on `addr`. Listening on the IPv6 any address (`::`) also takes IPv4 clients, whatever
+/// the system default is.
+pub fn listen(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let socket = try!(match *addr {
+        SocketAddr::V4(..) => TcpSocket::v4(),
+        SocketAddr::V6(..) => TcpSocket::v6(),
+    });
+    try!(socket.set_reuseaddr(true));
+    if let SocketAddr::V6(ref v6) = *addr {
+        if v6.ip().segments() == [0; 8] {
+            try!(set_v6only(&socket, false));
+        }
+    }
+    try!(socket.bind(addr));
+    socket.listen(1024)
+}
+
+/// Writes as much of `bytes` past `written` as `socket` takes without blocking, moving `written`
+/// along. Returns true once all of it has gone.
+pub fn write_some<S: Write>(socket: &mut S, bytes: &[u8], written: &mut usize) -> io::Result<bool> {
+    while *written < bytes.len() {
+        match socket.write(&bytes[*written..]) {
+            Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "wrote zero bytes")),
+            Ok(n) => *written += n,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                          e.kind() == ErrorKind::NotConnected => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn set_v6only(socket: &TcpSocket, v6only: bool) -> io::Result<()> {
+    let value = v6only as libc::c_int;
+    let res = unsafe {
+        libc::setsockopt(socket.as_raw_fd(),
+                         libc::IPPROTO_IPV6,
+                         libc::IPV6_V6ONLY,
+                         &value as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+fn round_trip(listen_on: &str, connect_to: &str) -> SocketAddr {
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::Duration;
+    use mio::tcp::TcpStream;
+    use super::packet::{Frame, FrameReader, FrameWriter, Protection};
+
+    let listener = listen(&format!("{}:0", listen_on).parse().unwrap()).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut client = TcpStream::connect(&format!("{}:{}", connect_to, port).parse().unwrap())
+                         .unwrap();
+    let mut server;
+    loop {
+        match listener.accept().unwrap() {
+            Some(s) => {
+                server = s;
+                break;
+            }
+            None => thread::sleep(Duration::from_millis(1)),
+        }
+    }
+
+    let frame = Frame::Silent {
+        timestamp: 42,
+        length: 7,
+    };
+    let bytes = FrameWriter::new(Protection::Plain).write(&frame);
+    let mut written = 0;
+    while written < bytes.len() {
+        match client.write(&bytes[written..]) {
+            Ok(n) => written += n,
+            Err(_) => thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    let mut reader = FrameReader::new(Protection::Plain);
+    let mut buf = [0; 64];
+    loop {
+        if let Ok(n) = server.read(&mut buf) {
+            reader.push(&buf[..n]);
+        }
+        match reader.next_frame().unwrap() {
+            Some(Frame::Silent { timestamp, length }) => {
+                assert_eq!((timestamp, length), (42, 7));
+                break;
+            }
+            Some(_) => panic!("expected a silent frame"),
+            None => thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    server.peer_addr().unwrap()
+}
+
+#[test]
+fn test_net_parse_ip() {
+    assert_eq!(parse_ip("::1"), Some("::1".parse().unwrap()));
+    assert_eq!(parse_ip("[::1]"), Some("::1".parse().unwrap()));
+    assert_eq!(parse_ip(" 10.0.0.1 "), Some("10.0.0.1".parse().unwrap()));
+    assert_eq!(parse_ip("[10.0.0.1"), None);
+    assert_eq!(parse_ip("localhost"), None);
+    assert_eq!(unmap("::ffff:10.0.0.1".parse().unwrap()),
+               "10.0.0.1".parse::<IpAddr>().unwrap());
+    assert_eq!(unmap("::1".parse().unwrap()), "::1".parse::<IpAddr>().unwrap());
+}
+
+#[test]
+fn test_net_ipv6() {
+    let peer = round_trip("[::1]", "[::1]");
+    assert_eq!(peer.ip(), "::1".parse::<IpAddr>().unwrap());
+}
+
+#[test]
+fn test_net_dual_stack() {
+    let peer = round_trip("[::]", "127.0.0.1");
+    assert_eq!(unmap(peer.ip()), "127.0.0.1".parse::<IpAddr>().unwrap());
+    round_trip("[::]", "[::1]");
+}
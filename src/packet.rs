@@ -1,30 +1,65 @@
-use ladspa::Data;
+use std::io::{self, ErrorKind, Read, Write};
+use std::ptr;
+use std::slice;
 
-use bincode::SizeLimit;
-use bincode::rustc_serialize::{encode, decode};
+use super::codec::CodedPacket;
+use super::auth::{self, Authenticator, CHALLENGE_SIZE};
+use super::secure::{self, Cipher, Handshake, HELLO_SIZE};
+use super::config::{Config, Security};
 
 pub const BUFFER_SIZE: usize = 1024;
-pub const BYTE_BUFFER_SIZE: usize = BUFFER_SIZE * 4 * 2 + 8 * 2 + 8; // data + data size + timestamp
+// the longest block a frame may hold. Transmitters split longer pushes, and receivers refuse
+// silent frames claiming more, which would otherwise be theirs to allocate.
+pub const MAX_BLOCK: usize = 1 << 16;
+
+// frames larger than this are assumed to be garbage rather than audio
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+const HEADER_SIZE: usize = 4;
+// a packet's timestamp and length, ahead of its samples
+const PACKET_HEADER_SIZE: usize = 12;
+
+// the first byte of a frame's payload says what kind it is
+const RAW: u8 = 0;
+const CODED: u8 = 1;
+const SILENT: u8 = 2;
 
 #[derive(RustcEncodable, RustcDecodable, Clone)]
 pub struct Packet {
-    ldata: Vec<Data>,
-    rdata: Vec<Data>,
+    ldata: Vec<f32>,
+    rdata: Vec<f32>,
     timestamp: u64,
 }
 
 impl Packet {
     pub fn parse(bytes: &[u8]) -> Packet {
-        decode(bytes).unwrap()
+        let mut packet = Packet::zeroed(0, 0);
+        packet.parse_into(bytes).unwrap();
+        packet
     }
 
-    pub fn new(ldata: &[Data], rdata: &[Data], time: u64) -> Packet {
-        assert_eq!(ldata.len(), BUFFER_SIZE);
-        assert_eq!(rdata.len(), BUFFER_SIZE);
+    /// Reads a packet written by `write_to` into this one, reusing its buffers. Fails if the
+    /// bytes aren't the right length for the samples they claim to hold.
+    pub fn parse_into(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if bytes.len() < PACKET_HEADER_SIZE {
+            return Err(format!("packet of {} bytes is too short", bytes.len()));
+        }
+        let len = read_u32(&bytes[8..]) as usize;
+        let samples = &bytes[PACKET_HEADER_SIZE..];
+        if len.checked_mul(8) != Some(samples.len()) {
+            return Err(format!("packet of {} samples in {} bytes", len, bytes.len()));
+        }
+        self.timestamp = read_u64(bytes);
+        read_samples(&samples[..len * 4], &mut self.ldata);
+        read_samples(&samples[len * 4..], &mut self.rdata);
+        Ok(())
+    }
+
+    pub fn new(ldata: &[f32], rdata: &[f32], time: u64) -> Packet {
+        assert_eq!(ldata.len(), rdata.len());
 
         let mut packet = Packet {
-            ldata: vec![0f32; BUFFER_SIZE],
-            rdata: vec![0f32; BUFFER_SIZE],
+            ldata: vec![0f32; ldata.len()],
+            rdata: vec![0f32; rdata.len()],
             timestamp: time,
         };
         (&mut packet.ldata[..]).clone_from_slice(ldata);
@@ -33,21 +68,49 @@ impl Packet {
         packet
     }
 
+    /// A packet of digital silence, as sent in place of a silent block.
+    pub fn zeroed(time: u64, len: usize) -> Packet {
+        Packet {
+            ldata: vec![0f32; len],
+            rdata: vec![0f32; len],
+            timestamp: time,
+        }
+    }
+
     pub fn as_bytes(&self) -> Vec<u8> {
-        let bytes = encode(self, SizeLimit::Infinite).unwrap();
-        assert_eq!(bytes.len(), BYTE_BUFFER_SIZE);
+        let mut bytes = Vec::with_capacity(PACKET_HEADER_SIZE + self.len() * 8);
+        self.write_to(&mut bytes);
         bytes
     }
 
-    pub fn get_ldata(&self) -> &[Data] {
+    /// Appends the packet to `out`: its timestamp as a u64, its length as a u32, then the left
+    /// and right samples, all little endian.
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        out.reserve(PACKET_HEADER_SIZE + self.len() * 8);
+        write_u64(out, self.timestamp);
+        write_u32(out, self.len() as u32);
+        write_samples(out, &self.ldata);
+        write_samples(out, &self.rdata);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ldata.len()
+    }
+
+    pub fn get_ldata(&self) -> &[f32] {
         &self.ldata[..]
     }
 
-    pub fn get_rdata(&self) -> &[Data] {
+    pub fn get_rdata(&self) -> &[f32] {
         &self.rdata[..]
     }
 
-    pub fn read(&self, time: u64) -> (Data, Data) {
+    /// Largest absolute sample value in either channel.
+    pub fn peak(&self) -> f32 {
+        self.ldata.iter().chain(self.rdata.iter()).fold(0_f32, |peak, x| peak.max(x.abs()))
+    }
+
+    pub fn read(&self, time: u64) -> (f32, f32) {
         if !self.active(time) {
             return (0_f32, 0_f32);
         }
@@ -62,7 +125,13 @@ impl Packet {
     }
 
     pub fn complete(&self, time: u64) -> bool {
-        time >= self.timestamp + BUFFER_SIZE as u64
+        time >= self.timestamp + self.len() as u64
+    }
+
+    /// Number of samples left to read at or after `time`.
+    pub fn remaining(&self, time: u64) -> usize {
+        let end = self.timestamp + self.len() as u64;
+        (end - end.min(time.max(self.timestamp))) as usize
     }
 
     pub fn get_timestamp(&self) -> u64 {
@@ -70,6 +139,425 @@ impl Packet {
     }
 }
 
+/// A unit of data on the wire. Each frame is sent as a little endian u32 length followed by that
+/// many bytes of payload. The payload is a byte giving the kind of frame, then its fields in a
+/// fixed little endian layout.
+#[derive(RustcEncodable, RustcDecodable, Clone)]
+pub enum Frame {
+    Raw(Packet),
+    Coded(CodedPacket),
+    /// A block quiet enough that it isn't worth sending. Stands in for a zeroed packet.
+    Silent {
+        timestamp: u64,
+        length: u32,
+    },
+}
+
+impl Frame {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes);
+        bytes
+    }
+
+    /// Appends the frame to `out`, length first.
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend(&[0; HEADER_SIZE]);
+        match *self {
+            Frame::Raw(ref packet) => {
+                out.push(RAW);
+                packet.write_to(out);
+            }
+            Frame::Coded(ref coded) => {
+                out.push(CODED);
+                coded.write_to(out);
+            }
+            Frame::Silent { timestamp, length } => {
+                out.push(SILENT);
+                write_u64(out, timestamp);
+                write_u32(out, length);
+            }
+        }
+        let len = out.len() - start - HEADER_SIZE;
+        put_u32(&mut out[start..], len as u32);
+    }
+
+    /// Reads a frame from its payload, as written by `write_to` after the length.
+    pub fn parse(payload: &[u8]) -> Result<Frame, String> {
+        let (&kind, fields) = try!(payload.split_first().ok_or("empty frame".to_string()));
+        match kind {
+            RAW => {
+                let mut packet = Packet::zeroed(0, 0);
+                try!(packet.parse_into(fields));
+                Ok(Frame::Raw(packet))
+            }
+            CODED => CodedPacket::parse(fields).map(Frame::Coded),
+            SILENT if fields.len() == 12 => {
+                let length = read_u32(&fields[8..]);
+                if length as usize > MAX_BLOCK {
+                    return Err(format!("silent frame of {} samples", length));
+                }
+                Ok(Frame::Silent {
+                    timestamp: read_u64(fields),
+                    length: length,
+                })
+            }
+            SILENT => Err(format!("silent frame of {} bytes", payload.len())),
+            _ => Err(format!("unknown kind of frame {}", kind)),
+        }
+    }
+
+    /// Like `parse`, but reads into this frame, reusing its buffers if it's the same kind. If it
+    /// fails, the frame is left holding nothing in particular.
+    pub fn parse_into(&mut self, payload: &[u8]) -> Result<(), String> {
+        let (&kind, fields) = try!(payload.split_first().ok_or("empty frame".to_string()));
+        match (kind, self) {
+            (RAW, &mut Frame::Raw(ref mut packet)) => packet.parse_into(fields),
+            (CODED, &mut Frame::Coded(ref mut coded)) => coded.parse_into(fields),
+            (_, frame) => {
+                *frame = try!(Frame::parse(payload));
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+pub fn read_u64(bytes: &[u8]) -> u64 {
+    read_u32(bytes) as u64 | (read_u32(&bytes[4..]) as u64) << 32
+}
+
+pub fn write_u32(out: &mut Vec<u8>, x: u32) {
+    out.extend(&[x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8]);
+}
+
+pub fn write_u64(out: &mut Vec<u8>, x: u64) {
+    write_u32(out, x as u32);
+    write_u32(out, (x >> 32) as u32);
+}
+
+fn put_u32(bytes: &mut [u8], x: u32) {
+    bytes[0] = x as u8;
+    bytes[1] = (x >> 8) as u8;
+    bytes[2] = (x >> 16) as u8;
+    bytes[3] = (x >> 24) as u8;
+}
+
+// On little endian machines samples are already laid out the way they go on the wire, so they're
+// copied straight across.
+#[cfg(target_endian = "little")]
+fn read_samples(bytes: &[u8], samples: &mut Vec<f32>) {
+    let len = bytes.len() / 4;
+    samples.clear();
+    samples.reserve(len);
+    unsafe {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), samples.as_mut_ptr() as *mut u8, len * 4);
+        samples.set_len(len);
+    }
+}
+
+#[cfg(target_endian = "little")]
+fn write_samples(out: &mut Vec<u8>, samples: &[f32]) {
+    let bytes = unsafe { slice::from_raw_parts(samples.as_ptr() as *const u8, samples.len() * 4) };
+    out.extend_from_slice(bytes);
+}
+
+#[cfg(target_endian = "big")]
+fn read_samples(bytes: &[u8], samples: &mut Vec<f32>) {
+    samples.clear();
+    samples.extend(bytes.chunks(4).map(|b| f32::from_bits(read_u32(b))));
+}
+
+#[cfg(target_endian = "big")]
+fn write_samples(out: &mut Vec<u8>, samples: &[f32]) {
+    let start = out.len();
+    out.resize(start + samples.len() * 4, 0);
+    for (bytes, x) in out[start..].chunks_mut(4).zip(samples) {
+        put_u32(bytes, x.to_bits());
+    }
+}
+
+fn with_header(payload: Vec<u8>) -> Vec<u8> {
+    let len = payload.len();
+    let mut bytes = vec![len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8];
+    bytes.extend(payload);
+    bytes
+}
+
+/// How the frames on one connection are protected.
+pub enum Protection {
+    Plain,
+    Signed(Authenticator),
+    Encrypted(Cipher),
+}
+
+impl Protection {
+    /// Sets up protection on a freshly opened connection, from the side that opened it.
+    pub fn initiate<S: Read + Write>(stream: &mut S, config: &Config) -> io::Result<Protection> {
+        match config.key {
+            None => Ok(Protection::Plain),
+            Some(ref key) => {
+                match config.security {
+                    Security::Sign => {
+                        let challenge = try!(secure::read_hello(stream, secure::deadline()));
+                        Authenticator::new(key, &challenge).map(Protection::Signed)
+                    }
+                    Security::Encrypt => {
+                        secure::handshake_initiator(stream, key).map(Protection::Encrypted)
+                    }
+                }
+            }
+        }
+    }
+
+    fn tag_size(&self) -> usize {
+        match *self {
+            Protection::Plain => 0,
+            Protection::Signed(_) => auth::TAG_SIZE,
+            Protection::Encrypted(_) => secure::TAG_SIZE,
+        }
+    }
+}
+
+/// Sets up protection on a non-blocking socket a little at a time, for connections run by the
+/// reactor. Call `advance` whenever the socket is ready until it gives the protection. Stalled
+/// handshakes are the caller's to time out.
+pub struct Negotiation {
+    done: Option<Protection>,
+    // only for encryption; signed connections just need the responder's challenge
+    handshake: Option<Handshake>,
+    key: Vec<u8>,
+    initiator: bool,
+    hello: Vec<u8>,
+    written: usize,
+    peer: Vec<u8>,
+    peer_size: usize,
+}
+
+impl Negotiation {
+    /// The side that opened the connection, which sends its hello first.
+    pub fn initiate(config: &Config) -> io::Result<Negotiation> {
+        Negotiation::new(config, true)
+    }
+
+    /// The side that accepted the connection.
+    pub fn respond(config: &Config) -> io::Result<Negotiation> {
+        Negotiation::new(config, false)
+    }
+
+    fn new(config: &Config, initiator: bool) -> io::Result<Negotiation> {
+        let mut negotiation = Negotiation {
+            done: None,
+            handshake: None,
+            key: Vec::new(),
+            initiator: initiator,
+            hello: Vec::new(),
+            written: 0,
+            peer: Vec::new(),
+            peer_size: 0,
+        };
+        match config.key {
+            None => negotiation.done = Some(Protection::Plain),
+            Some(ref key) => {
+                negotiation.key = key.clone();
+                match config.security {
+                    Security::Sign if initiator => negotiation.peer_size = CHALLENGE_SIZE,
+                    Security::Sign => negotiation.hello = try!(auth::challenge()),
+                    Security::Encrypt => {
+                        let handshake = try!(Handshake::new());
+                        negotiation.hello = handshake.hello();
+                        negotiation.handshake = Some(handshake);
+                        negotiation.peer_size = HELLO_SIZE;
+                    }
+                }
+            }
+        }
+        Ok(negotiation)
+    }
+
+    /// Reads and writes as much of the handshake as `stream` will take without blocking. Returns
+    /// the protection once the hellos have crossed, and `None` until then.
+    pub fn advance<S: Read + Write>(&mut self, stream: &mut S) -> io::Result<Option<Protection>> {
+        if let Some(protection) = self.done.take() {
+            return Ok(Some(protection));
+        }
+        loop {
+            let writing = self.written < self.hello.len();
+            let reading = self.peer.len() < self.peer_size;
+            let res = if writing && (self.initiator || !reading) {
+                stream.write(&self.hello[self.written..]).map(|n| {
+                    self.written += n;
+                    n
+                })
+            } else if reading {
+                let mut buf = [0u8; HELLO_SIZE];
+                let want = self.peer_size - self.peer.len();
+                stream.read(&mut buf[..want]).map(|n| {
+                    self.peer.extend(&buf[..n]);
+                    n
+                })
+            } else {
+                return self.finish().map(Some);
+            };
+            match res {
+                Ok(0) => {
+                    return Err(io::Error::new(ErrorKind::UnexpectedEof,
+                                              "connection closed in handshake"))
+                }
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                              e.kind() == ErrorKind::NotConnected => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn finish(&self) -> io::Result<Protection> {
+        match self.handshake {
+            Some(ref handshake) if self.initiator => {
+                handshake.finish_initiator(&self.key, &self.peer).map(Protection::Encrypted)
+            }
+            Some(ref handshake) => {
+                handshake.finish_responder(&self.key, &self.peer).map(Protection::Encrypted)
+            }
+            None => {
+                let challenge = if self.initiator { &self.peer } else { &self.hello };
+                Authenticator::new(&self.key, challenge).map(Protection::Signed)
+            }
+        }
+    }
+}
+
+/// Turns frames into bytes for a single connection.
+pub struct FrameWriter {
+    protection: Protection,
+}
+
+impl FrameWriter {
+    pub fn new(protection: Protection) -> FrameWriter {
+        FrameWriter { protection: protection }
+    }
+
+    pub fn write(&mut self, frame: &Frame) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_into(frame, &mut bytes);
+        bytes
+    }
+
+    /// Like `write`, but appends to `out`, so a connection can keep one buffer for every frame.
+    pub fn write_into(&mut self, frame: &Frame, out: &mut Vec<u8>) {
+        let start = out.len();
+        frame.write_to(out);
+        self.seal(out, start);
+    }
+
+    /// Frames and protects an arbitrary payload, for streams that carry something other than
+    /// audio frames.
+    pub fn write_payload(&mut self, payload: Vec<u8>) -> Vec<u8> {
+        let mut bytes = with_header(payload);
+        self.seal(&mut bytes, 0);
+        bytes
+    }
+
+    // protects the frame that starts at `start`
+    fn seal(&mut self, bytes: &mut Vec<u8>, start: usize) {
+        match self.protection {
+            Protection::Plain => {}
+            Protection::Signed(ref mut auth) => {
+                let tag = auth.sign(&bytes[start..]);
+                bytes.extend(tag);
+            }
+            Protection::Encrypted(ref mut cipher) => {
+                let body = start + HEADER_SIZE;
+                let sealed = cipher.seal(&bytes[start..body], &bytes[body..]);
+                bytes.truncate(body);
+                bytes.extend(sealed);
+            }
+        }
+    }
+}
+
+/// Reassembles frames from a stream of bytes which may arrive in arbitrary pieces. Frames that
+/// don't pass the connection's protection are rejected.
+pub struct FrameReader {
+    buf: Vec<u8>,
+    protection: Protection,
+}
+
+impl FrameReader {
+    pub fn new(protection: Protection) -> FrameReader {
+        FrameReader {
+            buf: Vec::new(),
+            protection: protection,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend(bytes.iter().cloned());
+    }
+
+    /// Returns the next complete frame, if there is one. Fails if the stream doesn't look like a
+    /// stream of frames, after which the connection should be dropped.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, String> {
+        match try!(self.next_with(Frame::parse)) {
+            Some(frame) => frame.map(Some).map_err(|e| format!("malformed frame: {}", e)),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `next_frame`, but reads the frame into `frame`, so a connection can keep one for
+    /// every frame it reads. Returns whether there was a frame.
+    pub fn next_frame_into(&mut self, frame: &mut Frame) -> Result<bool, String> {
+        match try!(self.next_with(|payload| frame.parse_into(payload))) {
+            Some(parsed) => parsed.map(|_| true).map_err(|e| format!("malformed frame: {}", e)),
+            None => Ok(false),
+        }
+    }
+
+    /// Like `next_frame`, but leaves decoding the payload to the caller.
+    pub fn next_payload(&mut self) -> Result<Option<Vec<u8>>, String> {
+        self.next_with(|payload| payload.to_vec())
+    }
+
+    // hands the next payload to `f` where it lies in the buffer, unless it had to be decrypted
+    fn next_with<T, F: FnOnce(&[u8]) -> T>(&mut self, f: F) -> Result<Option<T>, String> {
+        if self.buf.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        let len = read_u32(&self.buf) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(format!("frame of {} bytes is too large", len));
+        }
+        let total = HEADER_SIZE + len + self.protection.tag_size();
+        if self.buf.len() < total {
+            return Ok(None);
+        }
+        let value = match self.protection {
+            Protection::Plain => f(&self.buf[HEADER_SIZE..total]),
+            Protection::Signed(ref mut auth) => {
+                let (frame, tag) = self.buf[..total].split_at(HEADER_SIZE + len);
+                if !auth.verify(frame, tag) {
+                    return Err("bad signature".to_string());
+                }
+                f(&frame[HEADER_SIZE..])
+            }
+            Protection::Encrypted(ref mut cipher) => {
+                let (header, sealed) = self.buf[..total].split_at(HEADER_SIZE);
+                match cipher.open(header, sealed) {
+                    Some(payload) => f(&payload),
+                    None => return Err("frame failed to decrypt".to_string()),
+                }
+            }
+        };
+        self.buf.drain(..total);
+        Ok(Some(value))
+    }
+}
+
 #[test]
 fn test_packet_serialize() {
     let ldata = vec![1.0; BUFFER_SIZE];
@@ -81,7 +569,55 @@ fn test_packet_serialize() {
     assert_eq!(parsed.get_ldata(), ldata.as_slice());
     assert_eq!(parsed.get_rdata(), rdata.as_slice());
     assert_eq!(&new.as_bytes()[..], &parsed.as_bytes()[..]);
-    println!("{}", new.as_bytes().len());
+    // timestamp + length + data
+    assert_eq!(new.as_bytes().len(), 8 + 4 + BUFFER_SIZE * 4 * 2);
+}
+
+#[test]
+fn test_packet_layout() {
+    let packet = Packet::new(&[1.0, -2.0], &[0.5, 0.0], 0x0102030405060708);
+    assert_eq!(packet.as_bytes(),
+               vec![8, 7, 6, 5, 4, 3, 2, 1, 2, 0, 0, 0, 0, 0, 0x80, 0x3f, 0, 0, 0, 0xc0, 0, 0,
+                    0, 0x3f, 0, 0, 0, 0]);
+
+    // parsing into an old packet reuses it, whatever its size was
+    let mut reused = Packet::zeroed(0, BUFFER_SIZE);
+    reused.parse_into(&packet.as_bytes()).unwrap();
+    assert_eq!(reused.get_timestamp(), 0x0102030405060708);
+    assert_eq!(reused.get_ldata(), &[1.0, -2.0]);
+    assert_eq!(reused.get_rdata(), &[0.5, 0.0]);
+    assert!(reused.parse_into(&packet.as_bytes()[..27]).is_err());
+    assert!(reused.parse_into(&[0; 4]).is_err());
+
+    let frames = [Frame::Raw(packet),
+                  Frame::Silent {
+                      timestamp: 7,
+                      length: 64,
+                  }];
+    let mut bytes = Vec::new();
+    let mut writer = FrameWriter::new(Protection::Plain);
+    for frame in &frames {
+        writer.write_into(frame, &mut bytes);
+    }
+    let mut reader = FrameReader::new(Protection::Plain);
+    reader.push(&bytes);
+    for frame in &frames {
+        assert_eq!(reader.next_frame().unwrap().unwrap().as_bytes(), frame.as_bytes());
+    }
+    // or into one frame, whatever kind it held before
+    reader.push(&bytes);
+    reader.push(&bytes);
+    let mut reused = Frame::Silent {
+        timestamp: 0,
+        length: 0,
+    };
+    for frame in frames.iter().chain(&frames) {
+        assert!(reader.next_frame_into(&mut reused).unwrap());
+        assert_eq!(reused.as_bytes(), frame.as_bytes());
+    }
+    assert!(!reader.next_frame_into(&mut reused).unwrap());
+    assert!(Frame::parse(&[9]).is_err());
+    assert!(Frame::parse(&[SILENT, 0]).is_err());
 }
 
 #[test]
@@ -120,3 +656,140 @@ fn test_packet_active_complete() {
     assert!(!packet.active(100 + BUFFER_SIZE as u64));
     assert!(packet.complete(100 + BUFFER_SIZE as u64));
 }
+
+#[test]
+fn test_frame_reader() {
+    let ldata = vec![1.0; BUFFER_SIZE];
+    let rdata = vec![2.0; BUFFER_SIZE];
+    let first = Frame::Raw(Packet::new(&ldata, &rdata, 0)).as_bytes();
+    let second = Frame::Raw(Packet::new(&rdata, &ldata, BUFFER_SIZE as u64)).as_bytes();
+    let mut reader = FrameReader::new(Protection::Plain);
+
+    // feed the frames in awkward pieces
+    reader.push(&first[..3]);
+    assert!(reader.next_frame().unwrap().is_none());
+    reader.push(&first[3..100]);
+    assert!(reader.next_frame().unwrap().is_none());
+    reader.push(&first[100..]);
+    reader.push(&second[..10]);
+    match reader.next_frame().unwrap() {
+        Some(Frame::Raw(packet)) => assert_eq!(packet.get_timestamp(), 0),
+        _ => panic!("expected a raw frame"),
+    }
+    assert!(reader.next_frame().unwrap().is_none());
+    reader.push(&second[10..]);
+    match reader.next_frame().unwrap() {
+        Some(Frame::Raw(packet)) => {
+            assert_eq!(packet.get_timestamp(), BUFFER_SIZE as u64);
+            assert_eq!(packet.get_ldata(), rdata.as_slice());
+        }
+        _ => panic!("expected a raw frame"),
+    }
+}
+
+#[test]
+fn test_packet_variable_length() {
+    let ldata = vec![1.0; 64];
+    let rdata = vec![2.0; 64];
+    let packet = Packet::new(&ldata, &rdata, 100);
+    assert_eq!(packet.len(), 64);
+    assert_eq!((1.0, 2.0), packet.read(163));
+    assert_eq!((0.0, 0.0), packet.read(164));
+    assert!(packet.active(163));
+    assert!(packet.complete(164));
+    assert_eq!(packet.remaining(0), 64);
+    assert_eq!(packet.remaining(100), 64);
+    assert_eq!(packet.remaining(150), 14);
+    assert_eq!(packet.remaining(164), 0);
+    assert_eq!(packet.remaining(1000), 0);
+    let parsed = Packet::parse(&packet.as_bytes()[..]);
+    assert_eq!(parsed.get_ldata(), ldata.as_slice());
+}
+
+#[test]
+fn test_packet_zeroed() {
+    let ldata = vec![0.25; BUFFER_SIZE];
+    let rdata = vec![-0.5; BUFFER_SIZE];
+    assert_eq!(Packet::new(&ldata, &rdata, 0).peak(), 0.5);
+
+    let packet = Packet::zeroed(100, BUFFER_SIZE);
+    assert_eq!(packet.peak(), 0.0);
+    assert!(packet.active(100));
+    assert!(packet.complete(100 + BUFFER_SIZE as u64));
+    let silent = Frame::Silent {
+        timestamp: 100,
+        length: BUFFER_SIZE as u32,
+    };
+    assert!(silent.as_bytes().len() < 32);
+
+    // a peer can't make the receiver fill more silence than a frame could hold
+    let longest = Frame::Silent {
+        timestamp: 0,
+        length: MAX_BLOCK as u32,
+    };
+    assert!(Frame::parse(&longest.as_bytes()[HEADER_SIZE..]).is_ok());
+    let forged = Frame::Silent {
+        timestamp: 0,
+        length: u32::max_value(),
+    };
+    let mut reader = FrameReader::new(Protection::Plain);
+    reader.push(&forged.as_bytes());
+    assert!(reader.next_frame().is_err());
+}
+
+#[test]
+fn test_frame_reader_garbage() {
+    let mut reader = FrameReader::new(Protection::Plain);
+    reader.push(&[0xff; 16]);
+    assert!(reader.next_frame().is_err());
+}
+
+// signs as if on a connection that opened with the same challenge every time
+#[cfg(test)]
+fn signed(key: &[u8]) -> Protection {
+    let mut challenge = b"FBS1".to_vec();
+    challenge.resize(CHALLENGE_SIZE, 7);
+    Protection::Signed(Authenticator::new(key, &challenge).unwrap())
+}
+
+#[test]
+fn test_frame_signed() {
+    let ldata = vec![1.0; BUFFER_SIZE];
+    let frame = Frame::Raw(Packet::new(&ldata, &ldata, 0));
+    let mut writer = FrameWriter::new(signed(b"key"));
+    let mut bytes = writer.write(&frame);
+    bytes.extend(writer.write(&frame));
+
+    let mut reader = FrameReader::new(signed(b"key"));
+    reader.push(&bytes);
+    assert!(reader.next_frame().unwrap().is_some());
+    assert!(reader.next_frame().unwrap().is_some());
+    assert!(reader.next_frame().unwrap().is_none());
+}
+
+#[test]
+fn test_frame_unsigned_rejected() {
+    let ldata = vec![1.0; BUFFER_SIZE];
+    let frame = Frame::Raw(Packet::new(&ldata, &ldata, 0));
+    let mut bytes = FrameWriter::new(Protection::Plain).write(&frame);
+    bytes.extend(FrameWriter::new(Protection::Plain).write(&frame));
+    let mut reader = FrameReader::new(signed(b"key"));
+    reader.push(&bytes);
+    assert!(reader.next_frame().is_err());
+}
+
+#[test]
+fn test_frame_forged_rejected() {
+    let ldata = vec![1.0; BUFFER_SIZE];
+    let frame = Frame::Raw(Packet::new(&ldata, &ldata, 0));
+    let mut bytes = FrameWriter::new(signed(b"key")).write(&frame);
+    bytes[100] ^= 1;
+    let mut reader = FrameReader::new(signed(b"key"));
+    reader.push(&bytes);
+    assert!(reader.next_frame().is_err());
+
+    let bytes = FrameWriter::new(signed(b"other key")).write(&frame);
+    let mut reader = FrameReader::new(signed(b"key"));
+    reader.push(&bytes);
+    assert!(reader.next_frame().is_err());
+}
@@ -0,0 +1,396 @@
+// The LADSPA plugins. They only read their ports and pass them on to a `FeedbackSender` or
+// `FeedbackReceiver`, which do the real work.
+
+use ladspa::{PluginDescriptor, Plugin, PortConnection};
+use ladspa::{Port, PortDescriptor};
+use ladspa::{PROP_NONE, HINT_INTEGER, HINT_TOGGLED, DefaultValue};
+
+use super::SESSIONS;
+use super::codec::{MIN_BITRATE, MAX_BITRATE};
+use super::config::Config;
+use super::delay::{DelayLine, MAX_DELAY};
+use super::packet::BUFFER_SIZE;
+use super::receive::FeedbackReceiver;
+use super::transmit::FeedbackSender;
+
+// The Session ports start at -1, which stands for the configured default session.
+fn session(port: f32, default: u16) -> u16 {
+    if port < 0.0 {
+        default
+    } else {
+        port as u16
+    }
+}
+
+pub struct Transmitter {
+    sender: FeedbackSender,
+    dry: DelayLine,
+    // the receivers' latency, held from when it's first heard of until the plugin is activated
+    // again, so the dry delay and the latency the host sees stay put
+    measured: Option<usize>,
+    default_session: u16,
+}
+
+impl Transmitter {
+    pub fn new(_: &PluginDescriptor, sample_rate: u64) -> Box<Plugin + Send> {
+        let config = Config::load();
+        Box::new(Transmitter {
+            default_session: config.session,
+            sender: FeedbackSender::new(config, sample_rate),
+            dry: DelayLine::new(),
+            measured: None,
+        })
+    }
+
+    pub fn get_descriptor() -> PluginDescriptor {
+        PluginDescriptor {
+            unique_id: 5877,
+            label: "feedback_tx",
+            properties: PROP_NONE,
+            name: "Feedback Transmitter",
+            maker: "Noah Weninger",
+            copyright: "None",
+            ports: vec![Port {
+                            name: "Left Audio In",
+                            desc: PortDescriptor::AudioInput,
+                            ..Default::default()
+                        },
+                        Port {
+                            name: "Right Audio In",
+                            desc: PortDescriptor::AudioInput,
+                            ..Default::default()
+                        },
+                        Port {
+                            name: "Left Audio Out",
+                            desc: PortDescriptor::AudioOutput,
+                            ..Default::default()
+                        },
+                        Port {
+                            name: "Right Audio Out",
+                            desc: PortDescriptor::AudioOutput,
+                            ..Default::default()
+                        },
+                        Port {
+                            name: "Channel",
+                            desc: PortDescriptor::ControlInput,
+                            hint: Some(HINT_INTEGER),
+                            default: Some(DefaultValue::Value0),
+                            lower_bound: Some(0_f32),
+                            upper_bound: Some(255_f32),
+                        },
+                        Port {
+                            name: "Dry",
+                            desc: PortDescriptor::ControlInput,
+                            hint: None,
+                            default: Some(DefaultValue::Value1),
+                            lower_bound: Some(0_f32),
+                            upper_bound: Some(1_f32),
+                        },
+                        Port {
+                            name: "Send",
+                            desc: PortDescriptor::ControlInput,
+                            hint: None,
+                            default: Some(DefaultValue::Value1),
+                            lower_bound: Some(0_f32),
+                            upper_bound: Some(1_f32),
+                        },
+                        Port {
+                            name: "Lossy Codec",
+                            desc: PortDescriptor::ControlInput,
+                            hint: Some(HINT_TOGGLED),
+                            default: Some(DefaultValue::Value0),
+                            lower_bound: None,
+                            upper_bound: None,
+                        },
+                        Port {
+                            name: "Bitrate (kbps)",
+                            desc: PortDescriptor::ControlInput,
+                            hint: Some(HINT_INTEGER),
+                            default: Some(DefaultValue::Middle),
+                            lower_bound: Some(MIN_BITRATE as f32),
+                            upper_bound: Some(MAX_BITRATE as f32),
+                        },
+                        Port {
+                            name: "Codec Delay",
+                            desc: PortDescriptor::ControlOutput,
+                            hint: Some(HINT_INTEGER),
+                            default: None,
+                            lower_bound: Some(0_f32),
+                            upper_bound: None,
+                        },
+                        Port {
+                            name: "Silence Threshold (dB)",
+                            desc: PortDescriptor::ControlInput,
+                            hint: None,
+                            default: Some(DefaultValue::Minimum),
+                            lower_bound: Some(-144_f32),
+                            upper_bound: Some(0_f32),
+                        },
+                        Port {
+                            name: "Block Size",
+                            desc: PortDescriptor::ControlInput,
+                            hint: Some(HINT_INTEGER),
+                            default: Some(DefaultValue::Maximum),
+                            lower_bound: Some(0_f32),
+                            upper_bound: Some(BUFFER_SIZE as f32),
+                        },
+                        Port {
+                            name: "Session",
+                            desc: PortDescriptor::ControlInput,
+                            hint: Some(HINT_INTEGER),
+                            default: Some(DefaultValue::Minimum),
+                            lower_bound: Some(-1_f32),
+                            upper_bound: Some((SESSIONS - 1) as f32),
+                        },
+                        Port {
+                            name: "Dry Delay",
+                            desc: PortDescriptor::ControlInput,
+                            hint: Some(HINT_INTEGER),
+                            default: Some(DefaultValue::Minimum),
+                            lower_bound: Some(0_f32),
+                            upper_bound: Some(MAX_DELAY as f32),
+                        },
+                        Port {
+                            name: "Delay Dry By Latency",
+                            desc: PortDescriptor::ControlInput,
+                            hint: Some(HINT_TOGGLED),
+                            default: Some(DefaultValue::Value0),
+                            lower_bound: None,
+                            upper_bound: None,
+                        },
+                        // the dry outputs are late by however long they're delayed
+                        Port {
+                            name: "latency",
+                            desc: PortDescriptor::ControlOutput,
+                            hint: Some(HINT_INTEGER),
+                            default: None,
+                            lower_bound: Some(0_f32),
+                            upper_bound: None,
+                        }],
+            new: Transmitter::new,
+        }
+    }
+}
+
+impl Plugin for Transmitter {
+    fn run<'a>(&mut self, sample_count: usize, ports: &[&'a PortConnection<'a>]) {
+        let inputl = ports[0].unwrap_audio();
+        let inputr = ports[1].unwrap_audio();
+        let mut outputl = ports[2].unwrap_audio_mut();
+        let mut outputr = ports[3].unwrap_audio_mut();
+
+        let channel = *ports[4].unwrap_control() as u16;
+        let dry = ports[5].unwrap_control();
+        let wet = ports[6].unwrap_control();
+        let codec = *ports[7].unwrap_control() > 0.5;
+        let bitrate = *ports[8].unwrap_control() as u32;
+        let mut codec_delay = ports[9].unwrap_control_mut();
+        let threshold = *ports[10].unwrap_control();
+        let block_size = *ports[11].unwrap_control() as usize;
+        let session = session(*ports[12].unwrap_control(), self.default_session);
+        let dry_delay = *ports[13].unwrap_control() as usize;
+        let by_latency = *ports[14].unwrap_control() > 0.5;
+        let mut latency = ports[15].unwrap_control_mut();
+
+        self.sender.set_channel(channel, session);
+        self.sender.set_codec(codec, bitrate);
+        self.sender.set_gain(*wet);
+        self.sender.set_silence_threshold(threshold);
+        self.sender.set_block_size(block_size);
+        **codec_delay = self.sender.codec_delay() as f32;
+
+        if self.measured.is_none() && self.sender.receiver_latency() > 0 {
+            self.measured = Some(self.sender.receiver_latency());
+        }
+        let measured = if by_latency { self.measured.unwrap_or(0) } else { 0 };
+        self.dry.set_delay(dry_delay + measured);
+        **latency = self.dry.delay() as f32;
+
+        self.dry.process(&inputl[..sample_count],
+                         &inputr[..sample_count],
+                         &mut outputl[..sample_count],
+                         &mut outputr[..sample_count],
+                         *dry);
+        self.sender.push(&inputl[..sample_count], &inputr[..sample_count]);
+    }
+
+    fn activate(&mut self) {
+        self.dry.clear();
+        self.measured = None;
+        self.sender.start();
+    }
+
+    fn deactivate(&mut self) {
+        self.sender.stop();
+    }
+}
+
+pub struct Receiver {
+    receiver: FeedbackReceiver,
+    dry: DelayLine,
+    default_session: u16,
+}
+
+impl Receiver {
+    pub fn new(_: &PluginDescriptor, sample_rate: u64) -> Box<Plugin + Send> {
+        log!(Debug, "receiver::new");
+        let config = Config::load();
+        Box::new(Receiver {
+            default_session: config.session,
+            receiver: FeedbackReceiver::new(config, sample_rate),
+            dry: DelayLine::new(),
+        })
+    }
+
+    pub fn get_descriptor() -> PluginDescriptor {
+        PluginDescriptor {
+            unique_id: 5878,
+            label: "feedback_rx",
+            properties: PROP_NONE,
+            name: "Feedback Receiver",
+            maker: "Noah Weninger",
+            copyright: "None",
+            ports: vec![Port {
+                            name: "Left Audio In",
+                            desc: PortDescriptor::AudioInput,
+                            ..Default::default()
+                        },
+                        Port {
+                            name: "Right Audio In",
+                            desc: PortDescriptor::AudioInput,
+                            ..Default::default()
+                        },
+                        Port {
+                            name: "Left Audio Out",
+                            desc: PortDescriptor::AudioOutput,
+                            ..Default::default()
+                        },
+                        Port {
+                            name: "Right Audio Out",
+                            desc: PortDescriptor::AudioOutput,
+                            ..Default::default()
+                        },
+                        Port {
+                            name: "Channel",
+                            desc: PortDescriptor::ControlInput,
+                            hint: Some(HINT_INTEGER),
+                            default: Some(DefaultValue::Value0),
+                            lower_bound: Some(0_f32),
+                            upper_bound: Some(255_f32),
+                        },
+                        Port {
+                            name: "Dry",
+                            desc: PortDescriptor::ControlInput,
+                            hint: None,
+                            default: Some(DefaultValue::Value1),
+                            lower_bound: Some(0_f32),
+                            upper_bound: Some(1_f32),
+                        },
+                        Port {
+                            name: "Recv",
+                            desc: PortDescriptor::ControlInput,
+                            hint: None,
+                            default: Some(DefaultValue::Value1),
+                            lower_bound: Some(0_f32),
+                            upper_bound: Some(1_f32),
+                        },
+                        Port {
+                            name: "Rejected Frames",
+                            desc: PortDescriptor::ControlOutput,
+                            hint: Some(HINT_INTEGER),
+                            default: None,
+                            lower_bound: Some(0_f32),
+                            upper_bound: None,
+                        },
+                        Port {
+                            name: "Session",
+                            desc: PortDescriptor::ControlInput,
+                            hint: Some(HINT_INTEGER),
+                            default: Some(DefaultValue::Minimum),
+                            lower_bound: Some(-1_f32),
+                            upper_bound: Some((SESSIONS - 1) as f32),
+                        },
+                        Port {
+                            name: "Underruns",
+                            desc: PortDescriptor::ControlOutput,
+                            hint: Some(HINT_INTEGER),
+                            default: None,
+                            lower_bound: Some(0_f32),
+                            upper_bound: None,
+                        },
+                        // named this way so hosts find it. Like the transmitter's, it's how long
+                        // the dry outputs are delayed.
+                        Port {
+                            name: "latency",
+                            desc: PortDescriptor::ControlOutput,
+                            hint: Some(HINT_INTEGER),
+                            default: None,
+                            lower_bound: Some(0_f32),
+                            upper_bound: None,
+                        },
+                        Port {
+                            name: "Dry Delay",
+                            desc: PortDescriptor::ControlInput,
+                            hint: Some(HINT_INTEGER),
+                            default: Some(DefaultValue::Minimum),
+                            lower_bound: Some(0_f32),
+                            upper_bound: Some(MAX_DELAY as f32),
+                        },
+                        Port {
+                            name: "Delay Dry By Latency",
+                            desc: PortDescriptor::ControlInput,
+                            hint: Some(HINT_TOGGLED),
+                            default: Some(DefaultValue::Value0),
+                            lower_bound: None,
+                            upper_bound: None,
+                        }],
+            new: Receiver::new,
+        }
+    }
+}
+
+impl Plugin for Receiver {
+    fn run<'a>(&mut self, sample_count: usize, ports: &[&'a PortConnection<'a>]) {
+        let inputl = ports[0].unwrap_audio();
+        let inputr = ports[1].unwrap_audio();
+        let mut outputl = ports[2].unwrap_audio_mut();
+        let mut outputr = ports[3].unwrap_audio_mut();
+
+        let channel = *ports[4].unwrap_control() as u16;
+        let dry = ports[5].unwrap_control();
+        let wet = ports[6].unwrap_control();
+        let mut rejected = ports[7].unwrap_control_mut();
+        let session = session(*ports[8].unwrap_control(), self.default_session);
+        let mut underruns = ports[9].unwrap_control_mut();
+        let mut latency = ports[10].unwrap_control_mut();
+        let dry_delay = *ports[11].unwrap_control() as usize;
+        let by_latency = *ports[12].unwrap_control() > 0.5;
+
+        self.receiver.set_channel(channel, session);
+        self.receiver.set_gain(*wet);
+        **rejected = self.receiver.rejected() as f32;
+
+        let settled = self.receiver.settled_latency().unwrap_or(0);
+        let measured = if by_latency { settled } else { 0 };
+        self.dry.set_delay(dry_delay + measured);
+        self.dry.process(&inputl[..sample_count],
+                         &inputr[..sample_count],
+                         &mut outputl[..sample_count],
+                         &mut outputr[..sample_count],
+                         *dry);
+        self.receiver.mix(&mut outputl[..sample_count], &mut outputr[..sample_count]);
+        **underruns = self.receiver.underruns() as f32;
+        // the wet signal is as late as the network makes it whatever the host does, so only the
+        // dry delay is for the host to make up for
+        **latency = self.dry.delay() as f32;
+    }
+
+    fn activate(&mut self) {
+        self.dry.clear();
+        self.receiver.start();
+    }
+
+    fn deactivate(&mut self) {
+        self.receiver.stop();
+    }
+}
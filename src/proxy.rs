@@ -0,0 +1,293 @@
+// A TCP proxy that makes a good connection bad on purpose, for seeing how transmitters and
+// receivers cope without needing root or `tc netem`. It sits between the ports of two channels,
+// so a transmitter on one reaches a receiver on the other through it, and injects faults into
+// what the transmitter sends: delay, a bandwidth cap, stalls, truncation, corrupted bytes and
+// resets. Replies from the receiver, like handshakes, are passed back untouched.
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+/// Something to do to the connections through a proxy.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Fault {
+    /// Holds every byte back this long, from now on.
+    Delay(Duration),
+    /// Forwards at most this many bytes a second, from now on. Zero lifts the cap.
+    Bandwidth(u64),
+    /// Forwards nothing for a while.
+    Stall(Duration),
+    /// Forwards this many more bytes, then hangs up.
+    Truncate(usize),
+    /// Flips every bit of the next this many bytes.
+    Corrupt(usize),
+    /// Cuts both ends off with a reset, as if the line went dead.
+    Reset,
+}
+
+impl Fault {
+    /// Reads a fault written like `delay=20`, `bandwidth=64000`, `stall=500`, `truncate=10`,
+    /// `corrupt=1` or `reset`. Times are in milliseconds.
+    pub fn parse(s: &str) -> Result<Fault, String> {
+        let mut parts = s.splitn(2, '=');
+        let name = parts.next().unwrap();
+        let value = parts.next();
+        let number = || -> Result<u64, String> {
+            value.and_then(|v| v.parse().ok()).ok_or(format!("{} needs a number, like {}=10",
+                                                             name,
+                                                             name))
+        };
+        match name {
+            "delay" => Ok(Fault::Delay(Duration::from_millis(try!(number())))),
+            "bandwidth" => Ok(Fault::Bandwidth(try!(number()))),
+            "stall" => Ok(Fault::Stall(Duration::from_millis(try!(number())))),
+            "truncate" => Ok(Fault::Truncate(try!(number()) as usize)),
+            "corrupt" => Ok(Fault::Corrupt(try!(number()) as usize)),
+            "reset" if value.is_none() => Ok(Fault::Reset),
+            _ => Err(format!("unknown fault {}", s)),
+        }
+    }
+}
+
+// how one connection is being treated right now
+#[derive(Default)]
+struct Line {
+    stall_until: Option<Instant>,
+    truncate: Option<usize>,
+    corrupt: usize,
+    reset: bool,
+}
+
+struct Shared {
+    delay: Duration,
+    bandwidth: u64,
+    lines: Vec<Weak<Mutex<Line>>>,
+    connections: usize,
+    forwarded: u64,
+}
+
+/// A running proxy. It keeps going until the process ends.
+#[derive(Clone)]
+pub struct Proxy {
+    addr: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Proxy {
+    /// Listens on `listen`, and connects everyone who arrives through to `target`.
+    pub fn start(listen: &SocketAddr, target: &SocketAddr) -> io::Result<Proxy> {
+        let listener = try!(TcpListener::bind(listen));
+        let proxy = Proxy {
+            addr: try!(listener.local_addr()),
+            shared: Arc::new(Mutex::new(Shared {
+                delay: Duration::from_millis(0),
+                bandwidth: 0,
+                lines: Vec::new(),
+                connections: 0,
+                forwarded: 0,
+            })),
+        };
+        let target = *target;
+        let accepting = proxy.clone();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                match client.and_then(|client| accepting.open(client, &target)) {
+                    Ok(()) => {}
+                    Err(e) => log!(Warn, "proxy can't connect a client: {}", e),
+                }
+            }
+        });
+        Ok(proxy)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// How many connections have come through, including ones that are gone.
+    pub fn connections(&self) -> usize {
+        self.shared.lock().unwrap().connections
+    }
+
+    /// How many bytes have been passed on to the target.
+    pub fn forwarded(&self) -> u64 {
+        self.shared.lock().unwrap().forwarded
+    }
+
+    /// Injects a fault now. Faults that happen once, like stalls and resets, hit the connections
+    /// that are open, and take effect on the next bytes sent.
+    pub fn inject(&self, fault: &Fault) {
+        let mut shared = self.shared.lock().unwrap();
+        match *fault {
+            Fault::Delay(delay) => shared.delay = delay,
+            Fault::Bandwidth(bandwidth) => shared.bandwidth = bandwidth,
+            _ => {
+                let now = Instant::now();
+                shared.lines.retain(|line| line.upgrade().is_some());
+                for line in shared.lines.iter().filter_map(|line| line.upgrade()) {
+                    let mut line = line.lock().unwrap();
+                    match *fault {
+                        Fault::Stall(duration) => line.stall_until = Some(now + duration),
+                        Fault::Truncate(bytes) => line.truncate = Some(bytes),
+                        Fault::Corrupt(bytes) => line.corrupt += bytes,
+                        _ => line.reset = true,
+                    }
+                }
+            }
+        }
+        log!(Info, "proxy injects {:?}", fault);
+    }
+
+    /// Injects each fault once its time has passed, counting from now.
+    pub fn schedule(&self, mut faults: Vec<(Duration, Fault)>) {
+        faults.sort_by(|a, b| a.0.cmp(&b.0));
+        let proxy = self.clone();
+        let start = Instant::now();
+        thread::spawn(move || {
+            for (at, fault) in faults {
+                let elapsed = start.elapsed();
+                if elapsed < at {
+                    thread::sleep(at - elapsed);
+                }
+                proxy.inject(&fault);
+            }
+        });
+    }
+
+    fn open(&self, client: TcpStream, target: &SocketAddr) -> io::Result<()> {
+        let server = try!(TcpStream::connect(target));
+        try!(client.set_nodelay(true));
+        try!(server.set_nodelay(true));
+        let line = Arc::new(Mutex::new(Line::default()));
+        {
+            let mut shared = self.shared.lock().unwrap();
+            shared.connections += 1;
+            shared.lines.push(Arc::downgrade(&line));
+        }
+        log!(Info, "proxy connects {}", try!(client.peer_addr()));
+
+        // replies go straight back
+        let (mut from, mut to) = (try!(server.try_clone()), try!(client.try_clone()));
+        thread::spawn(move || {
+            let _ = io::copy(&mut from, &mut to);
+            let _ = to.shutdown(Shutdown::Both);
+        });
+
+        // the reader notes when bytes came in, so they can be held back from then
+        let (chunk_tx, chunk_rx) = channel();
+        let mut from = try!(client.try_clone());
+        thread::spawn(move || {
+            let mut buf = [0; 4096];
+            loop {
+                match from.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => {
+                        if chunk_tx.send((Instant::now(), buf[..n].to_vec())).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        let shared = self.shared.clone();
+        thread::spawn(move || forward(chunk_rx, client, server, line, shared));
+        Ok(())
+    }
+}
+
+// Passes what the client sends on to the server, doing whatever damage the line calls for.
+fn forward(chunks: Receiver<(Instant, Vec<u8>)>,
+           client: TcpStream,
+           mut server: TcpStream,
+           line: Arc<Mutex<Line>>,
+           shared: Arc<Mutex<Shared>>) {
+    for (arrival, mut bytes) in chunks {
+        let (delay, bandwidth) = {
+            let shared = shared.lock().unwrap();
+            (shared.delay, shared.bandwidth)
+        };
+        let due = arrival + delay;
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+        loop {
+            let stall_until = line.lock().unwrap().stall_until;
+            let now = Instant::now();
+            match stall_until {
+                Some(until) if until > now => thread::sleep(until - now),
+                _ => break,
+            }
+        }
+
+        let mut hang_up = false;
+        {
+            let mut line = line.lock().unwrap();
+            if line.reset {
+                log!(Info, "proxy resets a connection");
+                reset(&client, &server);
+                return;
+            }
+            for byte in bytes.iter_mut().take(line.corrupt) {
+                *byte = !*byte;
+            }
+            line.corrupt -= line.corrupt.min(bytes.len());
+            if let Some(left) = line.truncate {
+                hang_up = left <= bytes.len();
+                bytes.truncate(left);
+                line.truncate = Some(left - bytes.len());
+            }
+        }
+
+        if server.write_all(&bytes).is_err() {
+            break;
+        }
+        shared.lock().unwrap().forwarded += bytes.len() as u64;
+        if hang_up {
+            log!(Info, "proxy truncates a connection");
+            break;
+        }
+        if bandwidth > 0 {
+            thread::sleep(Duration::from_millis(bytes.len() as u64 * 1000 / bandwidth));
+        }
+    }
+    let _ = client.shutdown(Shutdown::Both);
+    let _ = server.shutdown(Shutdown::Both);
+}
+
+// Makes both sockets send a reset when they close, then stops the other threads reading them so
+// they close for good.
+fn reset(client: &TcpStream, server: &TcpStream) {
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    for socket in &[client, server] {
+        unsafe {
+            libc::setsockopt(socket.as_raw_fd(),
+                             libc::SOL_SOCKET,
+                             libc::SO_LINGER,
+                             &linger as *const libc::linger as *const libc::c_void,
+                             mem::size_of::<libc::linger>() as libc::socklen_t);
+        }
+        let _ = socket.shutdown(Shutdown::Read);
+    }
+}
+
+#[test]
+fn test_proxy_fault_parse() {
+    assert_eq!(Fault::parse("delay=20"), Ok(Fault::Delay(Duration::from_millis(20))));
+    assert_eq!(Fault::parse("bandwidth=64000"), Ok(Fault::Bandwidth(64000)));
+    assert_eq!(Fault::parse("reset"), Ok(Fault::Reset));
+    assert!(Fault::parse("stall").is_err());
+    assert!(Fault::parse("corrupt=x").is_err());
+    assert!(Fault::parse("reset=1").is_err());
+    assert!(Fault::parse("drop=1").is_err());
+}
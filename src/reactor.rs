@@ -0,0 +1,395 @@
+// One event loop for every transmitter, receiver and tap in the process. A session can put dozens
+// of plugins in one host, so rather than each running threads of its own, their sockets are all
+// watched from a single thread. It's started along with the first plugin, and stops when the last
+// one is dropped.
+//
+// Everything the reactor looks after is a `Source`. Sources run on the reactor's thread, so they
+// must never block: they read and write until their socket would block, and wait to be called
+// again. Anything a source needs to hear from another thread comes with a `Waker`.
+//
+// Plugins add and remove sources from the audio thread, so neither ever waits on the reactor. Only
+// creating and dropping plugins does, which hosts never do from the audio thread.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle, ThreadId};
+
+use mio::{Evented, EventLoop, EventSet, Handler, PollOpt, Sender, Token};
+
+lazy_static! {
+    static ref SHARED: Mutex<Shared> = Mutex::new(Shared {
+        reactor: None,
+        holds: 0,
+    });
+}
+
+// The running reactor, and how many are holding on to it.
+struct Shared {
+    reactor: Option<Arc<Reactor>>,
+    holds: usize,
+}
+
+/// Something run by the reactor, usually a socket.
+pub trait Source: Send {
+    /// Called once the source has been added, to register its socket. Returns false if it's
+    /// already finished.
+    fn start(&mut self, ctx: &mut Context) -> bool;
+
+    /// Called when the source's socket is ready, or with no events when it has been woken.
+    /// Sockets are watched edge-triggered, so this must read or write until they would block.
+    /// Returns false once the source is finished, which drops it.
+    fn ready(&mut self, ctx: &mut Context, events: EventSet) -> bool;
+
+    /// Called when a timeout set with `Context::timeout_ms` goes off.
+    fn timeout(&mut self, _ctx: &mut Context) -> bool {
+        true
+    }
+}
+
+enum Command {
+    Add(Token, Box<Source>),
+    Remove(Token),
+}
+
+// What wakes the event loop. Its queue is bounded, so everything sent on it may be dropped.
+enum Message {
+    // there are commands waiting
+    Commands,
+    Wake(Token, Arc<AtomicBool>),
+}
+
+/// The process's reactor. It runs for as long as anyone holds on to it with a `Hold`.
+pub struct Reactor {
+    commands: Mutex<mpsc::Sender<Command>>,
+    tx: Mutex<Sender<Message>>,
+    tokens: Arc<AtomicUsize>,
+    stopping: Arc<AtomicBool>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    thread_id: ThreadId,
+}
+
+/// Keeps the reactor running. Every plugin has one from when it's created until it's dropped, so
+/// restarting a plugin from the audio thread never stops the reactor.
+pub struct Hold {
+    reactor: Arc<Reactor>,
+}
+
+/// Holds on to the reactor, starting it if it isn't already running.
+pub fn hold() -> Hold {
+    let mut shared = SHARED.lock().unwrap();
+    if shared.reactor.is_none() {
+        shared.reactor = Some(Arc::new(Reactor::start()));
+    }
+    shared.holds += 1;
+    Hold { reactor: shared.reactor.clone().unwrap() }
+}
+
+impl Hold {
+    pub fn reactor(&self) -> &Arc<Reactor> {
+        &self.reactor
+    }
+}
+
+impl Drop for Hold {
+    // The lock is kept until the reactor has stopped and closed its sockets, so one started
+    // after it can have the same ports.
+    fn drop(&mut self) {
+        let mut shared = SHARED.lock().unwrap();
+        shared.holds -= 1;
+        if shared.holds == 0 {
+            if let Some(reactor) = shared.reactor.take() {
+                reactor.stop();
+            }
+        }
+    }
+}
+
+impl Reactor {
+    fn start() -> Reactor {
+        let mut event_loop = EventLoop::new().unwrap();
+        let tx = event_loop.channel();
+        let (commands, rx) = mpsc::channel();
+        let tokens = Arc::new(AtomicUsize::new(0));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let mut sources = Sources {
+            sources: HashMap::new(),
+            commands: rx,
+            tokens: tokens.clone(),
+            stopping: stopping.clone(),
+        };
+        let thread = thread::spawn(move || {
+            log!(Debug, "reactor started");
+            if let Err(e) = event_loop.run(&mut sources) {
+                log!(Error, "reactor failed: {}", e);
+            }
+            log!(Debug, "reactor stopped");
+        });
+        Reactor {
+            commands: Mutex::new(commands),
+            tx: Mutex::new(tx),
+            tokens: tokens,
+            stopping: stopping,
+            thread_id: thread.thread().id(),
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    // Tells the thread to stop, and waits for it unless this is the thread.
+    fn stop(&self) {
+        self.stopping.store(true, Ordering::Release);
+        self.poke();
+        let thread = self.thread.lock().unwrap().take();
+        if let Some(thread) = thread {
+            if self.thread_id != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    /// Hands `source` over to be started on the reactor's thread. Returns the token it's known
+    /// by from now on.
+    pub fn add(&self, source: Box<Source>) -> Token {
+        let token = Token(self.tokens.fetch_add(1, Ordering::Relaxed));
+        self.command(Command::Add(token, source));
+        token
+    }
+
+    /// Drops a source, along with every source it added.
+    pub fn remove(&self, token: Token) {
+        self.command(Command::Remove(token));
+    }
+
+    pub fn waker(&self, token: Token) -> Waker {
+        Waker::new(self.tx.lock().unwrap().clone(), token)
+    }
+
+    /// How many sources have ever been added, counting ones sources added themselves.
+    pub fn added(&self) -> usize {
+        self.tokens.load(Ordering::Relaxed)
+    }
+
+    /// The thread every source is run on.
+    pub fn thread_id(&self) -> ThreadId {
+        self.thread_id
+    }
+
+    fn command(&self, command: Command) {
+        let _ = self.commands.lock().unwrap().send(command);
+        self.poke();
+    }
+
+    // A full queue means the loop is already busy with messages, and it looks for commands
+    // after every turn, so a poke that doesn't fit isn't needed.
+    fn poke(&self) {
+        let _ = self.tx.lock().unwrap().send(Message::Commands);
+    }
+}
+
+/// Wakes a source from any thread. Wakes that come before the source gets to run are merged, so
+/// waking once per block of audio is cheap.
+#[derive(Clone)]
+pub struct Waker {
+    tx: Sender<Message>,
+    token: Token,
+    pending: Arc<AtomicBool>,
+}
+
+impl Waker {
+    fn new(tx: Sender<Message>, token: Token) -> Waker {
+        Waker {
+            tx: tx,
+            token: token,
+            pending: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn wake(&self) {
+        if self.pending.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        // this may be the reactor's own thread, which mustn't wait on its queue. A wake that
+        // doesn't fit is dropped, and the next one tries again.
+        if self.tx.send(Message::Wake(self.token, self.pending.clone())).is_err() {
+            self.pending.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// What a source can do while it's being run.
+pub struct Context<'a> {
+    event_loop: &'a mut EventLoop<Sources>,
+    tokens: &'a AtomicUsize,
+    token: Token,
+    added: Vec<(Token, Box<Source>)>,
+}
+
+impl<'a> Context<'a> {
+    /// Watches `io` for the source being run.
+    pub fn register<E: Evented>(&mut self, io: &E) -> io::Result<()> {
+        self.event_loop.register_opt(io, self.token, EventSet::all(), PollOpt::edge())
+    }
+
+    /// Calls the source's `timeout` after `delay` milliseconds.
+    pub fn timeout_ms(&mut self, delay: u64) {
+        if let Err(e) = self.event_loop.timeout_ms(self.token, delay) {
+            log!(Warn, "can't set timeout: {:?}", e);
+        }
+    }
+
+    /// Adds a source that belongs to the one being run, and is dropped along with it. It's
+    /// started once the current one returns.
+    pub fn add(&mut self, source: Box<Source>) -> Token {
+        let token = Token(self.tokens.fetch_add(1, Ordering::Relaxed));
+        self.added.push((token, source));
+        token
+    }
+
+    pub fn waker(&self, token: Token) -> Waker {
+        Waker::new(self.event_loop.channel(), token)
+    }
+}
+
+struct Slot {
+    source: Box<Source>,
+    parent: Option<Token>,
+}
+
+struct Sources {
+    sources: HashMap<Token, Slot>,
+    commands: mpsc::Receiver<Command>,
+    tokens: Arc<AtomicUsize>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl Sources {
+    fn add(&mut self,
+           event_loop: &mut EventLoop<Sources>,
+           token: Token,
+           parent: Option<Token>,
+           source: Box<Source>) {
+        self.sources.insert(token,
+                            Slot {
+                                source: source,
+                                parent: parent,
+                            });
+        self.run(event_loop, token, |source, ctx| source.start(ctx));
+    }
+
+    fn remove(&mut self, token: Token) {
+        if self.sources.remove(&token).is_none() {
+            return;
+        }
+        let children: Vec<Token> = self.sources
+                                       .iter()
+                                       .filter(|&(_, slot)| slot.parent == Some(token))
+                                       .map(|(&child, _)| child)
+                                       .collect();
+        for child in children {
+            self.remove(child);
+        }
+    }
+
+    fn commands(&mut self, event_loop: &mut EventLoop<Sources>) {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                Command::Add(token, source) => self.add(event_loop, token, None, source),
+                Command::Remove(token) => self.remove(token),
+            }
+        }
+    }
+
+    fn run<F>(&mut self, event_loop: &mut EventLoop<Sources>, token: Token, f: F)
+        where F: FnOnce(&mut Source, &mut Context) -> bool
+    {
+        let (keep, added) = {
+            // events can still turn up for sources that have just been dropped
+            let slot = match self.sources.get_mut(&token) {
+                Some(slot) => slot,
+                None => return,
+            };
+            let mut ctx = Context {
+                event_loop: event_loop,
+                tokens: &self.tokens,
+                token: token,
+                added: Vec::new(),
+            };
+            let keep = f(&mut *slot.source, &mut ctx);
+            (keep, ctx.added)
+        };
+        for (child, source) in added {
+            self.add(event_loop, child, Some(token), source);
+        }
+        if !keep {
+            self.remove(token);
+        }
+    }
+}
+
+impl Handler for Sources {
+    type Timeout = Token;
+    type Message = Message;
+
+    fn ready(&mut self, event_loop: &mut EventLoop<Sources>, token: Token, events: EventSet) {
+        self.run(event_loop, token, |source, ctx| source.ready(ctx, events));
+    }
+
+    fn notify(&mut self, event_loop: &mut EventLoop<Sources>, message: Message) {
+        // a source may be woken as soon as it's added, so the add comes first
+        self.commands(event_loop);
+        if let Message::Wake(token, pending) = message {
+            pending.store(false, Ordering::Release);
+            self.run(event_loop, token, |source, ctx| source.ready(ctx, EventSet::none()));
+        }
+    }
+
+    // Also catches commands whose message didn't fit in the queue.
+    fn tick(&mut self, event_loop: &mut EventLoop<Sources>) {
+        self.commands(event_loop);
+        if self.stopping.load(Ordering::Acquire) {
+            event_loop.shutdown();
+        }
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<Sources>, token: Token) {
+        self.run(event_loop, token, |source, ctx| source.timeout(ctx));
+    }
+}
+
+#[cfg(test)]
+struct Counter {
+    woken: Arc<AtomicUsize>,
+}
+
+#[cfg(test)]
+impl Source for Counter {
+    fn start(&mut self, _: &mut Context) -> bool {
+        true
+    }
+
+    fn ready(&mut self, _: &mut Context, _: EventSet) -> bool {
+        self.woken.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+}
+
+#[test]
+fn test_reactor_shared() {
+    use std::time::Duration;
+
+    let woken = Arc::new(AtomicUsize::new(0));
+    let held = hold();
+    let reactor = held.reactor();
+    assert!(Arc::ptr_eq(reactor, hold().reactor()));
+    let token = reactor.add(Box::new(Counter { woken: woken.clone() }));
+    reactor.waker(token).wake();
+    for _ in 0..1000 {
+        if woken.load(Ordering::SeqCst) > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(woken.load(Ordering::SeqCst) > 0);
+    reactor.remove(token);
+}
@@ -1,131 +1,189 @@
-use std::thread;
-use std::sync::mpsc::{self, channel, TryRecvError};
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, sync_channel, TryRecvError, TrySendError};
 use std::io::{Read, ErrorKind};
-use std::collections::HashMap;
-use std::time::Duration;
-use std::usize;
-
-use mio::*;
-use mio::tcp::TcpListener;
-
-use ladspa::{PluginDescriptor, Plugin, PortConnection};
-use ladspa::{Port, PortDescriptor};
-use ladspa::{PROP_NONE, HINT_INTEGER, DefaultValue};
-
-use super::BASE_PORT;
-use super::packet::{BUFFER_SIZE, BYTE_BUFFER_SIZE, Packet};
-
-type ClientPacket = (u64, Packet);
-
-const SERVER: Token = Token(0);
-
-pub struct Receiver {
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use mio::EventSet;
+use mio::tcp::{TcpListener, TcpStream};
+
+use super::packet::{Frame, FrameReader, Negotiation, Packet};
+use super::mixer::{Mixer, Tracks};
+use super::codec::Decoder;
+use super::config::Config;
+use super::access::{Gate, Pass};
+use super::reactor::{Context, Reactor, Source};
+use super::registry::{self, Entry, Registration, Role};
+use super::secure::HANDSHAKE_TIMEOUT_MS;
+use super::transport::{Inbox, Link, Tcp, Transport};
+use super::tap::{self, ClientTap, TapListener, Taps};
+use super::capture::CaptureWriter;
+use super::net;
+
+pub type ClientPacket = (u64, Packet);
+
+// how often the client count in the registry is brought up to date
+const REFRESH_MS: u64 = 1000;
+// how long to wait before trying again to listen on a port that's taken
+const RETRY_MS: u64 = 100;
+// how long the latency is measured over before it's allowed to fall
+const LATENCY_WINDOW_MS: u64 = 1000;
+
+/// Receives audio from the transmitters on a channel, and mixes it together. This is everything
+/// the receiver plugin does apart from talking to the host, for programs that want to play audio
+/// themselves.
+///
+/// Nothing is listened for until `start` is called. After that, each pull gives the next block
+/// from every transmitter connected, lined up by their timestamps.
+pub struct FeedbackReceiver {
+    config: Config,
+    rejected: Arc<AtomicUsize>,
+    gate: Gate,
+    underruns: u64,
+    // the deepest the buffers have been this window, and in the last one
+    peak_depth: usize,
+    latency: usize,
+    // how far into the window the mix is, and how long a window is in samples
+    window: usize,
+    window_len: usize,
+    // the latency over the first whole window, held for the host. It's shared with the
+    // transport, which tells transmitters about it, as zero until there is one.
+    settled: Option<usize>,
+    shared_latency: Arc<AtomicUsize>,
     channel: u16,
+    session: u16,
+    sample_rate: u64,
+    gain: f32,
     packet_rx: Option<mpsc::Receiver<ClientPacket>>,
-    active_packets: Vec<ClientPacket>,
-    notify_tx: Option<Sender<<PacketReceiver as Handler>::Message>>,
-    client_time_map: HashMap<u64, u64>,
+    mixer: Mixer,
+    // makes the mixer's tracks on the network thread as clients arrive
+    tracks: Tracks,
+    // packets the mixer had no room for, shared with the transport to report
+    dropped: Arc<AtomicUsize>,
+    // the gate's word on who has left, held until their last packets are in the mixer
+    departures: Option<mpsc::Receiver<u64>>,
+    departed: Vec<u64>,
+    // None when fed directly rather than from the network, for replaying captures
+    transport: Option<Arc<Transport>>,
+    link: Option<Link>,
+    // interleaved audio is mixed in these, then woven together
+    lscratch: Vec<f32>,
+    rscratch: Vec<f32>,
 }
 
-impl Receiver {
-    pub fn new(_: &PluginDescriptor, _: u64) -> Box<Plugin + Send> {
-        println!("receiver::new");
-        Box::new(Receiver {
+impl FeedbackReceiver {
+    /// A receiver for audio at `sample_rate`, on channel 0 of the configured session to begin with.
+    pub fn new(config: Config, sample_rate: u64) -> FeedbackReceiver {
+        FeedbackReceiver::with_transport(config, sample_rate, Arc::new(Tcp::new()))
+    }
+
+    /// A receiver that listens on something other than TCP.
+    pub fn with_transport(config: Config,
+                          sample_rate: u64,
+                          transport: Arc<Transport>)
+                          -> FeedbackReceiver {
+        FeedbackReceiver::build(config, sample_rate, Some(transport))
+    }
+
+    fn build(config: Config,
+             sample_rate: u64,
+             transport: Option<Arc<Transport>>)
+             -> FeedbackReceiver {
+        let departed = Vec::with_capacity(config.max_clients);
+        // one more than the limit, for a client replaced by another while it's still being played
+        let (mixer, tracks) = Mixer::new(config.max_clients + 1);
+        FeedbackReceiver {
+            gate: Gate::new(config.allow.clone(), config.max_clients, config.limit_policy),
+            session: config.session,
+            config: config,
+            rejected: Arc::new(AtomicUsize::new(0)),
+            underruns: 0,
+            peak_depth: 0,
+            latency: 0,
+            window: 0,
+            window_len: latency_window(sample_rate),
+            settled: None,
+            shared_latency: Arc::new(AtomicUsize::new(0)),
             channel: 0,
+            sample_rate: sample_rate,
+            gain: 1.0,
             packet_rx: None,
-            active_packets: Vec::new(),
-            notify_tx: None,
-            client_time_map: HashMap::new(),
-        })
-    }
-
-    pub fn get_descriptor() -> PluginDescriptor {
-        PluginDescriptor {
-            unique_id: 5878,
-            label: "feedback_rx",
-            properties: PROP_NONE,
-            name: "Feedback Receiver",
-            maker: "Noah Weninger",
-            copyright: "None",
-            ports: vec![Port {
-                            name: "Left Audio In",
-                            desc: PortDescriptor::AudioInput,
-                            ..Default::default()
-                        },
-                        Port {
-                            name: "Right Audio In",
-                            desc: PortDescriptor::AudioInput,
-                            ..Default::default()
-                        },
-                        Port {
-                            name: "Left Audio Out",
-                            desc: PortDescriptor::AudioOutput,
-                            ..Default::default()
-                        },
-                        Port {
-                            name: "Right Audio Out",
-                            desc: PortDescriptor::AudioOutput,
-                            ..Default::default()
-                        },
-                        Port {
-                            name: "Channel",
-                            desc: PortDescriptor::ControlInput,
-                            hint: Some(HINT_INTEGER),
-                            default: Some(DefaultValue::Value0),
-                            lower_bound: Some(0_f32),
-                            upper_bound: Some(255_f32),
-                        },
-                        Port {
-                            name: "Dry",
-                            desc: PortDescriptor::ControlInput,
-                            hint: None,
-                            default: Some(DefaultValue::Value1),
-                            lower_bound: Some(0_f32),
-                            upper_bound: Some(1_f32),
-                        },
-                        Port {
-                            name: "Recv",
-                            desc: PortDescriptor::ControlInput,
-                            hint: None,
-                            default: Some(DefaultValue::Value1),
-                            lower_bound: Some(0_f32),
-                            upper_bound: Some(1_f32),
-                        }],
-            new: Receiver::new,
+            mixer: mixer,
+            tracks: tracks,
+            dropped: Arc::new(AtomicUsize::new(0)),
+            departures: None,
+            departed: departed,
+            transport: transport,
+            link: None,
+            lscratch: Vec::new(),
+            rscratch: Vec::new(),
         }
     }
 
+    /// A receiver that doesn't listen for transmitters. Packets are handed to it through the
+    /// returned sender instead, which is bounded like the network's queue.
+    pub fn offline(config: Config) -> (FeedbackReceiver, mpsc::SyncSender<ClientPacket>) {
+        let (data_tx, data_rx) = sync_channel(config.receive_queue);
+        let mut receiver = FeedbackReceiver::build(config, 0, None);
+        receiver.gate = Gate::new(None, receiver.config.max_clients, receiver.config.limit_policy);
+        // clients aren't let in through the gate, so there's a track ready for every one
+        receiver.tracks.provide(receiver.config.max_clients);
+        receiver.packet_rx = Some(data_rx);
+        (receiver, data_tx)
+    }
+
+    /// Starts listening on the channel, and starts the mix over.
+    pub fn start(&mut self) {
+        log!(Info, "activate {}", self.channel);
+        self.mixer.clear();
+        self.peak_depth = 0;
+        self.latency = 0;
+        self.window = 0;
+        self.settled = None;
+        self.shared_latency.store(0, Ordering::Relaxed);
+        self.init_server();
+    }
+
+    /// Stops listening, hanging up on every transmitter.
+    pub fn stop(&mut self) {
+        log!(Info, "deactivate {}", self.channel);
+        self.kill_server();
+    }
+
     fn init_server(&mut self) {
-        let (data_tx, data_rx) = channel();
+        let transport = match self.transport {
+            Some(ref transport) => transport.clone(),
+            None => return,
+        };
+        let (data_tx, data_rx) = sync_channel(self.config.receive_queue);
         self.packet_rx = Some(data_rx);
 
-        let channel = self.channel;
-        let mut event_loop = EventLoop::new().unwrap();
-        self.notify_tx = Some(event_loop.channel());
-        thread::spawn(move || {
-            let addr = format!("127.0.0.1:{}", BASE_PORT + channel).parse().unwrap();
-            let server;
-            loop {
-                match TcpListener::bind(&addr) {
-                    Ok(s) => {
-                        server = s;
-                        break;
-                    }
-                    Err(_) => {}
-                }
-            }
-            event_loop.register(&server, SERVER).unwrap();
-            event_loop.run(&mut PacketReceiver {
-                          server: server,
-                          data_tx: data_tx,
-                          client_id: 0,
-                      })
-                      .unwrap();
-        });
+        let entry = Entry::new(&self.config,
+                               Role::Receiver,
+                               self.channel,
+                               self.session,
+                               self.sample_rate);
+        self.gate = Gate::new(self.config.allow.clone(),
+                              self.config.max_clients,
+                              self.config.limit_policy);
+        self.departures = Some(self.gate.departures());
+        // whoever was connected before belongs to the old gate, so none of them are still here
+        self.departed.clear();
+        self.mixer.depart_all();
+        let inbox = Inbox {
+            packets: data_tx,
+            gate: self.gate.clone(),
+            rejected: self.rejected.clone(),
+            latency: self.shared_latency.clone(),
+            dropped: self.dropped.clone(),
+            tracks: self.tracks.clone(),
+        };
+        self.link = Some(transport.receive(&self.config, entry, inbox));
     }
 
     fn kill_server(&mut self) {
-        self.notify_tx.as_ref().unwrap().send(()).unwrap();
+        self.link = None;
     }
 
     fn restart_server(&mut self) {
@@ -137,8 +195,9 @@ impl Receiver {
         loop {
             let packet = match self.packet_rx.as_ref().unwrap().try_recv() {
                 Ok(packet) => packet,
+                Err(TryRecvError::Disconnected) if self.transport.is_none() => break,
                 Err(TryRecvError::Disconnected) => {
-                    println!("ladspa packet receive failed, dead channel!");
+                    log!(Warn, "ladspa packet receive failed, dead channel!");
                     self.kill_server();
                     self.init_server();
                     break;
@@ -147,192 +206,500 @@ impl Receiver {
                     break;
                 }
             };
-            self.active_packets.push(packet);
+            let (client_id, packet) = packet;
+            self.mixer.push(client_id, packet);
         }
+        self.dropped.store(self.mixer.dropped(), Ordering::Relaxed);
     }
 
-    fn set_channel(&mut self, channel: u16) {
-        if channel != self.channel {
+    // Hears who has hung up. A client leaves after everything it sent has been queued, so once
+    // the queue has been taken in after this, their last packets are in the mixer.
+    fn note_departures(&mut self) {
+        if let Some(ref departures) = self.departures {
+            while let Ok(client_id) = departures.try_recv() {
+                self.departed.push(client_id);
+            }
+        }
+    }
+
+    fn forget_departed(&mut self, sample_count: usize) {
+        for &client_id in &self.departed {
+            self.mixer.depart(client_id);
+        }
+        self.departed.clear();
+        self.mixer.forget_departed(sample_count);
+    }
+
+    /// Moves to another channel and session, listening there instead if the receiver has been
+    /// started.
+    pub fn set_channel(&mut self, channel: u16, session: u16) {
+        if channel != self.channel || session != self.session {
             self.channel = channel;
-            println!("set channel {}", self.channel);
-            self.restart_server();
+            self.session = session;
+            log!(Info, "set channel {} session {}", self.channel, self.session);
+            if self.link.is_some() {
+                self.restart_server();
+            }
             return;
         }
     }
 
-    fn get_client_time(&self, client_id: u64) -> u64 {
-        self.client_time_map.get(&client_id).map(|x| *x).unwrap_or(0)
+    /// Scales everything received by `gain`. It starts at 1.
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
     }
 
-    fn set_client_time(&mut self, client_id: u64, time: u64) {
-        self.client_time_map.insert(client_id, time);
+    /// How many transmitters are connected.
+    pub fn clients(&self) -> usize {
+        self.gate.len()
     }
 
-    fn prune_packets(&mut self) {
-        let mut i = self.active_packets.len();
-        while i > 0 {
-            i -= 1;
-            let complete = {
-                let (client_id, ref packet) = self.active_packets[i];
-                let client_time = self.get_client_time(client_id);
-                packet.complete(client_time)
-            };
-            if complete {
-                self.active_packets.remove(i);
+    /// How many transmitters have been hung up on for sending something that couldn't be read.
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// How many packets were dropped because there was no room for them in the mix.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// How many blocks were pulled while someone was connected, but their audio hadn't arrived.
+    pub fn underruns(&self) -> u64 {
+        self.underruns
+    }
+
+    /// How many samples behind the transmitters the mix is playing. This is the most audio any
+    /// of them had waiting when a block was pulled, over roughly the last second. A transmitter's
+    /// block lands all at once, so that takes in both the time spent filling blocks and whatever
+    /// the network's jitter has left queued up.
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
+    /// The latency over the first second the mix was playing, held until the receiver is started
+    /// again. Hosts expect a plugin's latency to stay put while it runs, so this is what the
+    /// receiver plugin delays its dry outputs by, and what transmitters are told. None until the
+    /// mix has played for a second.
+    pub fn settled_latency(&self) -> Option<usize> {
+        self.settled
+    }
+
+    // Rises straight away, but only falls once a whole window has gone by without it. Measured
+    // once the block has been played, so it's what's left waiting for the blocks after it.
+    fn measure_latency(&mut self, sample_count: usize) {
+        self.peak_depth = self.peak_depth.max(self.mixer.depth());
+        let mut latency = self.latency.max(self.peak_depth);
+        if self.mixer.started() {
+            self.window += sample_count;
+        }
+        if self.window >= self.window_len {
+            latency = self.peak_depth;
+            self.peak_depth = 0;
+            self.window = 0;
+            if self.settled.is_none() {
+                self.settled = Some(latency);
+                self.shared_latency.store(latency, Ordering::Relaxed);
             }
         }
+        self.latency = latency;
     }
 
-    fn have_enough_data(&self, sample_count: usize) -> bool {
-        let mut client_availibility = HashMap::new();
-        for &(client_id, _) in &self.active_packets {
-            let availibility = client_availibility.get(&client_id).map(|x| *x).unwrap_or(0);
-            client_availibility.insert(client_id, availibility + 1);
+    /// Plays the next block into a left and right channel of the same length. Returns false,
+    /// leaving silence, if not enough has arrived yet.
+    pub fn pull(&mut self, left: &mut [f32], right: &mut [f32]) -> bool {
+        for x in left.iter_mut().chain(right.iter_mut()) {
+            *x = 0.0;
         }
+        self.mix(left, right)
+    }
 
-        if client_availibility.len() > 0 {
-            let mut min_availibility = usize::max_value();
-            for (_, &availibility) in &client_availibility {
-                if availibility < min_availibility {
-                    min_availibility = availibility;
-                }
+    /// Plays the next block into interleaved stereo, left first.
+    pub fn pull_interleaved(&mut self, samples: &mut [f32]) -> bool {
+        let mut left = mem::replace(&mut self.lscratch, Vec::new());
+        let mut right = mem::replace(&mut self.rscratch, Vec::new());
+        left.resize(samples.len() / 2, 0.0);
+        right.resize(samples.len() / 2, 0.0);
+        let mixed = self.pull(&mut left, &mut right);
+        for (i, frame) in samples.chunks_mut(2).enumerate() {
+            frame[0] = left.get(i).map(|&x| x).unwrap_or(0.0);
+            if frame.len() > 1 {
+                frame[1] = right[i];
             }
-
-            return min_availibility * BUFFER_SIZE >= sample_count;
         }
+        self.lscratch = left;
+        self.rscratch = right;
+        mixed
+    }
 
-        // no packets, don't waste time
-        return false;
+    /// Takes in the packets that have arrived and adds the next block of them to the outputs.
+    /// Returns false, leaving the outputs alone, if not enough has arrived yet.
+    pub fn mix(&mut self, outputl: &mut [f32], outputr: &mut [f32]) -> bool {
+        let sample_count = outputl.len();
+        self.note_departures();
+        self.recv_packets();
+        self.forget_departed(sample_count);
+        let mixed = self.mixer.mix(outputl, outputr, self.gain);
+        // someone we've been playing is still connected, but their audio didn't arrive in time.
+        // Waiting for a client's first block isn't an underrun.
+        if !mixed && self.gate.len() > 0 && self.mixer.started() {
+            self.underruns += 1;
+        }
+        self.measure_latency(sample_count);
+        mixed
     }
 }
 
-impl Plugin for Receiver {
-    fn run<'a>(&mut self, sample_count: usize, ports: &[&'a PortConnection<'a>]) {
-        let inputl = ports[0].unwrap_audio();
-        let inputr = ports[1].unwrap_audio();
-        let mut outputl = ports[2].unwrap_audio_mut();
-        let mut outputr = ports[3].unwrap_audio_mut();
-
-        let channel = *ports[4].unwrap_control() as u16;
-        let dry = ports[5].unwrap_control();
-        let wet = ports[6].unwrap_control();
+// in samples. Offline receivers aren't told their rate, so they go by 44.1 kHz.
+fn latency_window(sample_rate: u64) -> usize {
+    let rate = if sample_rate > 0 { sample_rate } else { 44100 };
+    (rate * LATENCY_WINDOW_MS / 1000) as usize
+}
 
-        self.set_channel(channel);
-        self.recv_packets();
+/// Listens for transmitters on the receiver's TCP port, and for taps on a loopback port. The
+/// receiver is published in the registry once both are open.
+pub fn listen(reactor: &Arc<Reactor>, config: &Config, entry: Entry, inbox: Inbox) -> Link {
+    let reactor = reactor.clone();
+    let token = reactor.add(Box::new(Listener {
+        server: None,
+        data_tx: inbox.packets,
+        client_id: 0,
+        gate: inbox.gate,
+        tracks: inbox.tracks,
+        config: config.clone(),
+        rejected: inbox.rejected,
+        latency: inbox.latency,
+        reported: inbox.dropped.load(Ordering::Relaxed),
+        dropped: inbox.dropped,
+        entry: entry,
+        registration: None,
+        taps: Taps::new(),
+        capture: None,
+    }));
+    Link::new(Box::new(move || reactor.remove(token)))
+}
 
-        for i in 0..sample_count {
-            outputl[i] = inputl[i] * (*dry);
-            outputr[i] = inputr[i] * (*dry);
+/// Turns a frame from a client into audio, if it finishes any. Coded streams keep their decoder
+/// between frames. A raw frame's packet is taken, leaving it empty.
+pub fn decode_frame(frame: &mut Frame, decoder: &mut Option<Decoder>) -> Option<Packet> {
+    match *frame {
+        Frame::Raw(ref mut packet) => Some(mem::replace(packet, Packet::zeroed(0, 0))),
+        Frame::Coded(ref coded) => {
+            if decoder.is_none() {
+                *decoder = Some(Decoder::new());
+            }
+            decoder.as_mut().unwrap().decode(coded)
+        }
+        Frame::Silent { timestamp, length } => {
+            match *decoder {
+                Some(ref mut decoder) => decoder.decode_silence(timestamp, length as usize),
+                None => Some(Packet::zeroed(timestamp, length as usize)),
+            }
         }
+    }
+}
 
-        if !self.have_enough_data(sample_count) {
+/// A receiver's listening socket, run by the reactor. Its clients and taps belong to it, so they
+/// go when it does.
+struct Listener {
+    server: Option<TcpListener>,
+    data_tx: mpsc::SyncSender<ClientPacket>,
+    client_id: u64,
+    gate: Gate,
+    tracks: Tracks,
+    config: Config,
+    rejected: Arc<AtomicUsize>,
+    latency: Arc<AtomicUsize>,
+    // the plugin can't log from the audio thread, so packets it drops are reported from here
+    dropped: Arc<AtomicUsize>,
+    reported: usize,
+    entry: Entry,
+    registration: Option<Registration>,
+    taps: Taps,
+    // opened when the first client arrives, so idle receivers leave no empty captures behind
+    capture: Option<CaptureWriter>,
+}
+
+impl Listener {
+    fn open_capture(&mut self) {
+        if self.capture.is_some() {
             return;
         }
+        let dir = match self.config.capture_dir {
+            Some(ref dir) => dir.clone(),
+            None => return,
+        };
+        match CaptureWriter::create_in(&dir, self.config.capture_limit, &self.entry) {
+            Ok(capture) => self.capture = Some(capture),
+            Err(e) => log!(Warn, "can't capture to {}: {}", dir, e),
+        }
+    }
 
-        let mut read_clients = Vec::new();
-        for &(client_id, ref packet) in &self.active_packets {
-            let client_time = self.get_client_time(client_id);
-            for i in 0..sample_count {
-                let (l, r) = packet.read(client_time + i as u64);
-                outputl[i] += l * (*wet);
-                outputr[i] += r * (*wet);
+    fn refresh_registry(&mut self) {
+        let clients = Some(self.gate.len());
+        let latency = match self.latency.load(Ordering::Relaxed) {
+            0 => None,
+            latency => Some(latency),
+        };
+        if clients != self.entry.clients || latency != self.entry.latency {
+            self.entry.clients = clients;
+            self.entry.latency = latency;
+            if let Some(ref registration) = self.registration {
+                if let Err(e) = registration.update(&self.entry) {
+                    log!(Warn, "can't update registry: {}", e);
+                }
             }
-            read_clients.push(client_id);
         }
-        for client_id in read_clients {
-            let client_time = self.get_client_time(client_id);
-            self.set_client_time(client_id, client_time + sample_count as u64);
+    }
+
+    fn accept(&mut self, ctx: &mut Context, socket: TcpStream) {
+        let client_id = self.client_id;
+        self.client_id += 1;
+        let admitted = socket.peer_addr()
+                             .map_err(|e| e.to_string())
+                             .and_then(|peer| {
+                                 self.gate
+                                     .admit(client_id, &peer, &socket)
+                                     .map(|pass| (pass, peer))
+                                     .map_err(|e| format!("{}: {}", peer, e))
+                             });
+        let (pass, peer) = match admitted {
+            Ok(admitted) => admitted,
+            Err(e) => {
+                // dropping the socket hangs up on them
+                log!(Warn, "rejected client {}: {}", client_id, e);
+                return;
+            }
+        };
+        self.open_capture();
+        ctx.add(Box::new(Client {
+            socket: socket,
+            id: client_id,
+            peer: peer,
+            pass: pass,
+            negotiation: None,
+            reader: None,
+            frame: Frame::Silent {
+                timestamp: 0,
+                length: 0,
+            },
+            decoder: None,
+            overflowing: false,
+            tx: self.data_tx.clone(),
+            gate: self.gate.clone(),
+            tracks: self.tracks.clone(),
+            config: self.config.clone(),
+            rejected: self.rejected.clone(),
+            taps: self.taps.clone(),
+            tap: None,
+            capture: self.capture.clone(),
+        }));
+    }
+}
+
+impl Source for Listener {
+    fn start(&mut self, ctx: &mut Context) -> bool {
+        let addr = SocketAddr::new(self.config.bind, self.config.port(self.entry.session,
+                                                                      self.entry.channel));
+        let server = match net::listen(&addr) {
+            Ok(server) => server,
+            Err(e) => {
+                // most likely the last receiver on this port hasn't quite let go of it
+                log!(Debug, "can't listen on {} yet: {}", addr, e);
+                ctx.timeout_ms(RETRY_MS);
+                return true;
+            }
+        };
+        if let Err(e) = ctx.register(&server) {
+            log!(Error, "can't listen on {}: {}", addr, e);
+            return false;
+        }
+        // taps are for local tools only
+        let tap_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+        match net::listen(&tap_addr) {
+            Ok(tap) => {
+                self.entry.tap = tap.local_addr().ok().map(|addr| addr.to_string());
+                ctx.add(Box::new(TapListener::new(tap, &self.taps, &self.gate, &self.config)));
+            }
+            Err(e) => log!(Warn, "can't listen for taps: {}", e),
         }
-        self.prune_packets();
+        self.server = Some(server);
+        // only now can transmitters reach us
+        self.registration = registry::announce(&self.config, &self.entry);
+        ctx.timeout_ms(REFRESH_MS);
+        self.ready(ctx, EventSet::none())
     }
 
-    fn activate(&mut self) {
-        println!("activate {}", self.channel);
-        self.client_time_map.clear();
-        self.init_server();
+    fn ready(&mut self, ctx: &mut Context, _: EventSet) -> bool {
+        loop {
+            let res = self.server.as_ref().unwrap().accept();
+            match res {
+                Ok(Some(socket)) => self.accept(ctx, socket),
+                Ok(None) => return true,
+                Err(e) => {
+                    log!(Warn, "listener.accept() errored: {}", e);
+                    return true;
+                }
+            }
+        }
     }
 
-    fn deactivate(&mut self) {
-        println!("deactivate {}", self.channel);
-        self.kill_server();
+    fn timeout(&mut self, ctx: &mut Context) -> bool {
+        if self.server.is_none() {
+            return self.start(ctx);
+        }
+        // clients leave on their own and the latency changes as the mix plays, so both are
+        // picked up here
+        self.refresh_registry();
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped > self.reported {
+            log!(Warn, "dropped {} packets with no room in the mix", dropped - self.reported);
+            self.reported = dropped;
+        }
+        ctx.timeout_ms(REFRESH_MS);
+        true
     }
 }
 
-struct PacketReceiver {
-    server: TcpListener,
-    data_tx: mpsc::Sender<ClientPacket>,
-    client_id: u64,
+/// One transmitter connected to a receiver, run by the reactor.
+struct Client {
+    socket: TcpStream,
+    id: u64,
+    peer: SocketAddr,
+    // taken when the client is let in, and only counted once its handshake is done
+    pass: Pass,
+    negotiation: Option<Negotiation>,
+    reader: Option<FrameReader>,
+    // each frame is read into this one, so coded frames don't need buffers of their own
+    frame: Frame,
+    decoder: Option<Decoder>,
+    overflowing: bool,
+    tx: mpsc::SyncSender<ClientPacket>,
+    gate: Gate,
+    tracks: Tracks,
+    config: Config,
+    rejected: Arc<AtomicUsize>,
+    taps: Taps,
+    tap: Option<ClientTap>,
+    capture: Option<CaptureWriter>,
 }
 
-impl Handler for PacketReceiver {
-    type Timeout = ();
-        type Message = ();
-
-    fn ready(&mut self, _: &mut EventLoop<Self>, token: Token, events: EventSet) {
-        match token {
-            SERVER => {
-                // Only receive readable events
-                assert!(events.is_readable());
-
-                println!("server wait");
-                match self.server.accept() {
-                    Ok(Some(mut socket)) => {
-                        let client_id = self.client_id;
-                        self.client_id += 1;
-                        let tx = self.data_tx.clone();
-                        thread::spawn(move || {
-                            socket.set_nodelay(true).unwrap();
-                            let mut buf = [0; BYTE_BUFFER_SIZE];
-                            let mut buf_pos = 0;
-                            println!("server accept client {}", client_id);
-                            loop {
-                                let res = socket.read(&mut buf[buf_pos..]);
-                                match res {
-                                    Ok(num_read) => {
-                                        // println!("server read {}", num_read);
-                                        // if we got a length zero read, the connection is done.
-                                        if num_read == 0 {
-                                            println!("read zero bytes");
-                                            return;
-                                        }
-
-                                        // check if we've filled the buffer
-                                        buf_pos += num_read;
-                                        if buf_pos != BYTE_BUFFER_SIZE {
-                                            continue;
-                                        }
-                                        buf_pos = 0;
-                                    }
-                                    Err(e) => {
-                                        if e.kind() == ErrorKind::WouldBlock {
-                                            // TODO this is a quick hack to reduce CPU usage
-                                            thread::sleep(Duration::from_millis(10));
-                                            continue;
-                                        }
-                                        panic!(e);
-                                    }
-                                }
-                                let packet = Packet::parse(&buf[..]);
-                                if let Err(_) = tx.send((client_id, packet)) {
-                                    println!("send packet to ladspa error! channel is dead.");
-                                    return;
-                                }
-                            }
-                        });
+impl Client {
+    // counts the client as rejected, and says how many have been so far
+    fn reject(&self) -> usize {
+        self.rejected.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // returns false if the connection should be dropped
+    fn handle(&mut self) -> bool {
+        if let Some(ref capture) = self.capture {
+            if let Err(e) = capture.write(self.id, tap::now(), &self.frame) {
+                log!(Warn, "capture failed: {}", e);
+            }
+        }
+        if let Some(packet) = decode_frame(&mut self.frame, &mut self.decoder) {
+            if let Some(ref tap) = self.tap {
+                tap.packet(&packet);
+            }
+            match self.tx.try_send((self.id, packet)) {
+                Ok(()) => self.overflowing = false,
+                // the host isn't keeping up, so drop packets rather than stall the connection
+                Err(TrySendError::Full(_)) => {
+                    if !self.overflowing {
+                        log!(Warn,
+                             "receive queue full, dropping packets from client {}",
+                             self.id);
                     }
-                    Ok(None) => {
-                        println!("the server socket wasn't actually ready");
+                    self.overflowing = true;
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    log!(Debug, "send packet to ladspa error! channel is dead.");
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+impl Source for Client {
+    fn start(&mut self, ctx: &mut Context) -> bool {
+        let res = self.socket.set_nodelay(true).and_then(|_| ctx.register(&self.socket));
+        match res.and_then(|_| Negotiation::respond(&self.config)) {
+            Ok(negotiation) => self.negotiation = Some(negotiation),
+            Err(e) => {
+                log!(Warn, "can't take client {}: {}", self.id, e);
+                return false;
+            }
+        }
+        ctx.timeout_ms(HANDSHAKE_TIMEOUT_MS);
+        self.ready(ctx, EventSet::none())
+    }
+
+    fn ready(&mut self, _: &mut Context, _: EventSet) -> bool {
+        if self.reader.is_none() {
+            match self.negotiation.as_mut().unwrap().advance(&mut self.socket) {
+                Ok(Some(protection)) => {
+                    if let Err(e) = self.pass.enter() {
+                        log!(Warn, "rejected client {}: {}: {}", self.id, self.peer, e);
+                        return false;
                     }
+                    self.tracks.provide(self.gate.len());
+                    self.reader = Some(FrameReader::new(protection));
+                    self.tap = Some(self.taps.client(self.id, &self.peer.to_string()));
+                    log!(Info, "server accept client {}", self.id);
+                }
+                Ok(None) => return true,
+                Err(e) => {
+                    self.reject();
+                    log!(Warn, "handshake with client {} failed: {}", self.id, e);
+                    return false;
+                }
+            }
+        }
+        let mut buf = [0; 4096];
+        loop {
+            match self.socket.read(&mut buf[..]) {
+                // if we got a length zero read, the connection is done.
+                Ok(0) => {
+                    log!(Debug, "read zero bytes");
+                    return false;
+                }
+                Ok(n) => self.reader.as_mut().unwrap().push(&buf[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(e) => {
+                    log!(Info, "client {} disconnected: {}", self.id, e);
+                    return false;
+                }
+            }
+            loop {
+                match self.reader.as_mut().unwrap().next_frame_into(&mut self.frame) {
+                    Ok(true) => {}
+                    Ok(false) => break,
                     Err(e) => {
-                        println!("listener.accept() errored: {}", e);
-                        // event_loop.shutdown();
+                        // we can't trust anything after this, so hang up
+                        let count = self.reject();
+                        log!(Warn,
+                             "rejected frame from client {}: {} ({} total)",
+                             self.id,
+                             e,
+                             count);
+                        return false;
                     }
                 }
+                if !self.handle() {
+                    return false;
+                }
             }
-            _ => panic!("Received unknown token"),
         }
     }
-    fn notify(&mut self, event_loop: &mut EventLoop<Self>, _: Self::Message) {
-        event_loop.shutdown();
+
+    fn timeout(&mut self, _: &mut Context) -> bool {
+        if self.reader.is_none() {
+            self.reject();
+            log!(Warn, "handshake with client {} timed out", self.id);
+            return false;
+        }
+        true
     }
 }
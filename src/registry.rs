@@ -0,0 +1,333 @@
+// A directory of the plugins running on this machine. Each active plugin keeps a small JSON file
+// in `$XDG_RUNTIME_DIR/feedback` saying what it is, which lets tools show channels by name and
+// find out who is on them. Receivers publish once they are listening and keep their client count
+// up to date, so the registry doubles as service discovery. Files left behind by a crashed host
+// are ignored and cleaned up by the next reader.
+
+use std::env;
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use libc;
+use rustc_serialize::json;
+
+use super::{CHANNELS, SESSIONS};
+use super::config::Config;
+
+static INSTANCE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[derive(RustcEncodable, RustcDecodable, Clone, Copy, PartialEq, Debug)]
+pub enum Role {
+    Transmitter,
+    Receiver,
+}
+
+#[derive(RustcEncodable, RustcDecodable, Clone, PartialEq, Debug)]
+pub struct Entry {
+    pub name: Option<String>,
+    pub channel: u16,
+    pub session: u16,
+    pub role: Role,
+    pub pid: u32,
+    /// Where the transmitter sends to or the receiver listens.
+    pub address: String,
+    pub sample_rate: u64,
+    /// How many transmitters a receiver is serving. None for transmitters.
+    pub clients: Option<usize>,
+    /// How many samples behind its transmitters a receiver is playing. None for transmitters.
+    pub latency: Option<usize>,
+    /// Where a receiver takes tap connections from monitoring tools.
+    pub tap: Option<String>,
+}
+
+impl Entry {
+    /// Describes a plugin in this process.
+    pub fn new(config: &Config, role: Role, channel: u16, session: u16, sample_rate: u64) -> Entry {
+        let session = session.min(SESSIONS - 1);
+        let host = match role {
+            Role::Transmitter => config.host,
+            Role::Receiver => config.bind,
+        };
+        Entry {
+            name: config.channel_name(channel).map(|name| name.to_string()),
+            channel: channel,
+            session: session,
+            role: role,
+            pid: unsafe { libc::getpid() } as u32,
+            address: SocketAddr::new(host, config.port(session, channel)).to_string(),
+            sample_rate: sample_rate,
+            clients: match role {
+                Role::Transmitter => None,
+                Role::Receiver => Some(0),
+            },
+            latency: match role {
+                Role::Transmitter => None,
+                Role::Receiver => Some(0),
+            },
+            tap: None,
+        }
+    }
+}
+
+pub struct Registry {
+    dir: PathBuf,
+}
+
+impl Registry {
+    /// The registry the config points at, or the one for the current user, if there is anywhere
+    /// to keep it.
+    pub fn open(config: &Config) -> Option<Registry> {
+        let (dir, res) = match (config.registry_dir.as_ref(), env::var("XDG_RUNTIME_DIR")) {
+            (Some(dir), _) => {
+                let dir = PathBuf::from(dir);
+                let res = fs::create_dir_all(&dir);
+                (dir, res)
+            }
+            (None, Ok(dir)) => {
+                let dir = PathBuf::from(dir).join("feedback");
+                let res = fs::create_dir_all(&dir);
+                (dir, res)
+            }
+            (None, Err(_)) => {
+                let dir = env::temp_dir().join(format!("feedback-{}", unsafe { libc::getuid() }));
+                let res = private_dir(&dir);
+                (dir, res)
+            }
+        };
+        match res {
+            Ok(()) => Some(Registry::at(dir)),
+            Err(e) => {
+                log!(Warn, "can't create registry at {}: {}", dir.display(), e);
+                None
+            }
+        }
+    }
+
+    pub fn at(dir: PathBuf) -> Registry {
+        Registry { dir: dir }
+    }
+
+    /// Adds an entry, which stays until the returned registration is dropped.
+    pub fn publish(&self, entry: &Entry) -> io::Result<Registration> {
+        let instance = INSTANCE.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.join(format!("{}-{}.json", entry.pid, instance));
+        try!(write_entry(&path, entry));
+        Ok(Registration { path: path })
+    }
+
+    /// Lists the plugins that are still alive, ordered by session and channel.
+    pub fn entries(&self) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(_) => return entries,
+        };
+        for file in dir.filter_map(|file| file.ok()) {
+            let path = file.path();
+            if path.extension().map(|ext| ext != "json").unwrap_or(true) {
+                continue;
+            }
+            let mut text = String::new();
+            if File::open(&path).and_then(|mut f| f.read_to_string(&mut text)).is_err() {
+                continue;
+            }
+            match json::decode::<Entry>(&text) {
+                Ok(ref entry) if !alive(entry.pid) => {
+                    let _ = fs::remove_file(&path);
+                }
+                Ok(entry) => entries.push(entry),
+                Err(_) => {}
+            }
+        }
+        entries.sort_by(|a, b| (a.session, a.channel, a.pid).cmp(&(b.session, b.channel, b.pid)));
+        entries
+    }
+
+    /// When an entry was last added, changed or removed. Entries are replaced by renaming, so
+    /// this is when the directory last changed.
+    pub fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.dir).and_then(|meta| meta.modified()).ok()
+    }
+
+    /// The receivers listening on a channel, for transmitters to check who they'll reach.
+    pub fn receivers(&self, session: u16, channel: u16) -> Vec<Entry> {
+        self.entries()
+            .into_iter()
+            .filter(|e| e.role == Role::Receiver && e.session == session && e.channel == channel)
+            .collect()
+    }
+}
+
+// Makes a directory only this user can get into, or checks that one already is. Anyone can make
+// directories in /tmp, so one that's already there may not be ours.
+fn private_dir(dir: &Path) -> io::Result<()> {
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    let meta = try!(fs::symlink_metadata(dir));
+    if !meta.is_dir() || meta.uid() != unsafe { libc::getuid() } || meta.mode() & 0o077 != 0 {
+        return Err(io::Error::new(ErrorKind::PermissionDenied,
+                                  "it isn't a directory only this user can use"));
+    }
+    Ok(())
+}
+
+fn write_entry(path: &Path, entry: &Entry) -> io::Result<()> {
+    // write then rename, so readers never see half an entry
+    let tmp = path.with_extension("tmp");
+    let text = json::encode(entry).unwrap();
+    try!(File::create(&tmp).and_then(|mut f| f.write_all(text.as_bytes())));
+    fs::rename(&tmp, path)
+}
+
+/// Publishes a plugin in this process to the registry `config` points at. Failing to is logged
+/// but otherwise harmless.
+pub fn announce(config: &Config, entry: &Entry) -> Option<Registration> {
+    let registry = match Registry::open(config) {
+        Some(registry) => registry,
+        None => return None,
+    };
+    match registry.publish(entry) {
+        Ok(registration) => Some(registration),
+        Err(e) => {
+            log!(Warn, "can't publish to registry: {}", e);
+            None
+        }
+    }
+}
+
+/// Keeps a plugin's entry in the registry. Dropping it removes the entry.
+pub struct Registration {
+    path: PathBuf,
+}
+
+impl Registration {
+    /// Replaces the published entry, like when a receiver's client count changes.
+    pub fn update(&self, entry: &Entry) -> io::Result<()> {
+        write_entry(&self.path, entry)
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn alive(pid: u32) -> bool {
+    // signal 0 checks the process exists without touching it
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Turns a channel given by the user into a channel number. It can be a number, a name from the
+/// config, or a name another process has published.
+pub fn resolve_channel(config: &Config,
+                       registry: Option<&Registry>,
+                       channel: &str)
+                       -> Result<u16, String> {
+    if let Ok(number) = channel.parse::<u32>() {
+        if number >= CHANNELS as u32 {
+            return Err(format!("channel {} is out of range, the last is {}", number, CHANNELS - 1));
+        }
+        return Ok(number as u16);
+    }
+    if let Some(channel) = config.channels.get(channel) {
+        return Ok(*channel);
+    }
+    for entry in registry.map(|r| r.entries()).unwrap_or(Vec::new()) {
+        if entry.name.as_ref().map(|name| name == channel).unwrap_or(false) {
+            return Ok(entry.channel);
+        }
+    }
+    Err(format!("no channel is named {}", channel))
+}
+
+#[cfg(test)]
+fn test_registry(name: &str) -> Registry {
+    let pid = unsafe { libc::getpid() };
+    let dir = env::temp_dir().join(format!("feedback-test-{}-{}", name, pid));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    Registry::at(dir)
+}
+
+#[test]
+fn test_registry_publish() {
+    let registry = test_registry("publish");
+    let mut config = Config::default();
+    config.channels.insert("vocal reverb".to_string(), 37);
+
+    let rx = registry.publish(&Entry::new(&config, Role::Receiver, 37, 0, 44100)).unwrap();
+    let tx = registry.publish(&Entry::new(&config, Role::Transmitter, 2, 1, 44100)).unwrap();
+    let entries = registry.entries();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].name, Some("vocal reverb".to_string()));
+    assert_eq!((entries[0].channel, entries[0].role), (37, Role::Receiver));
+    assert_eq!(entries[0].address, format!("127.0.0.1:{}", config.port(0, 37)));
+    assert_eq!(entries[1].name, None);
+    assert_eq!((entries[1].session, entries[1].clients), (1, None));
+
+    // receivers keep their client count current
+    let mut entry = entries[0].clone();
+    entry.clients = Some(3);
+    rx.update(&entry).unwrap();
+    assert_eq!(registry.receivers(0, 37), vec![entry]);
+    assert!(registry.receivers(0, 2).is_empty());
+
+    drop(rx);
+    assert_eq!(registry.entries().len(), 1);
+    drop(tx);
+    assert!(registry.entries().is_empty());
+}
+
+#[test]
+fn test_registry_stale() {
+    let registry = test_registry("stale");
+    let mut entry = Entry::new(&Config::default(), Role::Receiver, 1, 0, 44100);
+    // no process has this pid, the kernel never hands it out
+    entry.pid = i32::max_value() as u32;
+    let registration = registry.publish(&entry).unwrap();
+    assert!(registry.entries().is_empty());
+    // the stale file was cleaned up
+    assert!(!registration.path.exists());
+}
+
+#[test]
+fn test_registry_resolve() {
+    let registry = test_registry("resolve");
+    let mut config = Config::default();
+    assert_eq!(resolve_channel(&config, Some(&registry), "12"), Ok(12));
+    assert_eq!(resolve_channel(&config, Some(&registry), "255"), Ok(255));
+    assert!(resolve_channel(&config, Some(&registry), "256").is_err());
+    assert!(resolve_channel(&config, Some(&registry), "drums").is_err());
+
+    // a name only another process knows about
+    config.channels.insert("drums".to_string(), 9);
+    let _drums = registry.publish(&Entry::new(&config, Role::Receiver, 9, 0, 44100)).unwrap();
+    assert_eq!(resolve_channel(&Config::default(), Some(&registry), "drums"), Ok(9));
+    assert_eq!(resolve_channel(&config, None, "drums"), Ok(9));
+}
+
+#[test]
+fn test_registry_private_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = env::temp_dir().join(format!("feedback-test-private-{}", unsafe { libc::getpid() }));
+    let _ = fs::remove_dir_all(&dir);
+    private_dir(&dir).unwrap();
+    assert_eq!(fs::metadata(&dir).unwrap().mode() & 0o777, 0o700);
+    private_dir(&dir).unwrap();
+
+    // one that others can get into isn't used
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+    assert!(private_dir(&dir).is_err());
+    fs::remove_dir(&dir).unwrap();
+}
@@ -0,0 +1,287 @@
+// Authenticated encryption for the packet stream.
+//
+// When a connection opens, the transmitter and receiver each send a fresh X25519 public key. The
+// shared secret is run through HKDF-SHA256 salted with the pre-shared key, in the same spirit as
+// Noise's NNpsk0 pattern, so only peers holding the key arrive at the same session key, and a
+// recorded session can't be decrypted later even if the key leaks. Frames are then sealed with
+// ChaCha20-Poly1305 using a per-connection frame counter as the nonce.
+//
+// Only the length header of each frame is sent in the clear. It is covered by the tag.
+
+use std::io::{self, Read, Write, ErrorKind};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::curve25519::{curve25519, curve25519_base};
+use crypto::hkdf::{hkdf_extract, hkdf_expand};
+use crypto::sha2::Sha256;
+
+use rand::{Rng, OsRng};
+
+pub const TAG_SIZE: usize = 16;
+
+const MAGIC: &'static [u8] = b"FBK1";
+const KEY_SIZE: usize = 32;
+pub const HELLO_SIZE: usize = 4 + KEY_SIZE;
+pub const HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+
+pub struct Cipher {
+    key: [u8; KEY_SIZE],
+    counter: u64,
+}
+
+impl Cipher {
+    fn nonce(&mut self) -> [u8; 8] {
+        let mut nonce = [0u8; 8];
+        for i in 0..8 {
+            nonce[i] = (self.counter >> (8 * i)) as u8;
+        }
+        self.counter += 1;
+        nonce
+    }
+
+    /// Encrypts `payload`, returning the ciphertext followed by a tag covering it and `header`.
+    pub fn seal(&mut self, header: &[u8], payload: &[u8]) -> Vec<u8> {
+        let nonce = self.nonce();
+        let mut out = vec![0; payload.len() + TAG_SIZE];
+        {
+            let (ciphertext, tag) = out.split_at_mut(payload.len());
+            ChaCha20Poly1305::new(&self.key, &nonce, header).encrypt(payload, ciphertext, tag);
+        }
+        out
+    }
+
+    /// Reverses `seal`, or returns None if the frame has been tampered with.
+    pub fn open(&mut self, header: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < TAG_SIZE {
+            return None;
+        }
+        let nonce = self.nonce();
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_SIZE);
+        let mut payload = vec![0; ciphertext.len()];
+        if ChaCha20Poly1305::new(&self.key, &nonce, header).decrypt(ciphertext, &mut payload, tag) {
+            Some(payload)
+        } else {
+            None
+        }
+    }
+}
+
+fn session_key(psk: &[u8], shared: &[u8], initiator: &[u8], responder: &[u8]) -> Cipher {
+    let mut prk = [0u8; KEY_SIZE];
+    hkdf_extract(Sha256::new(), psk, shared, &mut prk);
+    let mut info = b"feedback stream".to_vec();
+    info.extend(initiator.iter().cloned());
+    info.extend(responder.iter().cloned());
+    let mut key = [0u8; KEY_SIZE];
+    hkdf_expand(Sha256::new(), &prk, &info, &mut key);
+    Cipher {
+        key: key,
+        counter: 0,
+    }
+}
+
+fn ephemeral() -> io::Result<([u8; KEY_SIZE], [u8; KEY_SIZE])> {
+    let mut secret = [0u8; KEY_SIZE];
+    try!(OsRng::new()).fill_bytes(&mut secret);
+    let public = curve25519_base(&secret);
+    Ok((secret, public))
+}
+
+fn hello(public: &[u8]) -> Vec<u8> {
+    let mut hello = MAGIC.to_vec();
+    hello.extend(public.iter().cloned());
+    hello
+}
+
+// The sockets are non-blocking, and the connection may not even be up yet, so keep trying until
+// the deadline.
+fn retry<F: FnMut() -> io::Result<usize>>(deadline: Instant, mut op: F) -> io::Result<usize> {
+    loop {
+        match op() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                          e.kind() == ErrorKind::NotConnected => {
+                if Instant::now() > deadline {
+                    return Err(io::Error::new(ErrorKind::TimedOut, "handshake timed out"));
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            res => return res,
+        }
+    }
+}
+
+fn write_all<S: Write>(stream: &mut S, mut bytes: &[u8], deadline: Instant) -> io::Result<()> {
+    while !bytes.is_empty() {
+        let n = try!(retry(deadline, || stream.write(bytes)));
+        if n == 0 {
+            return Err(io::Error::new(ErrorKind::WriteZero, "connection closed in handshake"));
+        }
+        bytes = &bytes[n..];
+    }
+    Ok(())
+}
+
+/// Reads the other side's hello, waiting until `deadline` for it.
+pub fn read_hello<S: Read>(stream: &mut S, deadline: Instant) -> io::Result<[u8; HELLO_SIZE]> {
+    let mut buf = [0u8; HELLO_SIZE];
+    let mut pos = 0;
+    while pos < HELLO_SIZE {
+        let n = try!(retry(deadline, || stream.read(&mut buf[pos..])));
+        if n == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed in handshake"));
+        }
+        pos += n;
+    }
+    Ok(buf)
+}
+
+fn parse_hello(buf: &[u8]) -> io::Result<[u8; KEY_SIZE]> {
+    if buf.len() != HELLO_SIZE || &buf[..4] != MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "peer isn't speaking encrypted feedback"));
+    }
+    let mut public = [0u8; KEY_SIZE];
+    public.clone_from_slice(&buf[4..]);
+    Ok(public)
+}
+
+/// When a handshake starting now has to be done by.
+pub fn deadline() -> Instant {
+    Instant::now() + Duration::from_millis(HANDSHAKE_TIMEOUT_MS)
+}
+
+/// One side of a handshake, for callers that do their own I/O. Each side sends its hello, reads
+/// `HELLO_SIZE` bytes of the other's, and finishes with them. The initiator sends first.
+pub struct Handshake {
+    secret: [u8; KEY_SIZE],
+    public: [u8; KEY_SIZE],
+}
+
+impl Handshake {
+    pub fn new() -> io::Result<Handshake> {
+        let (secret, public) = try!(ephemeral());
+        Ok(Handshake {
+            secret: secret,
+            public: public,
+        })
+    }
+
+    pub fn hello(&self) -> Vec<u8> {
+        hello(&self.public)
+    }
+
+    pub fn finish_initiator(&self, psk: &[u8], peer_hello: &[u8]) -> io::Result<Cipher> {
+        let peer = try!(parse_hello(peer_hello));
+        Ok(session_key(psk, &curve25519(&self.secret, &peer), &self.public, &peer))
+    }
+
+    pub fn finish_responder(&self, psk: &[u8], peer_hello: &[u8]) -> io::Result<Cipher> {
+        let peer = try!(parse_hello(peer_hello));
+        Ok(session_key(psk, &curve25519(&self.secret, &peer), &peer, &self.public))
+    }
+}
+
+/// Run by the transmitter as soon as it connects. A wrong key isn't detected here, but the first
+/// frame will fail to open on the other end.
+pub fn handshake_initiator<S: Read + Write>(stream: &mut S, psk: &[u8]) -> io::Result<Cipher> {
+    let deadline = deadline();
+    let handshake = try!(Handshake::new());
+    try!(write_all(stream, &handshake.hello(), deadline));
+    let peer = try!(read_hello(stream, deadline));
+    handshake.finish_initiator(psk, &peer)
+}
+
+/// The receiver's side of `handshake_initiator`. Receivers run theirs on the reactor with a
+/// `Negotiation` instead, so this is only for testing against.
+#[cfg(test)]
+pub fn handshake_responder<S: Read + Write>(stream: &mut S, psk: &[u8]) -> io::Result<Cipher> {
+    let deadline = deadline();
+    let peer = try!(read_hello(stream, deadline));
+    let handshake = try!(Handshake::new());
+    try!(write_all(stream, &handshake.hello(), deadline));
+    handshake.finish_responder(psk, &peer)
+}
+
+#[cfg(test)]
+fn connected_pair() -> (::std::net::TcpStream, ::std::net::TcpStream) {
+    use std::net::{TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    (client, server)
+}
+
+#[test]
+fn test_secure_seal_open() {
+    let (mut client, mut server) = connected_pair();
+    let responder = thread::spawn(move || handshake_responder(&mut server, b"key").unwrap());
+    let mut sealer = handshake_initiator(&mut client, b"key").unwrap();
+    let mut opener = responder.join().unwrap();
+
+    let first = sealer.seal(b"head", b"first frame");
+    let second = sealer.seal(b"head", b"first frame");
+    // the counter nonce makes identical frames look different on the wire
+    assert!(first != second);
+    assert_eq!(opener.open(b"head", &first).unwrap(), b"first frame".to_vec());
+    assert_eq!(opener.open(b"head", &second).unwrap(), b"first frame".to_vec());
+
+    let mut tampered = sealer.seal(b"head", b"third frame");
+    tampered[0] ^= 1;
+    assert!(opener.open(b"head", &tampered).is_none());
+    let wrong_header = sealer.seal(b"head", b"fourth frame");
+    assert!(opener.open(b"hea!", &wrong_header).is_none());
+}
+
+#[test]
+fn test_secure_wrong_key() {
+    let (mut client, mut server) = connected_pair();
+    let responder = thread::spawn(move || handshake_responder(&mut server, b"other").unwrap());
+    let mut sealer = handshake_initiator(&mut client, b"key").unwrap();
+    let mut opener = responder.join().unwrap();
+    assert!(opener.open(b"", &sealer.seal(b"", b"frame")).is_none());
+}
+
+#[test]
+fn test_secure_no_plaintext_on_wire() {
+    use super::packet::{BUFFER_SIZE, Frame, FrameReader, FrameWriter, Packet, Protection};
+
+    // a recognizable signal, so we can look for it in the captured bytes
+    let ldata: Vec<f32> = (0..BUFFER_SIZE).map(|i| i as f32 * 0.001).collect();
+    let frame = Frame::Raw(Packet::new(&ldata, &ldata, 12345));
+    let plaintext = frame.as_bytes();
+
+    let (mut client, mut server) = connected_pair();
+    let responder = thread::spawn(move || {
+        let cipher = handshake_responder(&mut server, b"key").unwrap();
+        let mut wire = Vec::new();
+        server.read_to_end(&mut wire).unwrap();
+        (cipher, wire)
+    });
+    let cipher = handshake_initiator(&mut client, b"key").unwrap();
+    let mut writer = FrameWriter::new(Protection::Encrypted(cipher));
+    for _ in 0..4 {
+        client.write_all(&writer.write(&frame)).unwrap();
+    }
+    drop(client);
+    let (cipher, wire) = responder.join().unwrap();
+
+    // no run of 16 plaintext bytes shows up anywhere on the wire
+    for chunk in plaintext[4..].chunks(16).filter(|c| c.len() == 16) {
+        assert!(!wire.windows(16).any(|w| w == chunk));
+    }
+
+    let mut reader = FrameReader::new(Protection::Encrypted(cipher));
+    reader.push(&wire);
+    for _ in 0..4 {
+        match reader.next_frame().unwrap() {
+            Some(Frame::Raw(packet)) => {
+                assert_eq!(packet.get_timestamp(), 12345);
+                assert_eq!(packet.get_ldata(), &ldata[..]);
+            }
+            _ => panic!("expected a raw frame"),
+        }
+    }
+}
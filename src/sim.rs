@@ -0,0 +1,463 @@
+// A network that only exists in memory, for testing plugins without sockets or sleeping. Time
+// stands still until a test moves the clock, and every delay, loss and swap comes from a seeded
+// generator, so a test sees exactly the same thing on every run.
+//
+// Frames a transmitter sends are picked up when the clock next moves, so its send queue has to
+// hold everything it sends in between.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError, TrySendError};
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use super::access::Pass;
+use super::codec::Decoder;
+use super::config::Config;
+use super::packet::Frame;
+use super::receive;
+use super::registry::Entry;
+use super::transport::{Inbox, Link, Outbox, Transport};
+
+/// How badly the network behaves. Times are in microseconds.
+#[derive(Clone, Default)]
+pub struct Conditions {
+    /// How long every frame takes to arrive.
+    pub latency: u64,
+    /// Up to this much more is added to each frame at random. Frames still arrive in the order
+    /// they were sent, as they do over TCP.
+    pub jitter: u64,
+    /// The chance that a frame is lost. TCP never loses a frame, so this and `reorder` are for
+    /// checking that the receiver places audio by its timestamps, not by when it arrived.
+    pub loss: f64,
+    /// The chance that a frame swaps places with the next one.
+    pub reorder: f64,
+}
+
+struct Listener {
+    id: u64,
+    inbox: Inbox,
+}
+
+struct Client {
+    listener: u64,
+    id: u64,
+    _pass: Pass,
+    decoder: Option<Decoder>,
+}
+
+struct Connection {
+    id: u64,
+    // session and channel
+    to: (u16, u16),
+    frames: Receiver<Frame>,
+    // in the order they will arrive
+    in_flight: VecDeque<(u64, Frame)>,
+    held: Option<Frame>,
+    last_arrival: u64,
+    // set once the receiver lets the transmitter in
+    client: Option<Client>,
+    // the transmitter has stopped, but what it already sent is still on its way
+    finished: bool,
+    hung_up: Arc<AtomicBool>,
+    latency: Arc<AtomicUsize>,
+    connected: Arc<AtomicBool>,
+}
+
+struct State {
+    now: u64,
+    conditions: Conditions,
+    rng: XorShiftRng,
+    // the first listener on a channel gets it, the rest wait their turn
+    listeners: HashMap<(u16, u16), Vec<Listener>>,
+    connections: Vec<Connection>,
+    disconnects: Vec<u64>,
+    next_id: u64,
+}
+
+impl State {
+    fn id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    // takes in what transmitters sent since the clock last moved
+    fn pick_up(&mut self) {
+        let now = self.now;
+        let conditions = self.conditions.clone();
+        for conn in &mut self.connections {
+            loop {
+                let frame = match conn.frames.try_recv() {
+                    Ok(frame) => frame,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        conn.finished = true;
+                        break;
+                    }
+                };
+                if self.rng.next_f64() < conditions.loss {
+                    continue;
+                }
+                if conn.held.is_none() && self.rng.next_f64() < conditions.reorder {
+                    conn.held = Some(frame);
+                    continue;
+                }
+                for frame in Some(frame).into_iter().chain(conn.held.take()) {
+                    let delay = conditions.latency + self.rng.gen_range(0, conditions.jitter + 1);
+                    let arrival = (now + delay).max(conn.last_arrival);
+                    conn.last_arrival = arrival;
+                    conn.in_flight.push_back((arrival, frame));
+                }
+            }
+        }
+    }
+
+    // hands over every frame that has arrived by now
+    fn deliver(&mut self) {
+        let now = self.now;
+        let mut next_id = self.next_id;
+        let listeners = &mut self.listeners;
+        for conn in &mut self.connections {
+            if conn.client.is_none() {
+                let listener = match listeners.get(&conn.to).and_then(|l| l.first()) {
+                    Some(listener) => listener,
+                    None => {
+                        // nobody is listening, so the connection is refused
+                        conn.hung_up.store(true, Ordering::SeqCst);
+                        continue;
+                    }
+                };
+                next_id += 1;
+                let peer: SocketAddr = format!("127.0.0.1:{}", 40000 + conn.id).parse().unwrap();
+                let hung_up = conn.hung_up.clone();
+                let hangup = Box::new(move || hung_up.store(true, Ordering::SeqCst));
+                // there's no handshake, so the client is counted straight away
+                let admitted = listener.inbox.gate.admit_with(next_id, &peer, hangup);
+                match admitted.and_then(|mut pass| pass.enter().map(|_| pass)) {
+                    Ok(pass) => {
+                        listener.inbox.tracks.provide(listener.inbox.gate.len());
+                        conn.client = Some(Client {
+                            listener: listener.id,
+                            id: next_id,
+                            _pass: pass,
+                            decoder: None,
+                        });
+                        conn.connected.store(true, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        log!(Warn, "rejected client {}: {}", next_id, e);
+                        conn.hung_up.store(true, Ordering::SeqCst);
+                        continue;
+                    }
+                }
+            }
+            let client = conn.client.as_mut().unwrap();
+            let listener = match listeners.get(&conn.to).and_then(|l| l.first()) {
+                Some(listener) if listener.id == client.listener => listener,
+                _ => {
+                    // the receiver went away
+                    conn.hung_up.store(true, Ordering::SeqCst);
+                    continue;
+                }
+            };
+            conn.latency.store(listener.inbox.latency.load(Ordering::Relaxed), Ordering::Relaxed);
+            while conn.in_flight.front().map(|&(arrival, _)| arrival <= now).unwrap_or(false) {
+                let (_, mut frame) = conn.in_flight.pop_front().unwrap();
+                if let Some(packet) = receive::decode_frame(&mut frame, &mut client.decoder) {
+                    match listener.inbox.packets.try_send((client.id, packet)) {
+                        Ok(()) | Err(TrySendError::Full(_)) => {}
+                        Err(TrySendError::Disconnected(_)) => {
+                            conn.hung_up.store(true, Ordering::SeqCst)
+                        }
+                    }
+                }
+            }
+        }
+        self.next_id = next_id;
+        self.connections.retain(|conn| {
+            let keep = !conn.hung_up.load(Ordering::SeqCst) &&
+                       !(conn.finished && conn.in_flight.is_empty());
+            if !keep {
+                conn.connected.store(false, Ordering::Relaxed);
+            }
+            keep
+        });
+    }
+}
+
+/// The network. Clones are the same network.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<State>>,
+}
+
+impl SimNetwork {
+    pub fn new(conditions: Conditions, seed: u32) -> SimNetwork {
+        SimNetwork {
+            state: Arc::new(Mutex::new(State {
+                now: 0,
+                conditions: conditions,
+                rng: XorShiftRng::from_seed([seed, 0x9e3779b9, 0x7f4a7c15, 0x85ebca6b]),
+                listeners: HashMap::new(),
+                connections: Vec::new(),
+                disconnects: Vec::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    /// The time on the clock, in microseconds.
+    pub fn now(&self) -> u64 {
+        self.state.lock().unwrap().now
+    }
+
+    /// Hangs up every connection once the clock reaches `time`. What was in flight is lost.
+    pub fn disconnect_at(&self, time: u64) {
+        self.state.lock().unwrap().disconnects.push(time);
+    }
+
+    /// Moves the clock on, delivering every frame that arrives in the meantime.
+    pub fn advance(&self, micros: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.pick_up();
+        let end = state.now + micros;
+        state.disconnects.sort();
+        while state.disconnects.first().map(|&t| t <= end).unwrap_or(false) {
+            let time = state.disconnects.remove(0);
+            state.now = state.now.max(time);
+            state.deliver();
+            for conn in &state.connections {
+                conn.hung_up.store(true, Ordering::SeqCst);
+            }
+            state.deliver();
+        }
+        state.now = end;
+        state.deliver();
+    }
+
+    /// How many transmitters are connected or connecting.
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections.len()
+    }
+}
+
+impl Transport for SimNetwork {
+    fn transmit(&self, _: &Config, entry: Entry, outbox: Outbox) -> Link {
+        let mut state = self.state.lock().unwrap();
+        let id = state.id();
+        let hung_up = Arc::new(AtomicBool::new(false));
+        state.connections.push(Connection {
+            id: id,
+            to: (entry.session, entry.channel),
+            frames: outbox.frames,
+            in_flight: VecDeque::new(),
+            held: None,
+            last_arrival: 0,
+            client: None,
+            finished: false,
+            hung_up: hung_up.clone(),
+            latency: outbox.latency,
+            connected: outbox.connected,
+        });
+        Link::new(Box::new(move || hung_up.store(true, Ordering::SeqCst)))
+    }
+
+    fn receive(&self, _: &Config, entry: Entry, inbox: Inbox) -> Link {
+        let mut state = self.state.lock().unwrap();
+        let id = state.id();
+        let to = (entry.session, entry.channel);
+        state.listeners.entry(to).or_insert(Vec::new()).push(Listener {
+            id: id,
+            inbox: inbox,
+        });
+        let network = self.clone();
+        Link::new(Box::new(move || {
+            let mut state = network.state.lock().unwrap();
+            if let Some(listeners) = state.listeners.get_mut(&to) {
+                listeners.retain(|listener| listener.id != id);
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+use super::transmit::FeedbackSender;
+#[cfg(test)]
+use super::receive::FeedbackReceiver;
+
+// at 100 kHz, each 100 sample block lasts a millisecond
+#[cfg(test)]
+fn test_plugins(network: &SimNetwork) -> (FeedbackSender, FeedbackReceiver) {
+    let mut config = Config::default();
+    config.send_queue = 64;
+    let mut tx = FeedbackSender::with_transport(config.clone(), 100000, Arc::new(network.clone()));
+    let mut rx = FeedbackReceiver::with_transport(config, 100000, Arc::new(network.clone()));
+    rx.start();
+    tx.start();
+    (tx, rx)
+}
+
+// Sends a block at each level in turn, a millisecond apart, and returns the first sample the
+// receiver played after each, if it played anything.
+#[cfg(test)]
+fn test_levels(network: &SimNetwork,
+               tx: &mut FeedbackSender,
+               rx: &mut FeedbackReceiver,
+               levels: &[f32])
+               -> Vec<Option<f32>> {
+    levels.iter()
+          .map(|&level| {
+              tx.push(&[level; 100], &[-level; 100]);
+              network.advance(1000);
+              let mut outputl = [0.0; 100];
+              let mut outputr = [0.0; 100];
+              if rx.pull(&mut outputl, &mut outputr) {
+                  assert_eq!(outputl[99], -outputr[99]);
+                  Some(outputl[0])
+              } else {
+                  None
+              }
+          })
+          .collect()
+}
+
+#[test]
+fn test_sim_latency() {
+    let network = SimNetwork::new(Conditions { latency: 2500, ..Conditions::default() }, 1);
+    let (mut tx, mut rx) = test_plugins(&network);
+    assert_eq!(test_levels(&network, &mut tx, &mut rx, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]),
+               vec![None, None, Some(1.0), Some(2.0), Some(3.0), Some(4.0)]);
+    assert_eq!(network.now(), 6000);
+    assert_eq!(network.connections(), 1);
+}
+
+#[test]
+fn test_sim_jitter() {
+    let conditions = Conditions {
+        latency: 1000,
+        jitter: 4000,
+        ..Conditions::default()
+    };
+    let levels: Vec<f32> = (1..41).map(|i| i as f32).collect();
+    let run = || {
+        let network = SimNetwork::new(conditions.clone(), 7);
+        let (mut tx, mut rx) = test_plugins(&network);
+        test_levels(&network, &mut tx, &mut rx, &levels)
+    };
+    let played = run();
+    // the same seed gives the same run
+    assert_eq!(played, run());
+    // late blocks come up short, but everything is played, in order
+    assert!(played.iter().any(|level| level.is_none()));
+    let heard: Vec<f32> = played.iter().filter_map(|&level| level).collect();
+    assert!(heard.len() > 30);
+    assert_eq!(heard, levels[..heard.len()].to_vec());
+}
+
+#[test]
+fn test_sim_loss_and_reorder() {
+    let levels: Vec<f32> = (1..41).map(|i| i as f32).collect();
+
+    // lost blocks are played as silence, and the rest stay where they belong
+    let network = SimNetwork::new(Conditions { loss: 0.25, ..Conditions::default() }, 3);
+    let (mut tx, mut rx) = test_plugins(&network);
+    let played = test_levels(&network, &mut tx, &mut rx, &levels);
+    let heard: Vec<f32> = played.iter().filter_map(|&level| level).collect();
+    let lost = heard.iter().filter(|&&level| level == 0.0).count();
+    assert!(lost > 0 && lost < 20);
+    for (i, &level) in heard.iter().enumerate() {
+        assert!(level == levels[i] || level == 0.0);
+    }
+
+    // swapped blocks are put back in order as long as they both arrive in time
+    let conditions = Conditions {
+        latency: 1500,
+        reorder: 0.3,
+        ..Conditions::default()
+    };
+    let network = SimNetwork::new(conditions, 5);
+    let (mut tx, mut rx) = test_plugins(&network);
+    let played = test_levels(&network, &mut tx, &mut rx, &levels);
+    let heard: Vec<f32> = played.iter().filter_map(|&level| level).collect();
+    assert_eq!(heard, levels[..heard.len()].to_vec());
+}
+
+#[test]
+fn test_sim_receive_queue_full() {
+    // a receiver that isn't being run keeps what fits in its queue and drops the rest
+    let network = SimNetwork::new(Conditions::default(), 1);
+    let mut config = Config::default();
+    config.send_queue = 64;
+    config.receive_queue = 4;
+    let mut tx = FeedbackSender::with_transport(config.clone(), 100000, Arc::new(network.clone()));
+    let mut rx = FeedbackReceiver::with_transport(config, 100000, Arc::new(network.clone()));
+    rx.start();
+    tx.start();
+    for level in 1..11 {
+        tx.push(&[level as f32; 100], &[level as f32; 100]);
+        network.advance(1000);
+    }
+    let mut played = Vec::new();
+    for _ in 0..5 {
+        let mut outputl = [0.0; 100];
+        let mut outputr = [0.0; 100];
+        played.push(if rx.pull(&mut outputl, &mut outputr) { Some(outputl[0]) } else { None });
+    }
+    assert_eq!(played, vec![Some(1.0), Some(2.0), Some(3.0), Some(4.0), None]);
+
+    // what was dropped is played as silence, and the receiver carries on from there
+    assert_eq!(test_levels(&network, &mut tx, &mut rx, &[11.0; 7]),
+               vec![Some(0.0), Some(0.0), Some(0.0), Some(0.0), Some(0.0), Some(0.0), Some(11.0)]);
+}
+
+#[test]
+fn test_sim_codec_small_blocks() {
+    // a sound shorter than a codec frame is still heard in full when silence follows it
+    let network = SimNetwork::new(Conditions::default(), 1);
+    let (mut tx, mut rx) = test_plugins(&network);
+    tx.set_codec(true, 512);
+    tx.set_block_size(20);
+    let mut played = Vec::new();
+    for i in 0..20 {
+        let level = if i < 5 { 0.5 } else { 0.0 };
+        tx.push(&[level; 20], &[level; 20]);
+        network.advance(200);
+        let mut outputl = [0.0; 20];
+        let mut outputr = [0.0; 20];
+        if rx.pull(&mut outputl, &mut outputr) {
+            played.extend_from_slice(&outputl);
+        }
+    }
+    assert!(played.len() >= 100);
+    assert!(played[20..80].iter().all(|&x| (x - 0.5).abs() < 0.15));
+}
+
+#[test]
+fn test_sim_disconnect() {
+    let network = SimNetwork::new(Conditions::default(), 1);
+    let (mut tx, mut rx) = test_plugins(&network);
+    network.disconnect_at(2500);
+    let levels: Vec<f32> = (1..11).map(|i| i as f32).collect();
+    // the third block arrives just before the line drops, and the fourth fails to send, then
+    // the transmitter reconnects and carries on
+    assert_eq!(test_levels(&network, &mut tx, &mut rx, &levels),
+               vec![Some(1.0),
+                    Some(2.0),
+                    Some(3.0),
+                    None,
+                    Some(5.0),
+                    Some(6.0),
+                    Some(7.0),
+                    Some(8.0),
+                    Some(9.0),
+                    Some(10.0)]);
+    assert_eq!(network.connections(), 1);
+
+    // nothing gets through once the receiver goes away
+    drop(rx);
+    tx.push(&[1.0; 100], &[1.0; 100]);
+    network.advance(1000);
+    assert_eq!(network.connections(), 0);
+}
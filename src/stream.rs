@@ -0,0 +1,506 @@
+// Transmitting and receiving as futures, for programs that already run on tokio. A
+// transmitter is a `Sink` of packets and a receiver is a `Stream` of the packets its clients
+// send, so channels can be bridged, filtered or watched alongside other async network code. The
+// bytes on the wire are the same frames the plugins send, protected the same way, so either end
+// can be a plugin.
+//
+// Every client of a `PacketStream` is served by the runtime that polls it, rather than by a
+// thread of its own. Unlike the plugins, neither end shows up in the registry.
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use futures::future::Either;
+use tokio::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+use tokio::io::{read_exact, write_all};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::Incoming;
+use tokio::timer::Delay;
+
+use super::access::{Gate, Pass};
+use super::auth::{self, Authenticator, CHALLENGE_SIZE};
+use super::codec;
+use super::config::{Config, Security};
+use super::packet::{Frame, FrameReader, FrameWriter, Packet, Protection};
+use super::receive;
+use super::secure::{Handshake, HANDSHAKE_TIMEOUT_MS, HELLO_SIZE};
+
+// how many packets are read ahead of the stream's consumer before clients are left for later
+const MAX_READY: usize = 64;
+
+type Protected = Box<Future<Item = (TcpStream, Protection), Error = io::Error> + Send>;
+// what each end of a handshake has sent the other
+type Exchange = Box<Future<Item = (TcpStream, Vec<u8>), Error = io::Error> + Send>;
+
+/// Connects to the receiver on `channel` of `session`, and resolves to a sink for the packets to
+/// send it. Each packet is sent whole, without the codec or silence detection, and should carry
+/// on in time from the one before.
+pub fn connect(config: &Config,
+               channel: u16,
+               session: u16)
+               -> Box<Future<Item = PacketSink, Error = io::Error> + Send> {
+    let addr = SocketAddr::new(config.host, config.port(session, channel));
+    let config = config.clone();
+    Box::new(TcpStream::connect(&addr)
+                 .and_then(move |socket| {
+                     try!(socket.set_nodelay(true));
+                     Ok(protect(socket, &config, true))
+                 })
+                 .flatten()
+                 .map(|(socket, protection)| {
+                     PacketSink { frames: FramedWrite::new(socket, FrameEncoder::new(protection)) }
+                 }))
+}
+
+/// Listens on `channel` of `session`. The stream gives each packet clients send, decoded and
+/// tagged with the client it came from, and only ends if listening fails. Clients are served on
+/// whichever runtime polls it.
+pub fn listen(config: &Config, channel: u16, session: u16) -> io::Result<PacketStream> {
+    let addr = SocketAddr::new(config.bind, config.port(session, channel));
+    let listener = try!(TcpListener::bind(&addr));
+    Ok(PacketStream {
+        local_addr: try!(listener.local_addr()),
+        incoming: listener.incoming(),
+        gate: Gate::new(config.allow.clone(), config.max_clients, config.limit_policy),
+        config: config.clone(),
+        next_client: 0,
+        pending: Vec::new(),
+        clients: Vec::new(),
+        ready: VecDeque::new(),
+        rejected: 0,
+    })
+}
+
+/// A connection to a receiver. Dropping it hangs up.
+pub struct PacketSink {
+    frames: FramedWrite<TcpStream, FrameEncoder>,
+}
+
+impl Sink for PacketSink {
+    type SinkItem = Packet;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, packet: Packet) -> StartSend<Packet, io::Error> {
+        match try!(self.frames.start_send(Frame::Raw(packet))) {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(Frame::Raw(packet)) => Ok(AsyncSink::NotReady(packet)),
+            AsyncSink::NotReady(_) => unreachable!(),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.frames.poll_complete()
+    }
+}
+
+// a client that hasn't finished its handshake yet
+struct Pending {
+    id: u64,
+    pass: Pass,
+    hung_up: Arc<AtomicBool>,
+    protected: Protected,
+}
+
+struct Client {
+    id: u64,
+    _pass: Pass,
+    hung_up: Arc<AtomicBool>,
+    frames: FramedRead<TcpStream, FrameDecoder>,
+    decoder: Option<codec::Decoder>,
+}
+
+/// The packets arriving on a channel, from every client connected to it. Clients are let in by
+/// the config's allowlist and client limit, like the receiver plugin's are.
+pub struct PacketStream {
+    local_addr: SocketAddr,
+    incoming: Incoming,
+    gate: Gate,
+    config: Config,
+    next_client: u64,
+    pending: Vec<Pending>,
+    clients: Vec<Client>,
+    ready: VecDeque<(u64, Packet)>,
+    rejected: usize,
+}
+
+impl PacketStream {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// How many clients have finished their handshake and are connected.
+    pub fn clients(&self) -> usize {
+        self.gate.len()
+    }
+
+    /// How many clients have been hung up on for failing the handshake or sending something that
+    /// couldn't be read.
+    pub fn rejected(&self) -> usize {
+        self.rejected
+    }
+
+    fn accept(&mut self, socket: TcpStream) {
+        let peer = match socket.peer_addr() {
+            Ok(peer) => peer,
+            // already gone
+            Err(_) => return,
+        };
+        let id = self.next_client;
+        self.next_client += 1;
+        // a client pushed out to make room is dropped the next time the stream is polled
+        let hung_up = Arc::new(AtomicBool::new(false));
+        let flag = hung_up.clone();
+        let hangup = Box::new(move || flag.store(true, Ordering::Relaxed));
+        let pass = match self.gate.admit_with(id, &peer, hangup) {
+            Ok(pass) => pass,
+            Err(e) => {
+                log!(Warn, "rejected client {}: {}: {}", id, peer, e);
+                return;
+            }
+        };
+        let _ = socket.set_nodelay(true);
+        self.pending.push(Pending {
+            id: id,
+            pass: pass,
+            hung_up: hung_up,
+            protected: protect(socket, &self.config, false),
+        });
+    }
+
+    fn poll_pending(&mut self) {
+        let mut i = 0;
+        while i < self.pending.len() {
+            if self.pending[i].hung_up.load(Ordering::Relaxed) {
+                self.pending.swap_remove(i);
+                continue;
+            }
+            match self.pending[i].protected.poll() {
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready((socket, protection))) => {
+                    let mut pending = self.pending.swap_remove(i);
+                    if let Err(e) = pending.pass.enter() {
+                        log!(Warn, "rejected client {}: {}", pending.id, e);
+                        continue;
+                    }
+                    log!(Info, "stream accepts client {}", pending.id);
+                    self.clients.push(Client {
+                        id: pending.id,
+                        _pass: pending.pass,
+                        hung_up: pending.hung_up,
+                        frames: FramedRead::new(socket, FrameDecoder::new(protection)),
+                        decoder: None,
+                    });
+                }
+                Err(e) => {
+                    let pending = self.pending.swap_remove(i);
+                    self.rejected += 1;
+                    log!(Warn, "handshake with client {} failed: {}", pending.id, e);
+                }
+            }
+        }
+    }
+
+    // Reads everything a client has sent so far, or until `MAX_READY` packets are waiting.
+    // Returns whether it's still there.
+    fn poll_client(client: &mut Client,
+                   ready: &mut VecDeque<(u64, Packet)>)
+                   -> Result<bool, String> {
+        loop {
+            if client.hung_up.load(Ordering::Relaxed) {
+                return Ok(false);
+            }
+            if ready.len() >= MAX_READY {
+                return Ok(true);
+            }
+            match client.frames.poll() {
+                Ok(Async::Ready(Some(mut frame))) => {
+                    if let Some(packet) = receive::decode_frame(&mut frame, &mut client.decoder) {
+                        ready.push_back((client.id, packet));
+                    }
+                }
+                Ok(Async::Ready(None)) => return Ok(false),
+                Ok(Async::NotReady) => return Ok(true),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+}
+
+impl Stream for PacketStream {
+    type Item = (u64, Packet);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<(u64, Packet)>, io::Error> {
+        // what has been read already goes first, so a client sending faster than the stream is
+        // taken from can't pile packets up
+        if let Some(packet) = self.ready.pop_front() {
+            return Ok(Async::Ready(Some(packet)));
+        }
+        // everything is polled until it isn't ready, so the runtime wakes us for whatever's next.
+        // Only once enough is waiting are the rest left, and then there's a packet to return.
+        loop {
+            match try!(self.incoming.poll()) {
+                Async::Ready(Some(socket)) => self.accept(socket),
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => break,
+            }
+        }
+        self.poll_pending();
+        let mut i = 0;
+        while i < self.clients.len() {
+            match PacketStream::poll_client(&mut self.clients[i], &mut self.ready) {
+                Ok(true) if self.ready.len() >= MAX_READY => {
+                    // the ones after this go first next time, so no client is always left out
+                    self.clients.rotate_left(i + 1);
+                    break;
+                }
+                Ok(true) => i += 1,
+                Ok(false) => {
+                    log!(Info, "stream client {} disconnected", self.clients[i].id);
+                    self.clients.swap_remove(i);
+                }
+                Err(e) => {
+                    log!(Warn, "rejected frame from client {}: {}", self.clients[i].id, e);
+                    self.rejected += 1;
+                    self.clients.swap_remove(i);
+                }
+            }
+        }
+        match self.ready.pop_front() {
+            Some(packet) => Ok(Async::Ready(Some(packet))),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+// Sets up protection on a fresh connection without blocking the runtime, the way
+// `Protection::initiate` and `Protection::respond` do on the plugins' threads.
+fn protect(socket: TcpStream, config: &Config, initiator: bool) -> Protected {
+    let key = match config.key {
+        None => return Box::new(future::ok((socket, Protection::Plain))),
+        Some(ref key) => key.clone(),
+    };
+    let protected: Protected = match config.security {
+        Security::Sign => sign(socket, key, initiator),
+        Security::Encrypt => encrypt(socket, key, initiator),
+    };
+    let timeout = Delay::new(Instant::now() + Duration::from_millis(HANDSHAKE_TIMEOUT_MS))
+                      .map_err(|e| io::Error::new(ErrorKind::Other, e));
+    Box::new(protected.select2(timeout).then(|result| {
+        match result {
+            Ok(Either::A((protected, _))) => Ok(protected),
+            Ok(Either::B(_)) => Err(io::Error::new(ErrorKind::TimedOut, "handshake timed out")),
+            Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+        }
+    }))
+}
+
+// The receiver sends a challenge, which goes into the key both ends sign with.
+fn sign(socket: TcpStream, key: Vec<u8>, initiator: bool) -> Protected {
+    let exchange: Exchange = if initiator {
+        Box::new(read_exact(socket, vec![0; CHALLENGE_SIZE]))
+    } else {
+        match auth::challenge() {
+            Ok(challenge) => Box::new(write_all(socket, challenge)),
+            Err(e) => return Box::new(future::err(e)),
+        }
+    };
+    Box::new(exchange.and_then(move |(socket, challenge)| {
+        Authenticator::new(&key, &challenge).map(|auth| (socket, Protection::Signed(auth)))
+    }))
+}
+
+fn encrypt(socket: TcpStream, key: Vec<u8>, initiator: bool) -> Protected {
+    let handshake = match Handshake::new() {
+        Ok(handshake) => handshake,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let hello = handshake.hello();
+    let exchange: Exchange = if initiator {
+        Box::new(write_all(socket, hello)
+                     .and_then(|(socket, _)| read_exact(socket, vec![0; HELLO_SIZE])))
+    } else {
+        Box::new(read_exact(socket, vec![0; HELLO_SIZE]).and_then(|(socket, peer)| {
+            write_all(socket, hello).map(|(socket, _)| (socket, peer))
+        }))
+    };
+    Box::new(exchange.and_then(move |(socket, peer)| {
+        let cipher = if initiator {
+            handshake.finish_initiator(&key, &peer)
+        } else {
+            handshake.finish_responder(&key, &peer)
+        };
+        cipher.map(|cipher| (socket, Protection::Encrypted(cipher)))
+    }))
+}
+
+struct FrameEncoder {
+    writer: FrameWriter,
+}
+
+impl FrameEncoder {
+    fn new(protection: Protection) -> FrameEncoder {
+        FrameEncoder { writer: FrameWriter::new(protection) }
+    }
+}
+
+impl Encoder for FrameEncoder {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> io::Result<()> {
+        dst.extend_from_slice(&self.writer.write(&frame));
+        Ok(())
+    }
+}
+
+struct FrameDecoder {
+    reader: FrameReader,
+}
+
+impl FrameDecoder {
+    fn new(protection: Protection) -> FrameDecoder {
+        FrameDecoder { reader: FrameReader::new(protection) }
+    }
+}
+
+impl Decoder for FrameDecoder {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Frame>> {
+        // the reader keeps its own buffer, so take everything
+        let len = src.len();
+        self.reader.push(&src.split_to(len));
+        self.reader.next_frame().map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+use tokio::runtime::current_thread::Runtime;
+
+#[cfg(test)]
+fn test_config(security: Security, name: &str) -> Config {
+    use std::env;
+    use super::tap;
+
+    let mut config = Config::default();
+    config.base_port = super::test::free_base_port(4);
+    config.key = Some(b"stream tests".to_vec());
+    config.security = security;
+    // keep whatever the tests start out of the user's registry
+    let dir = env::temp_dir().join(format!("feedback-test-stream-{}-{}", name, tap::now()));
+    config.registry_dir = Some(dir.to_string_lossy().into_owned());
+    config
+}
+
+// Runs `future` on `runtime`, failing if it takes more than a few seconds.
+#[cfg(test)]
+fn test_run<F: Future>(runtime: &mut Runtime, future: F) -> F::Item
+    where F::Error: ::std::fmt::Debug
+{
+    let timeout = Delay::new(Instant::now() + Duration::from_secs(5));
+    match runtime.block_on(future.select2(timeout)) {
+        Ok(Either::A((item, _))) => item,
+        Ok(Either::B(_)) => panic!("timed out"),
+        Err(Either::A((e, _))) => panic!("{:?}", e),
+        Err(Either::B((e, _))) => panic!("{:?}", e),
+    }
+}
+
+#[test]
+fn test_stream_sink_to_stream() {
+    use futures::stream;
+
+    let mut runtime = Runtime::new().unwrap();
+    let config = test_config(Security::Encrypt, "sink");
+    let packets = listen(&config, 1, 0).unwrap();
+    let blocks: Vec<Packet> = (0..3)
+                                  .map(|i| Packet::new(&[i as f32; 64], &[-(i as f32); 64], i * 64))
+                                  .collect();
+    let send = connect(&config, 1, 0)
+                   .and_then(move |sink| sink.send_all(stream::iter_ok::<_, io::Error>(blocks)));
+    let (_, (received, packets)) = test_run(&mut runtime,
+                                            send.join(packets.into_future()
+                                                             .map_err(|(e, _)| e)));
+    let (client, packet) = received.unwrap();
+    assert_eq!((client, packet.get_timestamp()), (0, 0));
+    let rest = test_run(&mut runtime, packets.take(2).collect());
+    let levels: Vec<f32> = rest.iter().map(|&(_, ref packet)| packet.get_ldata()[0]).collect();
+    assert_eq!(levels, vec![1.0, 2.0]);
+}
+
+#[test]
+fn test_stream_from_plugin() {
+    use std::fs;
+    use super::registry::Registry;
+    use super::transmit::FeedbackSender;
+
+    let mut runtime = Runtime::new().unwrap();
+    let config = test_config(Security::Sign, "plugin");
+    let mut packets = listen(&config, 2, 0).unwrap();
+    let mut sender = FeedbackSender::new(config.clone(), 44100);
+    sender.set_channel(2, 0);
+    sender.start();
+    sender.push(&[0.5; 100], &[0.25; 100]);
+    // the stream is kept, so the sender isn't hung up on and doesn't leave the registry
+    let received = test_run(&mut runtime, packets.by_ref().take(1).collect());
+    assert_eq!(received[0].1.get_ldata(), &[0.5; 100][..]);
+    assert_eq!(received[0].1.get_rdata(), &[0.25; 100][..]);
+    // the sender published itself in the test's registry
+    assert_eq!(Registry::open(&config).unwrap().entries().len(), 1);
+    drop(sender);
+    let _ = fs::remove_dir_all(config.registry_dir.unwrap());
+}
+
+#[test]
+fn test_stream_wrong_key() {
+    let mut runtime = Runtime::new().unwrap();
+    let config = test_config(Security::Encrypt, "key");
+    let mut packets = listen(&config, 3, 0).unwrap();
+    let mut wrong = config.clone();
+    wrong.key = Some(b"something else".to_vec());
+    let send = connect(&wrong, 3, 0)
+                   .and_then(|sink| sink.send(Packet::new(&[1.0; 64], &[1.0; 64], 0)));
+    // the frame doesn't open, and the client is hung up on
+    let rejected = future::poll_fn(|| {
+        assert!(try!(packets.poll()).is_not_ready());
+        Ok(if packets.rejected() > 0 {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        })
+    });
+    test_run(&mut runtime, send.join(rejected));
+}
+
+#[test]
+fn test_stream_reads_ahead_only_so_far() {
+    use futures::stream;
+
+    let mut runtime = Runtime::new().unwrap();
+    let mut config = test_config(Security::Sign, "ahead");
+    // without a key there's no handshake, so everything can be sent before the stream is polled
+    config.key = None;
+    let packets = listen(&config, 1, 0).unwrap();
+    let blocks: Vec<Packet> = (0..MAX_READY as u64 * 3)
+                                  .map(|i| Packet::new(&[0.5; 64], &[0.5; 64], i * 64))
+                                  .collect();
+    let blocks = stream::iter_ok::<_, io::Error>(blocks);
+    let sink = test_run(&mut runtime,
+                        connect(&config, 1, 0).and_then(|sink| sink.send_all(blocks)));
+    let (first, packets) = test_run(&mut runtime, packets.into_future().map_err(|(e, _)| e));
+    assert_eq!(first.unwrap().1.get_timestamp(), 0);
+    assert_eq!(packets.ready.len(), MAX_READY - 1);
+    let rest = test_run(&mut runtime, packets.take(MAX_READY as u64 * 3 - 1).collect());
+    for (i, &(_, ref packet)) in rest.iter().enumerate() {
+        assert_eq!(packet.get_timestamp(), (i as u64 + 1) * 64);
+    }
+    drop(sink);
+}
@@ -0,0 +1,309 @@
+// Lets tools listen in on a receiver. Each receiver takes tap connections on a loopback port it
+// publishes in the registry, and copies what happens on its channel to every tap: clients coming
+// and going, and each block of audio as it arrives. Taps never hold up the receiver. One that
+// can't keep up misses events rather than slowing anything down.
+
+use std::io::{self, ErrorKind, Read};
+use std::net;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, decode};
+use mio::EventSet;
+use mio::tcp::{TcpListener, TcpStream};
+
+use super::access::Gate;
+use super::config::Config;
+use super::net::write_some;
+use super::packet::{FrameReader, FrameWriter, Negotiation, Packet, Protection};
+use super::reactor::{Context, Source, Waker};
+use super::secure::HANDSHAKE_TIMEOUT_MS;
+
+// events queued for a tap before it starts missing them, about 1.5 seconds of audio at 44.1 kHz
+const TAP_QUEUE: usize = 64;
+
+#[derive(RustcEncodable, RustcDecodable, Clone)]
+pub enum Event {
+    Connect {
+        client: u64,
+        peer: String,
+    },
+    Disconnect {
+        client: u64,
+    },
+    /// A decoded block of audio, and when it arrived in microseconds since the Unix epoch.
+    Packet {
+        client: u64,
+        arrival: u64,
+        packet: Packet,
+    },
+}
+
+/// Microseconds since the Unix epoch.
+pub fn now() -> u64 {
+    let since = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    since.as_secs() * 1000000 + since.subsec_nanos() as u64 / 1000
+}
+
+// one tap's queue, and how to get the reactor to empty it
+struct Tap {
+    tx: SyncSender<Vec<u8>>,
+    waker: Waker,
+}
+
+/// The taps on one receiver. Clones share the same set of taps.
+#[derive(Clone)]
+pub struct Taps {
+    taps: Arc<Mutex<Vec<Tap>>>,
+}
+
+impl Taps {
+    pub fn new() -> Taps {
+        Taps { taps: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    /// Starts feeding events to a newly accepted tap. It first hears about the clients that are
+    /// already connected.
+    fn add(&self, ctx: &mut Context, socket: TcpStream, gate: &Gate, config: &Config) {
+        let negotiation = match Negotiation::respond(config) {
+            Ok(negotiation) => negotiation,
+            Err(e) => {
+                log!(Warn, "handshake with tap failed: {}", e);
+                return;
+            }
+        };
+        let (tx, rx) = sync_channel(TAP_QUEUE);
+        for (client, peer) in gate.peers() {
+            let peer = peer.map(|peer| peer.to_string()).unwrap_or("unknown".to_string());
+            let _ = tx.try_send(encode(&Event::Connect {
+                                           client: client,
+                                           peer: peer,
+                                       },
+                                       SizeLimit::Infinite)
+                                    .unwrap());
+        }
+        let token = ctx.add(Box::new(TapConnection {
+            socket: socket,
+            negotiation: negotiation,
+            writer: None,
+            events: rx,
+            pending: Vec::new(),
+            written: 0,
+        }));
+        self.taps.lock().unwrap().push(Tap {
+            tx: tx,
+            waker: ctx.waker(token),
+        });
+    }
+
+    /// Whether no taps are attached, so events needn't be made at all.
+    pub fn is_empty(&self) -> bool {
+        self.taps.lock().unwrap().is_empty()
+    }
+
+    pub fn send(&self, event: &Event) {
+        let mut taps = self.taps.lock().unwrap();
+        if taps.is_empty() {
+            return;
+        }
+        let payload = encode(event, SizeLimit::Infinite).unwrap();
+        // taps that have gone away are forgotten, full ones just miss this event
+        taps.retain(|tap| {
+            match tap.tx.try_send(payload.clone()) {
+                Ok(()) => {
+                    tap.waker.wake();
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+                Err(TrySendError::Full(_)) => true,
+            }
+        });
+    }
+
+    /// Announces a client, which is reported gone when the returned handle is dropped.
+    pub fn client(&self, client: u64, peer: &str) -> ClientTap {
+        self.send(&Event::Connect {
+            client: client,
+            peer: peer.to_string(),
+        });
+        ClientTap {
+            taps: self.clone(),
+            client: client,
+        }
+    }
+}
+
+/// Reports on one client of a receiver for as long as it is connected.
+pub struct ClientTap {
+    taps: Taps,
+    client: u64,
+}
+
+impl ClientTap {
+    pub fn packet(&self, packet: &Packet) {
+        // every packet a receiver takes in comes through here, and usually nothing is tapping it
+        if self.taps.is_empty() {
+            return;
+        }
+        self.taps.send(&Event::Packet {
+            client: self.client,
+            arrival: now(),
+            packet: packet.clone(),
+        });
+    }
+}
+
+impl Drop for ClientTap {
+    fn drop(&mut self) {
+        self.taps.send(&Event::Disconnect { client: self.client });
+    }
+}
+
+/// Takes tap connections for a receiver, run by the reactor.
+pub struct TapListener {
+    socket: TcpListener,
+    taps: Taps,
+    gate: Gate,
+    config: Config,
+}
+
+impl TapListener {
+    pub fn new(socket: TcpListener, taps: &Taps, gate: &Gate, config: &Config) -> TapListener {
+        TapListener {
+            socket: socket,
+            taps: taps.clone(),
+            gate: gate.clone(),
+            config: config.clone(),
+        }
+    }
+}
+
+impl Source for TapListener {
+    fn start(&mut self, ctx: &mut Context) -> bool {
+        if let Err(e) = ctx.register(&self.socket) {
+            log!(Warn, "can't listen for taps: {}", e);
+            return false;
+        }
+        self.ready(ctx, EventSet::none())
+    }
+
+    fn ready(&mut self, ctx: &mut Context, _: EventSet) -> bool {
+        loop {
+            match self.socket.accept() {
+                Ok(Some(socket)) => self.taps.add(ctx, socket, &self.gate, &self.config),
+                Ok(None) => return true,
+                Err(e) => {
+                    log!(Warn, "tap accept errored: {}", e);
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+// one tap, woken by `Taps::send` whenever there are events for it
+struct TapConnection {
+    socket: TcpStream,
+    negotiation: Negotiation,
+    writer: Option<FrameWriter>,
+    events: Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl Source for TapConnection {
+    fn start(&mut self, ctx: &mut Context) -> bool {
+        if let Err(e) = ctx.register(&self.socket) {
+            log!(Warn, "can't take tap: {}", e);
+            return false;
+        }
+        ctx.timeout_ms(HANDSHAKE_TIMEOUT_MS);
+        self.ready(ctx, EventSet::none())
+    }
+
+    fn ready(&mut self, _: &mut Context, _: EventSet) -> bool {
+        if self.writer.is_none() {
+            match self.negotiation.advance(&mut self.socket) {
+                Ok(Some(protection)) => {
+                    self.writer = Some(FrameWriter::new(protection));
+                    log!(Info, "tap connected");
+                }
+                Ok(None) => return true,
+                Err(e) => {
+                    log!(Warn, "handshake with tap failed: {}", e);
+                    return false;
+                }
+            }
+        }
+        loop {
+            match write_some(&mut self.socket, &self.pending, &mut self.written) {
+                Ok(true) => {}
+                Ok(false) => return true,
+                Err(e) => {
+                    log!(Info, "tap disconnected: {}", e);
+                    return false;
+                }
+            }
+            match self.events.try_recv() {
+                Ok(payload) => {
+                    self.pending = self.writer.as_mut().unwrap().write_payload(payload);
+                    self.written = 0;
+                }
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    fn timeout(&mut self, _: &mut Context) -> bool {
+        if self.writer.is_none() {
+            log!(Warn, "handshake with tap timed out");
+            return false;
+        }
+        true
+    }
+}
+
+/// The listening end of a tap.
+pub struct TapReader {
+    socket: net::TcpStream,
+    reader: FrameReader,
+}
+
+impl TapReader {
+    pub fn connect(addr: &SocketAddr, config: &Config) -> io::Result<TapReader> {
+        let mut socket = try!(net::TcpStream::connect(addr));
+        let protection = try!(Protection::initiate(&mut socket, config));
+        Ok(TapReader {
+            socket: socket,
+            reader: FrameReader::new(protection),
+        })
+    }
+
+    /// Waits up to `timeout` for the next event. Fails once the receiver goes away.
+    pub fn next_event(&mut self, timeout: Duration) -> io::Result<Option<Event>> {
+        try!(self.socket.set_read_timeout(Some(timeout)));
+        let mut buf = [0; 4096];
+        loop {
+            match self.reader.next_payload() {
+                Ok(Some(payload)) => {
+                    return decode(&payload)
+                               .map(Some)
+                               .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))
+                }
+                Ok(None) => {}
+                Err(e) => return Err(io::Error::new(ErrorKind::InvalidData, e)),
+            }
+            match self.socket.read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "receiver closed")),
+                Ok(n) => self.reader.push(&buf[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                              e.kind() == ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
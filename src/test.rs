@@ -1,206 +1,424 @@
-// TODO
-// should directly test the plugins, because why not?
-// maybe ladspa should have some testing facilities built in.
+// Runs a transmitter into a receiver and checks that what goes in comes out. Most tests use the
+// simulated network, which needs no sockets or waiting. One goes through the host and real
+// sockets, the way a DAW would run the plugins.
 
-use super::get_ladspa_descriptor;
-use ladspa::{Data, Port, PortConnection, PortData};
-use std::cell::RefCell;
-use std::thread;
-use std::time::Duration;
+use super::{CHANNELS, SESSIONS};
+use super::config::Config;
+#[cfg(feature = "ladspa")]
+use super::host::{Instance, Library};
+use super::packet::BUFFER_SIZE;
+use super::reactor::{self, Context, Source};
+use super::receive::FeedbackReceiver;
+#[cfg(feature = "ladspa")]
+use super::registry::Registry;
+use super::sim::{Conditions, SimNetwork};
+#[cfg(feature = "ladspa")]
+use super::tap;
+use super::transmit::FeedbackSender;
+use mio::EventSet;
+#[cfg(feature = "ladspa")]
+use std::env;
+use std::net::TcpListener;
+use std::sync::{mpsc, Arc};
+#[cfg(feature = "ladspa")]
+use std::sync::{Once, ONCE_INIT};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
 
 const SAMPLE_RATE: u64 = 44100;
 
-// TODO this port connection stuff is a mess because the Plugin trait in ladspa is too specific.
-// it would be a breaking change to fix it, but should be done at some point because this is nuts.
-
-#[derive(Debug)]
-enum OwnedPortData {
-    AudioInput(Vec<Data>),
-    AudioOutput(Vec<Data>),
-    ControlInput(Data),
-    ControlOutput(Data),
-}
-
-impl PartialEq for OwnedPortData {
-    fn eq(&self, other: &Self) -> bool {
-        use self::OwnedPortData::*;
-
-        match *self {
-            AudioInput(ref a) | AudioOutput(ref a) => {
-                match *other {
-                    AudioInput(ref b) | AudioOutput(ref b) => a == b,
-                    _ => false,
-                }
-            }
-            ControlInput(ref a) | ControlOutput(ref a) => {
-                match *other {
-                    ControlInput(ref b) | ControlOutput(ref b) => a == b,
-                    _ => false,
-                }
-            }
-        }
-    }
-}
+#[cfg(feature = "ladspa")]
+static PLUGINS: Once = ONCE_INIT;
 
-struct OwnedPortConnection {
-    port: Port,
-    data: OwnedPortData,
+#[test]
+fn test_working_basic() {
+    test_sample_count(BUFFER_SIZE);
 }
 
-fn make_owned_port_connections(ports: &[Port], size: usize) -> Vec<OwnedPortConnection> {
-    use ladspa::PortDescriptor::*;
+#[test]
+fn test_working_multi_packet() {
+    test_sample_count(BUFFER_SIZE * 32);
+}
 
-    let mut out = Vec::new();
-    for port in ports {
-        let data = match port.desc {
-            AudioInput => OwnedPortData::AudioInput(vec![0.0; size]),
-            AudioOutput => OwnedPortData::AudioOutput(vec![0.0; size]),
-            ControlInput => OwnedPortData::ControlInput(0.0),
-            ControlOutput => OwnedPortData::ControlOutput(0.0),
-            Invalid => panic!(),
-        };
-        out.push(OwnedPortConnection {
-            port: port.clone(),
-            data: data,
-        });
-    }
-    out
-}
-
-trait Tagged {
-    fn set_tags(&mut self, port_tag: f32, input_tag: f32, output_tag: f32);
-}
-
-impl Tagged for Vec<OwnedPortConnection> {
-    fn set_tags(&mut self, port_tag: f32, input_tag: f32, output_tag: f32) {
-        use self::OwnedPortData::*;
-
-        for port in self {
-            match port.data {
-                AudioInput(ref mut v) => {
-                    for x in v.iter_mut() {
-                        *x = input_tag;
-                    }
-                }
-                AudioOutput(ref mut v) => {
-                    for x in v.iter_mut() {
-                        *x = output_tag;
-                    }
-                }
-                ControlInput(ref mut x) => {
-                    match port.port.name {
-                        "Channel" => *x = port_tag,
-                        _ => *x = 1.0,
-                    }
-                }
-                ControlOutput(ref mut x) => {
-                    *x = 0.0;
-                }
-            }
-        }
-    }
+#[test]
+fn test_working_odd_sample_number_small() {
+    test_sample_count(BUFFER_SIZE * 2 - 15);
 }
 
-fn make_port_connections<'a>(owned: &'a mut [OwnedPortConnection]) -> Vec<PortConnection<'a>> {
-    let mut out = Vec::new();
-    for port in owned {
-        let data = match port.data {
-            OwnedPortData::AudioInput(ref data) => PortData::AudioInput(data),
-            OwnedPortData::AudioOutput(ref mut data) => PortData::AudioOutput(RefCell::new(data)),
-            OwnedPortData::ControlInput(ref data) => PortData::ControlInput(data),
-            OwnedPortData::ControlOutput(ref mut data) => {
-                PortData::ControlOutput(RefCell::new(data))
-            }
-        };
+#[test]
+fn test_working_odd_sample_number_large() {
+    test_sample_count(BUFFER_SIZE + 15);
+}
 
-        out.push(PortConnection {
-            port: port.port,
-            data: data,
-        });
-    }
-    out
+#[test]
+fn test_working_host_block() {
+    test_block_size(100, 0);
 }
 
-fn borrow_port_connections<'a>(ports: &'a [PortConnection<'a>]) -> Vec<&'a PortConnection<'a>> {
-    ports.iter().map(|x| x).collect()
+#[test]
+fn test_working_small_block() {
+    test_block_size(256, 64);
 }
 
 #[test]
-fn test_working_basic() {
-    let sample_count = super::packet::BUFFER_SIZE;
-    test_sample_count(sample_count, 0);
+#[cfg(feature = "ladspa")]
+fn test_working_tcp() {
+    assert!(link_tcp(BUFFER_SIZE, 4, BUFFER_SIZE, (0, 0)));
 }
 
 #[test]
-fn test_working_multi_packet() {
-    let sample_count = super::packet::BUFFER_SIZE * 32;
-    test_sample_count(sample_count, 1);
+#[cfg(feature = "ladspa")]
+fn test_sessions_isolated() {
+    // every session and channel has a port of its own
+    let config = Config::default();
+    let mut ports: Vec<u16> = (0..SESSIONS)
+                                  .flat_map(|session| (0..CHANNELS).map(move |c| (session, c)))
+                                  .map(|(session, channel)| config.port(session, channel))
+                                  .collect();
+    ports.sort();
+    ports.dedup();
+    assert_eq!(ports.len(), SESSIONS as usize * CHANNELS as usize);
+
+    // so over real sockets, not even part of a block crosses between sessions
+    assert!(heard_tcp(BUFFER_SIZE, 5, BUFFER_SIZE, (1, 2)).iter().all(|&x| x == 0.0));
+    assert!(link_tcp(BUFFER_SIZE, 5, BUFFER_SIZE, (3, 3)));
 }
 
 #[test]
-fn test_working_odd_sample_number_small() {
-    let sample_count = super::packet::BUFFER_SIZE * 2 - 15;
-    test_sample_count(sample_count, 2);
+fn test_interleaved() {
+    let network = SimNetwork::new(Conditions::default(), 0);
+    let mut tx = FeedbackSender::with_transport(Config::default(),
+                                                SAMPLE_RATE,
+                                                Arc::new(network.clone()));
+    let mut rx = FeedbackReceiver::with_transport(Config::default(),
+                                                  SAMPLE_RATE,
+                                                  Arc::new(network.clone()));
+    // nothing goes anywhere until started
+    tx.push_interleaved(&[1.0; 200]);
+    rx.start();
+    tx.start();
+    assert!(!tx.connected());
+    network.advance(0);
+    assert!(tx.connected());
+    let mut output = [0.0; 200];
+    // waiting for the first audio isn't an underrun
+    assert!(!rx.pull_interleaved(&mut output));
+    assert_eq!(rx.underruns(), 0);
+
+    let input: Vec<f32> = (0..200).map(|i| i as f32).collect();
+    tx.push_interleaved(&input);
+    network.advance(0);
+    let mut left = [0.0; 100];
+    let mut right = [0.0; 100];
+    assert!(rx.pull(&mut left, &mut right));
+    assert_eq!(left[..3], [0.0, 2.0, 4.0]);
+    assert_eq!(right[..3], [1.0, 3.0, 5.0]);
+
+    tx.push_interleaved(&input);
+    network.advance(0);
+    assert!(rx.pull_interleaved(&mut output));
+    assert_eq!(output[..], input[..]);
+    assert!(!rx.pull_interleaved(&mut output));
+    assert_eq!(rx.underruns(), 1);
 }
 
 #[test]
-fn test_working_odd_sample_number_large() {
-    let sample_count = super::packet::BUFFER_SIZE + 15;
-    test_sample_count(sample_count, 3);
+fn test_latency() {
+    let network = SimNetwork::new(Conditions::default(), 0);
+    let mut tx = FeedbackSender::with_transport(Config::default(),
+                                                SAMPLE_RATE,
+                                                Arc::new(network.clone()));
+    let mut rx = FeedbackReceiver::with_transport(Config::default(),
+                                                  SAMPLE_RATE,
+                                                  Arc::new(network.clone()));
+    rx.start();
+    tx.start();
+    tx.set_block_size(BUFFER_SIZE);
+    let input = [0.5; 256];
+    let mut left = [0.0; 256];
+    let mut right = [0.0; 256];
+    // each block sent waits out the host blocks it took to fill
+    for _ in 0..16 {
+        tx.push(&input, &input);
+        network.advance(0);
+        rx.pull(&mut left, &mut right);
+    }
+    // what's left of a block once a host block of it has been played
+    assert_eq!(rx.latency(), BUFFER_SIZE - 256);
+    assert_eq!(left[..], input[..]);
+    // but it hasn't settled yet, so the transmitter isn't told
+    network.advance(0);
+    assert_eq!(tx.receiver_latency(), 0);
+    assert_eq!(rx.settled_latency(), None);
+
+    // once it has played for a second the host and transmitter are told, and that holds while
+    // it runs
+    for _ in 0..SAMPLE_RATE / 256 {
+        tx.push(&input, &input);
+        network.advance(0);
+        rx.pull(&mut left, &mut right);
+    }
+    assert_eq!(rx.settled_latency(), Some(BUFFER_SIZE - 256));
+    network.advance(0);
+    assert_eq!(tx.receiver_latency(), BUFFER_SIZE - 256);
+    // a stall leaves more waiting from then on
+    for _ in 0..8 {
+        tx.push(&input, &input);
+        network.advance(0);
+    }
+    rx.pull(&mut left, &mut right);
+    assert!(rx.latency() > BUFFER_SIZE * 2);
+    assert_eq!(rx.settled_latency(), Some(BUFFER_SIZE - 256));
+    network.advance(0);
+    assert_eq!(tx.receiver_latency(), BUFFER_SIZE - 256);
 }
 
-fn test_sample_count(sample_count: usize, port: u8) {
-    let tx_desc = get_ladspa_descriptor(0).unwrap();
-    let rx_desc = get_ladspa_descriptor(1).unwrap();
-    let mut tx = (tx_desc.new)(&tx_desc, SAMPLE_RATE);
-    let mut rx = (rx_desc.new)(&rx_desc, SAMPLE_RATE);
+#[test]
+#[cfg(feature = "ladspa")]
+fn test_dry_delay() {
+    plugin_config();
+    let library = Library::linked();
+    let mut tx = library.plugin("feedback_tx").unwrap().instantiate(SAMPLE_RATE).unwrap();
+    tx.reserve(8);
+    set_up(&mut tx, 200, 0.0, &[("Dry Delay", 3.0)]);
+    for port in tx.audio_inputs() {
+        for (i, x) in tx.buffer_mut(port).iter_mut().enumerate() {
+            *x = i as f32 + 1.0;
+        }
+    }
+    tx.run(8);
+    let outputs = tx.audio_outputs();
+    assert_eq!(tx.buffer(outputs[0])[..8], [0.0, 0.0, 0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+    assert_eq!(tx.control("latency"), Some(3.0));
 
+    // the receiver only reports the delay it puts on its dry outputs, not how late the wet
+    // signal is
+    let mut rx = library.plugin("feedback_rx").unwrap().instantiate(SAMPLE_RATE).unwrap();
+    rx.reserve(8);
+    set_up(&mut rx, 200, 1.0, &[("Dry Delay", 2.0), ("Delay Dry By Latency", 1.0)]);
     rx.activate();
-    tx.activate();
+    rx.run(8);
+    let outputs = rx.audio_outputs();
+    assert_eq!(rx.buffer(outputs[0])[..4], [0.0, 0.0, 1.0, 1.0]);
+    assert_eq!(rx.control("latency"), Some(2.0));
+    rx.set_control("Delay Dry By Latency", 0.0).unwrap();
+    rx.set_control("Dry Delay", 0.0).unwrap();
+    rx.run(8);
+    assert_eq!(rx.control("latency"), Some(0.0));
+    rx.deactivate();
+}
 
-    let mut tx_owned = make_owned_port_connections(&tx_desc.ports, sample_count);
-    tx_owned.set_tags(port as f32, 1.0, 0.0);
-    let mut rx_owned = make_owned_port_connections(&rx_desc.ports, sample_count);
-    rx_owned.set_tags(port as f32, 0.0, 0.0);
+#[test]
+fn test_many_over_tcp() {
+    let mut config = Config::default();
+    config.base_port = free_base_port(16);
+    // every plugin below holds on to this same one
+    let held = reactor::hold();
+    let reactor = held.reactor();
+    let added = reactor.added();
+    let mut pairs: Vec<(FeedbackSender, FeedbackReceiver)> = (0..16)
+        .map(|channel| {
+            let mut tx = FeedbackSender::new(config.clone(), SAMPLE_RATE);
+            let mut rx = FeedbackReceiver::new(config.clone(), SAMPLE_RATE);
+            tx.set_channel(channel, 0);
+            rx.set_channel(channel, 0);
+            rx.start();
+            (tx, rx)
+        })
+        .collect();
+    for &mut (ref mut tx, _) in &mut pairs {
+        tx.start();
+    }
+    // every channel's sockets are run from the one shared thread
+    assert!(reactor.added() >= added + 32);
+    let (ran_on, rx) = mpsc::channel();
+    reactor.add(Box::new(ThreadProbe(ran_on)));
+    let ran_on = rx.recv().unwrap();
+    assert_eq!(ran_on, reactor.thread_id());
+    assert!(ran_on != thread::current().id());
+    assert!(wait_until(|| pairs.iter().all(|&(ref tx, _)| tx.connected())));
 
-    // run once to handle channel change
-    {
-        let tx_ports = make_port_connections(&mut tx_owned);
-        let rx_ports = make_port_connections(&mut rx_owned);
-        let tx_ports = borrow_port_connections(&tx_ports);
-        let rx_ports = borrow_port_connections(&rx_ports);
+    let input: Vec<f32> = (0..256).map(|i| i as f32).collect();
+    for &mut (ref mut tx, _) in &mut pairs {
+        tx.push(&input, &input);
+    }
+    for &mut (_, ref mut rx) in &mut pairs {
+        // a transmitter can be done shaking hands before its receiver has counted it
+        assert!(wait_until(|| rx.clients() == 1));
+        let mut left = [0.0; 256];
+        let mut right = [0.0; 256];
+        assert!(wait_until(|| rx.pull(&mut left, &mut right)));
+        assert_eq!(left[..], input[..]);
+    }
+}
+
+// Tells whoever added it which thread it was started on.
+struct ThreadProbe(mpsc::Sender<ThreadId>);
 
-        rx.run(sample_count, &rx_ports);
-        tx.run(sample_count, &tx_ports);
+impl Source for ThreadProbe {
+    fn start(&mut self, _: &mut Context) -> bool {
+        let _ = self.0.send(thread::current().id());
+        false
     }
 
-    // reset state
-    rx.deactivate();
-    tx.deactivate();
+    fn ready(&mut self, _: &mut Context, _: EventSet) -> bool {
+        false
+    }
+}
+
+// A base port that puts the first `channels` channels of session 0 on ports the OS has just
+// handed out as free.
+pub fn free_base_port(channels: u16) -> u16 {
+    let ports: Vec<(u16, u16)> = (0..channels).map(|channel| (0, channel)).collect();
+    free_base_port_for(&ports)
+}
+
+// Like `free_base_port`, but for the given sessions and channels. The base is one the config
+// would take from a file, too.
+fn free_base_port_for(ports: &[(u16, u16)]) -> u16 {
+    let max = 65536 - (SESSIONS * CHANNELS) as u32;
+    for _ in 0..1000 {
+        let base = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        if base as u32 > max {
+            continue;
+        }
+        let free = |&(session, channel): &(u16, u16)| {
+            TcpListener::bind(("127.0.0.1", base + session * CHANNELS + channel)).is_ok()
+        };
+        if ports.iter().all(free) {
+            return base;
+        }
+    }
+    panic!("can't find free ports for {:?}", ports);
+}
+
+// Checks `done` every few milliseconds until it's true, for up to five seconds. Returns whether
+// it came true.
+fn wait_until<F: FnMut() -> bool>(mut done: F) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    true
+}
+
+fn test_sample_count(sample_count: usize) {
+    test_block_size(sample_count, BUFFER_SIZE);
+}
+
+fn test_block_size(sample_count: usize, block_size: usize) {
+    assert!(link(sample_count, block_size, (0, 0)));
+}
+
+// Sends a block from a transmitter to a receiver over the simulated network, and checks whether
+// it came out the other side.
+fn link(sample_count: usize, block_size: usize, sessions: (u16, u16)) -> bool {
+    heard(sample_count, block_size, sessions) == vec![1.0; sample_count * 2]
+}
+
+// Like `link`, but returns what the receiver played, left then right.
+fn heard(sample_count: usize, block_size: usize, sessions: (u16, u16)) -> Vec<f32> {
+    let network = SimNetwork::new(Conditions::default(), 0);
+    let mut config = Config::default();
+    config.send_queue = sample_count + 1;
+    let mut tx = FeedbackSender::with_transport(config.clone(),
+                                                SAMPLE_RATE,
+                                                Arc::new(network.clone()));
+    let mut rx = FeedbackReceiver::with_transport(config, SAMPLE_RATE, Arc::new(network.clone()));
+    rx.start();
+    tx.start();
+    rx.set_channel(0, sessions.1);
+    tx.set_channel(0, sessions.0);
+    tx.set_block_size(block_size);
+
+    let input = vec![1.0; sample_count];
+    tx.push(&input, &input);
+    network.advance(0);
+    let mut outputl = vec![0.0; sample_count];
+    let mut outputr = vec![0.0; sample_count];
+    rx.pull(&mut outputl, &mut outputr);
+    outputl.extend(outputr);
+    outputl
+}
+
+// Like `link`, but through the host and real sockets on `port`.
+#[cfg(feature = "ladspa")]
+fn link_tcp(sample_count: usize, port: u8, block_size: usize, sessions: (u16, u16)) -> bool {
+    heard_tcp(sample_count, port, block_size, sessions) == vec![1.0; sample_count * 2]
+}
+
+// Like `heard`, but through the host and real sockets on `port`.
+#[cfg(feature = "ladspa")]
+fn heard_tcp(sample_count: usize,
+             port: u8,
+             block_size: usize,
+             sessions: (u16, u16))
+             -> Vec<f32> {
+    let registry = Registry::open(&plugin_config()).unwrap();
+    let library = Library::linked();
+    let mut tx = library.plugin("feedback_tx").unwrap().instantiate(SAMPLE_RATE).unwrap();
+    let mut rx = library.plugin("feedback_rx").unwrap().instantiate(SAMPLE_RATE).unwrap();
+
+    tx.reserve(sample_count);
+    rx.reserve(sample_count);
+    set_up(&mut tx, port, 1.0, &[("Send", 1.0),
+                                  ("Block Size", block_size as f32),
+                                  ("Session", sessions.0 as f32)]);
+    set_up(&mut rx, port, 0.0, &[("Recv", 1.0), ("Session", sessions.1 as f32)]);
+
+    // the receiver moves to its channel on its first run, and the transmitter's block is lost
+    // if it gets there before the receiver is listening
     rx.activate();
-    thread::sleep(Duration::from_millis(100));
+    rx.run(sample_count);
+    assert!(wait_until(|| !registry.receivers(sessions.1, port as u16).is_empty()));
     tx.activate();
-    thread::sleep(Duration::from_millis(100));
+    tx.run(sample_count);
 
-    tx_owned.set_tags(port as f32, 1.0, 0.0);
-    rx_owned.set_tags(port as f32, 0.0, 0.0);
+    // anything that's going to be heard arrives as a whole block
+    let outputs = rx.audio_outputs();
+    wait_until(|| {
+        rx.run(sample_count);
+        rx.buffer(outputs[0])[..sample_count].iter().any(|&x| x != 0.0)
+    });
+    let mut heard = rx.buffer(outputs[0])[..sample_count].to_vec();
+    heard.extend(&rx.buffer(outputs[1])[..sample_count]);
+    heard
+}
 
-    // run again to do the computation
-    {
-        let tx_ports = make_port_connections(&mut tx_owned);
-        let rx_ports = make_port_connections(&mut rx_owned);
-        let tx_ports = borrow_port_connections(&tx_ports);
-        let rx_ports = borrow_port_connections(&rx_ports);
+// The plugins read their settings from the environment, so every test that runs them shares
+// them: a base port that leaves the channels the tests use free, and a registry of their own
+// rather than the user's.
+#[cfg(feature = "ladspa")]
+fn plugin_config() -> Config {
+    PLUGINS.call_once(|| {
+        let base_port = free_base_port_for(&[(0, 4), (0, 200), (1, 5), (2, 5), (3, 5)]);
+        env::set_var("FEEDBACK_BASE_PORT", base_port.to_string());
+        let dir = env::temp_dir().join(format!("feedback-test-plugins-{}", tap::now()));
+        env::set_var("FEEDBACK_REGISTRY_DIR", dir);
+    });
+    Config::load()
+}
 
-        tx.run(sample_count, &tx_ports);
-        thread::sleep(Duration::from_millis(100)); // wait for recv
-        rx.run(sample_count, &rx_ports);
+// Fills the inputs with `input` and clears the outputs, puts the plugin on `channel` with its dry
+// signal at full, and sets any other controls given.
+#[cfg(feature = "ladspa")]
+fn set_up(instance: &mut Instance, channel: u8, input: f32, controls: &[(&str, f32)]) {
+    for port in instance.audio_inputs() {
+        for x in instance.buffer_mut(port) {
+            *x = input;
+        }
     }
-
-    for i in 0..2 {
-        assert_eq!(tx_owned[i].data, rx_owned[i + 2].data);
+    for port in instance.audio_outputs() {
+        for x in instance.buffer_mut(port) {
+            *x = 0.0;
+        }
+    }
+    instance.set_control("Channel", channel as f32).unwrap();
+    instance.set_control("Dry", 1.0).unwrap();
+    for &(name, value) in controls {
+        instance.set_control(name, value).unwrap();
     }
-
-    rx.deactivate();
-    tx.deactivate();
 }
@@ -1,118 +1,131 @@
-use std::thread;
-use std::sync::mpsc::{self, sync_channel};
-use std::io::{Write, ErrorKind};
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, sync_channel, TryRecvError};
+use std::net::SocketAddr;
+use std::time::SystemTime;
 
-use mio::*;
-use mio::tcp::{TcpStream, Shutdown};
+use mio::EventSet;
+use mio::tcp::TcpStream;
 
-use ladspa::{PluginDescriptor, Plugin, PortConnection, Data};
-use ladspa::{Port, PortDescriptor};
-use ladspa::{PROP_NONE, HINT_INTEGER, DefaultValue};
+use super::packet::{Frame, FrameWriter, Negotiation, Packet, MAX_BLOCK};
+use super::codec::Encoder;
+use super::config::Config;
+use super::net;
+use super::reactor::{Context, Reactor, Source};
+use super::registry::{self, Entry, Registration, Registry, Role};
+use super::secure::HANDSHAKE_TIMEOUT_MS;
+use super::transport::{Link, Outbox, Tcp, Transport};
 
-use super::BASE_PORT;
-use super::packet::{BUFFER_SIZE, BYTE_BUFFER_SIZE, Packet};
+// how often the latency of the receivers is looked up again
+const REFRESH_MS: u64 = 1000;
 
-const CLIENT: Token = Token(1);
-
-pub struct Transmitter {
+/// Sends audio to the receivers on a channel. This is everything the transmitter plugin does
+/// apart from talking to the host, for programs that want to send audio themselves.
+///
+/// Nothing is sent until `start` is called. Audio pushed after that is cut into blocks, each
+/// stamped with the number of samples pushed before it, so the receiver can put them back
+/// together in order.
+pub struct FeedbackSender {
+    config: Config,
     channel: u16,
-    data_tx: Option<mpsc::SyncSender<Packet>>,
-    notify_tx: Option<Sender<<PacketTransmitter as Handler>::Message>>,
-    lbuffer: Vec<Data>,
-    rbuffer: Vec<Data>,
+    session: u16,
+    sample_rate: u64,
+    gain: f32,
+    block_size: usize,
+    threshold: f32,
+    encoder: Option<Encoder>,
+    // the end of the last block that wasn't silent
+    loud_until: u64,
+    data_tx: Option<mpsc::SyncSender<Frame>>,
+    transport: Arc<Transport>,
+    link: Option<Link>,
+    receiver_latency: Arc<AtomicUsize>,
+    connected: Arc<AtomicBool>,
+    lbuffer: Vec<f32>,
+    rbuffer: Vec<f32>,
+    // interleaved audio is split into these on the way in
+    lscratch: Vec<f32>,
+    rscratch: Vec<f32>,
     time: u64,
 }
 
-impl Transmitter {
-    pub fn new(_: &PluginDescriptor, _: u64) -> Box<Plugin + Send> {
-        Box::new(Transmitter {
+impl FeedbackSender {
+    /// A sender for audio at `sample_rate`, on channel 0 of the configured session to begin with.
+    pub fn new(config: Config, sample_rate: u64) -> FeedbackSender {
+        FeedbackSender::with_transport(config, sample_rate, Arc::new(Tcp::new()))
+    }
+
+    /// A sender that sends over something other than TCP.
+    pub fn with_transport(config: Config,
+                          sample_rate: u64,
+                          transport: Arc<Transport>)
+                          -> FeedbackSender {
+        FeedbackSender {
+            session: config.session,
+            config: config,
             channel: 0,
+            sample_rate: sample_rate,
+            gain: 1.0,
+            block_size: 0,
+            threshold: 0.0,
+            encoder: None,
+            loud_until: 0,
             data_tx: None,
-            notify_tx: None,
+            transport: transport,
+            link: None,
+            receiver_latency: Arc::new(AtomicUsize::new(0)),
+            connected: Arc::new(AtomicBool::new(false)),
             lbuffer: Vec::new(),
             rbuffer: Vec::new(),
+            lscratch: Vec::new(),
+            rscratch: Vec::new(),
             time: 0,
-        })
-    }
-
-    pub fn get_descriptor() -> PluginDescriptor {
-        PluginDescriptor {
-            unique_id: 5877,
-            label: "feedback_tx",
-            properties: PROP_NONE,
-            name: "Feedback Transmitter",
-            maker: "Noah Weninger",
-            copyright: "None",
-            ports: vec![Port {
-                            name: "Left Audio In",
-                            desc: PortDescriptor::AudioInput,
-                            ..Default::default()
-                        },
-                        Port {
-                            name: "Right Audio In",
-                            desc: PortDescriptor::AudioInput,
-                            ..Default::default()
-                        },
-                        Port {
-                            name: "Left Audio Out",
-                            desc: PortDescriptor::AudioOutput,
-                            ..Default::default()
-                        },
-                        Port {
-                            name: "Right Audio Out",
-                            desc: PortDescriptor::AudioOutput,
-                            ..Default::default()
-                        },
-                        Port {
-                            name: "Channel",
-                            desc: PortDescriptor::ControlInput,
-                            hint: Some(HINT_INTEGER),
-                            default: Some(DefaultValue::Value0),
-                            lower_bound: Some(0_f32),
-                            upper_bound: Some(255_f32),
-                        },
-                        Port {
-                            name: "Dry",
-                            desc: PortDescriptor::ControlInput,
-                            hint: None,
-                            default: Some(DefaultValue::Value1),
-                            lower_bound: Some(0_f32),
-                            upper_bound: Some(1_f32),
-                        },
-                        Port {
-                            name: "Send",
-                            desc: PortDescriptor::ControlInput,
-                            hint: None,
-                            default: Some(DefaultValue::Value1),
-                            lower_bound: Some(0_f32),
-                            upper_bound: Some(1_f32),
-                        }],
-            new: Transmitter::new,
         }
     }
 
+    /// Connects to the channel and starts a new stream from time zero. Anything left over from
+    /// before is dropped.
+    pub fn start(&mut self) {
+        log!(Info, "activate {}", self.channel);
+        self.lbuffer.clear();
+        self.rbuffer.clear();
+        self.time = 0;
+        self.loud_until = 0;
+        if let Some(ref mut encoder) = self.encoder {
+            encoder.reset();
+        }
+        self.init_client();
+    }
+
+    /// Hangs up. Audio pushed from now until the next `start` goes nowhere.
+    pub fn stop(&mut self) {
+        log!(Info, "deactivate {}", self.channel);
+        self.kill_client();
+    }
+
     fn init_client(&mut self) {
-        let (data_tx, data_rx) = sync_channel(16);
+        let (data_tx, data_rx) = sync_channel(self.config.send_queue);
         self.data_tx = Some(data_tx);
-
-        let channel = self.channel;
-        let mut event_loop = EventLoop::new().unwrap();
-        self.notify_tx = Some(event_loop.channel());
-        thread::spawn(move || {
-            let addr = format!("127.0.0.1:{}", BASE_PORT + channel).parse().unwrap();
-            let client = TcpStream::connect(&addr).unwrap();
-            client.set_nodelay(true).unwrap();
-            event_loop.register(&client, CLIENT).unwrap();
-            event_loop.run(&mut PacketTransmitter {
-                          socket: client,
-                          data_rx: data_rx,
-                      })
-                      .unwrap();
-        });
+        let entry = Entry::new(&self.config,
+                               Role::Transmitter,
+                               self.channel,
+                               self.session,
+                               self.sample_rate);
+        // a new channel has new receivers, so the last one's latency doesn't count
+        self.receiver_latency.store(0, Ordering::Relaxed);
+        // a fresh flag, so a connection on its way out can't mark this one down
+        self.connected = Arc::new(AtomicBool::new(false));
+        let outbox = Outbox {
+            frames: data_rx,
+            latency: self.receiver_latency.clone(),
+            connected: self.connected.clone(),
+        };
+        self.link = Some(self.transport.transmit(&self.config, entry, outbox));
     }
 
     fn kill_client(&mut self) {
-        let _ = self.notify_tx.as_ref().unwrap().send(());
+        self.link = None;
     }
 
     fn restart_client(&mut self) {
@@ -120,129 +133,323 @@ impl Transmitter {
         self.init_client();
     }
 
-    fn set_channel(&mut self, channel: u16) {
-        if channel != self.channel {
+    /// Moves to another channel and session, reconnecting if the sender has been started.
+    pub fn set_channel(&mut self, channel: u16, session: u16) {
+        if channel != self.channel || session != self.session {
             self.channel = channel;
-            println!("set channel {}", self.channel);
-            self.restart_client();
+            self.session = session;
+            log!(Info, "set channel {} session {}", self.channel, self.session);
+            if self.link.is_some() {
+                self.restart_client();
+            }
             return;
         }
     }
-}
 
-impl Plugin for Transmitter {
-    fn run<'a>(&mut self, sample_count: usize, ports: &[&'a PortConnection<'a>]) {
-        let inputl = ports[0].unwrap_audio();
-        let inputr = ports[1].unwrap_audio();
-        let mut outputl = ports[2].unwrap_audio_mut();
-        let mut outputr = ports[3].unwrap_audio_mut();
+    /// Scales everything sent by `gain`. It starts at 1.
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+    }
+
+    /// Sends blocks of this many samples, up to the configured `stream.block_size`. Zero, the
+    /// default, sends each push as one block, or in blocks of `MAX_BLOCK` if it's longer.
+    pub fn set_block_size(&mut self, block_size: usize) {
+        self.block_size = block_size;
+    }
 
-        let channel = *ports[4].unwrap_control() as u16;
-        let dry = ports[5].unwrap_control();
-        let wet = ports[6].unwrap_control();
+    /// Sends blocks no louder than `db` decibels as silence, which takes a few bytes instead of
+    /// a block's worth. By default only blocks of nothing but zeroes are.
+    pub fn set_silence_threshold(&mut self, db: f32) {
+        self.threshold = (10_f32).powf(db / 20.0);
+    }
 
-        self.set_channel(channel);
+    /// Turns the lossy codec on at `bitrate` kbps, or off.
+    pub fn set_codec(&mut self, enabled: bool, bitrate: u32) {
+        if !enabled {
+            self.encoder = None;
+            return;
+        }
+        if self.encoder.is_none() {
+            let encoder = Encoder::new(self.sample_rate, bitrate);
+            log!(Info, "codec enabled, {} samples delay", encoder.delay());
+            self.encoder = Some(encoder);
+        }
+        let encoder = self.encoder.as_mut().unwrap();
+        if encoder.get_bitrate() != bitrate {
+            encoder.set_bitrate(bitrate);
+            log!(Info, "codec bitrate {} kbps", encoder.get_bitrate());
+        }
+    }
 
-        let mut need_reboot = false;
-        let mut i = 0;
-        while i < sample_count {
-            while self.lbuffer.len() < BUFFER_SIZE && i < sample_count {
-                self.lbuffer.push(inputl[i] * (*wet));
-                self.rbuffer.push(inputr[i] * (*wet));
+    /// How many samples behind this transmitter the receivers on its channel are playing, as far
+    /// as the transport can tell. This is the latency each receiver settled on once it had played
+    /// for a second. Over TCP it comes from the registry, so only receivers on this machine are
+    /// counted, and it's zero until one has been found.
+    pub fn receiver_latency(&self) -> usize {
+        self.receiver_latency.load(Ordering::Relaxed)
+    }
 
-                outputl[i] = inputl[i] * (*dry);
-                outputr[i] = inputr[i] * (*dry);
+    /// Whether the sender is started and a receiver has let it in, so what's pushed gets there.
+    pub fn connected(&self) -> bool {
+        self.link.is_some() && self.connected.load(Ordering::Relaxed)
+    }
+
+    /// How many samples the codec holds back, or zero with it off.
+    pub fn codec_delay(&self) -> usize {
+        self.encoder.as_ref().map(|e| e.delay()).unwrap_or(0)
+    }
+
+    fn encode(&mut self, packet: Packet) -> Option<Frame> {
+        let silent = packet.peak() <= self.threshold;
+        if !silent {
+            self.loud_until = packet.get_timestamp() + packet.len() as u64;
+        }
+        let silence = Frame::Silent {
+            timestamp: packet.get_timestamp(),
+            length: packet.len() as u32,
+        };
+        match self.encoder {
+            // silent blocks still go through the codec until the last loud one has all come out
+            // of it, which can take several small blocks
+            Some(ref mut encoder) if silent && encoder.finished() >= self.loud_until => {
+                encoder.reset();
+                Some(silence)
+            }
+            Some(ref mut encoder) => {
+                // small blocks may not fill a whole codec frame
+                let coded = encoder.encode(&packet);
+                if coded.len() > 0 {
+                    Some(Frame::Coded(coded))
+                } else {
+                    None
+                }
+            }
+            None if silent => Some(silence),
+            None => Some(Frame::Raw(packet)),
+        }
+    }
 
+    /// Sends a left and right channel. If one is longer, only as much as the shorter one has is
+    /// sent. Does nothing unless started.
+    pub fn push(&mut self, left: &[f32], right: &[f32]) {
+        let len = left.len().min(right.len());
+        let (left, right) = (&left[..len], &right[..len]);
+        if self.link.is_none() {
+            return;
+        }
+        let block_size = match self.block_size {
+            0 => left.len().min(MAX_BLOCK),
+            n => n.min(self.config.block_size),
+        };
+        let mut need_reboot = false;
+        let mut i = 0;
+        while i < left.len() {
+            while self.lbuffer.len() < block_size && i < left.len() {
+                self.lbuffer.push(left[i] * self.gain);
+                self.rbuffer.push(right[i] * self.gain);
                 i += 1;
             }
 
-            if self.lbuffer.len() == BUFFER_SIZE {
+            if self.lbuffer.len() >= block_size && block_size > 0 {
                 let packet = Packet::new(&self.lbuffer, &self.rbuffer, self.time);
-                self.time += BUFFER_SIZE as u64;
+                self.time += self.lbuffer.len() as u64;
 
-                need_reboot |= self.data_tx.as_ref().unwrap().send(packet).is_err();
+                if let Some(frame) = self.encode(packet) {
+                    need_reboot |= self.data_tx.as_ref().unwrap().send(frame).is_err();
+                    self.link.as_ref().unwrap().wake();
+                }
 
                 self.lbuffer.clear();
                 self.rbuffer.clear();
             }
         }
         if need_reboot {
-            println!("transmit failed, rebooting");
+            log!(Warn, "transmit failed, rebooting");
             self.restart_client();
         }
     }
 
-    fn activate(&mut self) {
-        println!("activate {}", self.channel);
-        self.lbuffer.clear();
-        self.rbuffer.clear();
-        self.time = 0;
-        self.init_client();
+    /// Sends interleaved stereo, left first.
+    pub fn push_interleaved(&mut self, samples: &[f32]) {
+        let mut left = mem::replace(&mut self.lscratch, Vec::new());
+        let mut right = mem::replace(&mut self.rscratch, Vec::new());
+        left.clear();
+        right.clear();
+        for frame in samples.chunks(2) {
+            left.push(frame[0]);
+            right.push(frame.get(1).map(|&x| x).unwrap_or(0.0));
+        }
+        self.push(&left, &right);
+        self.lscratch = left;
+        self.rscratch = right;
     }
+}
 
-    fn deactivate(&mut self) {
-        println!("deactivate {}", self.channel);
-        self.kill_client();
+/// Warns about problems the registry can spot before any audio is sent, and notes how far behind
+/// the receivers are running. Receivers on other machines don't show up here, so finding none is
+/// not an error.
+fn check_receivers(registry: &Registry, entry: &Entry, latency: &AtomicUsize) {
+    let receivers = registry.receivers(entry.session, entry.channel);
+    store_latency(&receivers, latency);
+    if receivers.is_empty() {
+        log!(Debug, "no local receiver on channel {} session {}", entry.channel, entry.session);
+    }
+    for receiver in receivers {
+        if receiver.sample_rate != entry.sample_rate {
+            log!(Warn,
+                 "receiver {} runs at {} Hz but this transmitter at {} Hz",
+                 receiver.pid,
+                 receiver.sample_rate,
+                 entry.sample_rate);
+        }
+    }
+}
+
+// Sets `latency` to the most any of the receivers is running behind.
+fn store_latency(receivers: &[Entry], latency: &AtomicUsize) {
+    if let Some(max) = receivers.iter().filter_map(|receiver| receiver.latency).max() {
+        latency.store(max, Ordering::Relaxed);
     }
 }
 
-struct PacketTransmitter {
-    socket: TcpStream,
-    data_rx: mpsc::Receiver<Packet>,
+/// Sends frames to the receiver `entry` points at over TCP, and keeps the transmitter in the
+/// registry while it does. The receiver's latency is looked up there too. The registry is only
+/// read and written on the reactor's thread, never on the one pushing audio.
+pub fn connect(reactor: &Arc<Reactor>, config: &Config, entry: Entry, outbox: Outbox) -> Link {
+    let reactor = reactor.clone();
+    let token = reactor.add(Box::new(Connection {
+        config: config.clone(),
+        entry: entry,
+        socket: None,
+        negotiation: None,
+        writer: None,
+        frames: outbox.frames,
+        pending: Vec::new(),
+        written: 0,
+        latency: outbox.latency,
+        connected: outbox.connected,
+        registry: None,
+        modified: None,
+        registration: None,
+    }));
+    let waker = reactor.waker(token);
+    Link::with_wake(Box::new(move || reactor.remove(token)),
+                    Box::new(move || waker.wake()))
+}
+
+/// A transmitter's connection to its receiver, run by the reactor. It's woken whenever a frame
+/// is queued.
+struct Connection {
+    config: Config,
+    entry: Entry,
+    socket: Option<TcpStream>,
+    negotiation: Option<Negotiation>,
+    writer: Option<FrameWriter>,
+    frames: mpsc::Receiver<Frame>,
+    // the frame being written, and how much of it has gone. The buffer is kept between frames.
+    pending: Vec<u8>,
+    written: usize,
+    latency: Arc<AtomicUsize>,
+    connected: Arc<AtomicBool>,
+    // opened once, and only read again when it has changed since it was last read
+    registry: Option<Registry>,
+    modified: Option<SystemTime>,
+    registration: Option<Registration>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.connected.store(false, Ordering::Relaxed);
+    }
 }
 
-impl Handler for PacketTransmitter {
-    type Timeout = ();
-    type Message = ();
-
-    fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token, events: EventSet) {
-        match token {
-            CLIENT => {
-                println!("client accept");
-                'outer: loop {
-                    assert!(events.is_writable());
-                    let packet = match self.data_rx.recv() {
-                        Ok(p) => p,
-                        Err(_) => {
-                            println!("err recieving packet from ladspa, channel is dead!");
-                            event_loop.shutdown();
-                            break;
-                        }
-                    };
-                    let mut write_offset = 0;
-                    loop {
-                        match self.socket.write(&packet.as_bytes()[write_offset..]) {
-                            Ok(num_written) => {
-                                // println!("client wrote {}", num_written);
-                                write_offset += num_written;
-                                assert!(write_offset <= BYTE_BUFFER_SIZE);
-                                if write_offset == BYTE_BUFFER_SIZE {
-                                    break;
-                                }
-                                if num_written == 0 {
-                                    println!("wrote zero bytes");
-                                    event_loop.shutdown();
-                                    break 'outer;
-                                }
-                            }
-                            Err(e) => {
-                                if e.kind() == ErrorKind::WouldBlock {
-                                    continue;
-                                }
-                                println!("error writing to socket: {}", e);
-                                event_loop.shutdown();
-                                break 'outer;
-                            }
-                        }
-                    }
+impl Source for Connection {
+    fn start(&mut self, ctx: &mut Context) -> bool {
+        self.registration = registry::announce(&self.config, &self.entry);
+        self.registry = Registry::open(&self.config);
+        if let Some(ref registry) = self.registry {
+            self.modified = registry.modified();
+            check_receivers(registry, &self.entry, &self.latency);
+        }
+        let port = self.config.port(self.entry.session, self.entry.channel);
+        let addr = SocketAddr::new(self.config.host, port);
+        let res = TcpStream::connect(&addr).and_then(|socket| {
+            try!(socket.set_nodelay(true));
+            try!(ctx.register(&socket));
+            Ok(socket)
+        });
+        match res.and_then(|socket| Negotiation::initiate(&self.config).map(|n| (socket, n))) {
+            Ok((socket, negotiation)) => {
+                self.socket = Some(socket);
+                self.negotiation = Some(negotiation);
+            }
+            Err(e) => {
+                log!(Warn, "can't connect to {}: {}", addr, e);
+                return false;
+            }
+        }
+        ctx.timeout_ms(HANDSHAKE_TIMEOUT_MS);
+        self.ready(ctx, EventSet::none())
+    }
+
+    fn ready(&mut self, _: &mut Context, events: EventSet) -> bool {
+        if events.is_error() || events.is_hup() {
+            log!(Info, "receiver hung up");
+            return false;
+        }
+        let socket = self.socket.as_mut().unwrap();
+        if self.writer.is_none() {
+            match self.negotiation.as_mut().unwrap().advance(socket) {
+                Ok(Some(protection)) => {
+                    self.writer = Some(FrameWriter::new(protection));
+                    self.connected.store(true, Ordering::Relaxed);
+                }
+                Ok(None) => return true,
+                Err(e) => {
+                    log!(Warn, "handshake with receiver failed: {}", e);
+                    return false;
+                }
+            }
+        }
+        loop {
+            match net::write_some(socket, &self.pending, &mut self.written) {
+                Ok(true) => {}
+                Ok(false) => return true,
+                Err(e) => {
+                    log!(Warn, "error writing to socket: {}", e);
+                    return false;
+                }
+            }
+            match self.frames.try_recv() {
+                Ok(frame) => {
+                    self.pending.clear();
+                    self.writer.as_mut().unwrap().write_into(&frame, &mut self.pending);
+                    self.written = 0;
+                }
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => {
+                    log!(Debug, "transmitter hung up");
+                    return false;
                 }
             }
-            _ => panic!("Received unknown token"),
         }
     }
 
-    fn notify(&mut self, event_loop: &mut EventLoop<Self>, _: Self::Message) {
-        let _ = self.socket.shutdown(Shutdown::Both);
-        event_loop.shutdown();
+    // The first timeout is for the handshake, and the ones after keep the latency up to date.
+    fn timeout(&mut self, ctx: &mut Context) -> bool {
+        if self.writer.is_none() {
+            log!(Warn, "handshake with receiver timed out");
+            return false;
+        }
+        if let Some(ref registry) = self.registry {
+            let modified = registry.modified();
+            if modified.is_none() || modified != self.modified {
+                self.modified = modified;
+                store_latency(&registry.receivers(self.entry.session, self.entry.channel),
+                              &self.latency);
+            }
+        }
+        ctx.timeout_ms(REFRESH_MS);
+        true
     }
 }
@@ -0,0 +1,114 @@
+// How frames get from transmitters to receivers. Plugins use TCP, but anything that can carry
+// frames to the receiver on the same session and channel will do, like the simulated network
+// tests run on.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::mpsc::{Receiver, SyncSender};
+
+use super::access::Gate;
+use super::config::Config;
+use super::mixer::Tracks;
+use super::packet::Frame;
+use super::reactor::{self, Hold};
+use super::receive::{self, ClientPacket};
+use super::registry::Entry;
+use super::transmit;
+
+/// Where a receiver's transport delivers audio, and the state it shares with the plugin.
+pub struct Inbox {
+    /// Decoded packets, tagged with the client they came from. When this is full, packets are
+    /// dropped rather than holding up the connection.
+    pub packets: SyncSender<ClientPacket>,
+    /// Every client must be let in through here, and keep its pass while connected.
+    pub gate: Gate,
+    /// Counts clients hung up on for sending something that couldn't be read.
+    pub rejected: Arc<AtomicUsize>,
+    /// The latency the receiver has settled on in samples, for transmitters to see. It's zero
+    /// until the receiver has played for long enough to settle on one.
+    pub latency: Arc<AtomicUsize>,
+    /// How many packets the mix had no room for, kept up to date by the plugin to be reported.
+    pub dropped: Arc<AtomicUsize>,
+    /// Makes the mix's tracks. Once a client has been let in, tracks are provided for as many as
+    /// the gate holds.
+    pub tracks: Tracks,
+}
+
+/// Where a transmitter's transport takes frames from, and the state it shares with the plugin.
+pub struct Outbox {
+    pub frames: Receiver<Frame>,
+    /// Set to the latency of the receivers being sent to, if the transport can find it out.
+    pub latency: Arc<AtomicUsize>,
+    /// Set while a receiver has let the transmitter in and frames can go to it.
+    pub connected: Arc<AtomicBool>,
+}
+
+pub trait Transport: Send + Sync {
+    /// Connects to the receiver `entry` is sending to, and sends it each frame from
+    /// `outbox.frames`. The link is woken after each frame is queued. The connection is dropped,
+    /// hanging up the frames, if it fails.
+    fn transmit(&self, config: &Config, entry: Entry, outbox: Outbox) -> Link;
+
+    /// Takes connections for the receiver `entry` describes, and delivers what they send to the
+    /// inbox. Hangs up `inbox.packets` if it stops by itself.
+    fn receive(&self, config: &Config, entry: Entry, inbox: Inbox) -> Link;
+}
+
+/// Keeps a connection or listener going. Dropping it stops them.
+pub struct Link {
+    stop: Option<Box<FnMut() + Send>>,
+    wake: Option<Box<Fn() + Send>>,
+}
+
+impl Link {
+    pub fn new(stop: Box<FnMut() + Send>) -> Link {
+        Link {
+            stop: Some(stop),
+            wake: None,
+        }
+    }
+
+    /// A link that calls `wake` whenever there's something new for it to send.
+    pub fn with_wake(stop: Box<FnMut() + Send>, wake: Box<Fn() + Send>) -> Link {
+        Link {
+            stop: Some(stop),
+            wake: Some(wake),
+        }
+    }
+
+    pub fn wake(&self) {
+        if let Some(ref wake) = self.wake {
+            wake();
+        }
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        if let Some(mut stop) = self.stop.take() {
+            stop();
+        }
+    }
+}
+
+/// The real network. Each channel is a TCP port, and plugins show up in the registry. Its
+/// sockets are run by the reactor, which it keeps going for as long as it's around.
+pub struct Tcp {
+    hold: Hold,
+}
+
+impl Tcp {
+    pub fn new() -> Tcp {
+        Tcp { hold: reactor::hold() }
+    }
+}
+
+impl Transport for Tcp {
+    fn transmit(&self, config: &Config, entry: Entry, outbox: Outbox) -> Link {
+        transmit::connect(self.hold.reactor(), config, entry, outbox)
+    }
+
+    fn receive(&self, config: &Config, entry: Entry, inbox: Inbox) -> Link {
+        receive::listen(self.hold.reactor(), config, entry, inbox)
+    }
+}
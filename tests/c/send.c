@@ -0,0 +1,60 @@
+/* Sends a ramp on the channel given, through the C API, for tests/capi.rs to receive. Even
+ * blocks go out as separate channels and odd ones interleaved. */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <time.h>
+
+#include "feedback.h"
+
+#define BLOCK 256
+#define BLOCKS 100
+
+static float level(int sample) {
+    return sample / 100000.0f;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 2) {
+        fprintf(stderr, "usage: send CHANNEL\n");
+        return 2;
+    }
+    if (feedback_sender_open(256, 0, 44100) != NULL ||
+        feedback_sender_send(NULL, NULL, NULL, 0) != -1 ||
+        feedback_sender_connected(NULL) != -1) {
+        fprintf(stderr, "bad arguments were accepted\n");
+        return 1;
+    }
+    FeedbackSender *sender = feedback_sender_open(atoi(argv[1]), 0, 44100);
+    if (sender == NULL) {
+        fprintf(stderr, "can't open a sender on channel %s\n", argv[1]);
+        return 1;
+    }
+
+    struct timespec pause = { 0, 5000000 };
+    for (int tries = 0; feedback_sender_connected(sender) != 1; tries++) {
+        if (tries == 400) {
+            fprintf(stderr, "never got through to the receiver\n");
+            return 1;
+        }
+        nanosleep(&pause, NULL);
+    }
+    for (int block = 0; block < BLOCKS; block++) {
+        float left[BLOCK], right[BLOCK], interleaved[BLOCK * 2];
+        for (int i = 0; i < BLOCK; i++) {
+            left[i] = level(block * BLOCK + i);
+            right[i] = -left[i];
+            interleaved[i * 2] = left[i];
+            interleaved[i * 2 + 1] = right[i];
+        }
+        int result = block % 2 == 0 ? feedback_sender_send(sender, left, right, BLOCK)
+                                    : feedback_sender_send_interleaved(sender, interleaved, BLOCK);
+        if (result != 0) {
+            fprintf(stderr, "sending block %d failed\n", block);
+            return 1;
+        }
+        nanosleep(&pause, NULL);
+    }
+    feedback_sender_close(sender);
+    return 0;
+}
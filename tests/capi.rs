@@ -0,0 +1,80 @@
+// Builds tests/c/send.c against the C API and the header, and checks that what it sends arrives
+// at a receiver in this process over loopback.
+
+extern crate feedback;
+
+mod common;
+
+use std::env;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use feedback::FeedbackReceiver;
+use feedback::config::Config;
+
+const CHANNEL: u16 = 60;
+const BLOCK: usize = 256;
+
+#[test]
+fn test_capi_send() {
+    let base_port = common::free_base_port(CHANNEL..CHANNEL + 1);
+    env::set_var("FEEDBACK_BASE_PORT", base_port.to_string());
+    env::set_var("FEEDBACK_KEY", "capi tests");
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let library = common::library_path();
+    let lib = library.parent().unwrap();
+    let program = env::current_exe().unwrap().with_file_name("capi-send");
+    let status = match Command::new("cc")
+                     .arg("-std=c99")
+                     .arg("-Wall")
+                     .arg("-Werror")
+                     .arg("-D_POSIX_C_SOURCE=199309L")
+                     .arg("-I")
+                     .arg(root.join("include"))
+                     .arg(root.join("tests/c/send.c"))
+                     .arg("-L")
+                     .arg(lib)
+                     .arg(format!("-Wl,-rpath,{}", lib.display()))
+                     .arg("-lfeedback")
+                     .arg("-o")
+                     .arg(&program)
+                     .status() {
+        Ok(status) => status,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            println!("skipping test_capi_send: there's no C compiler, cc");
+            return;
+        }
+        Err(e) => panic!("can't run cc: {}", e),
+    };
+    assert!(status.success());
+
+    let mut receiver = FeedbackReceiver::new(Config::load(), 44100);
+    receiver.set_channel(CHANNEL, 0);
+    receiver.start();
+    thread::sleep(Duration::from_millis(200));
+    let mut sender = Command::new(&program).arg(CHANNEL.to_string()).spawn().unwrap();
+
+    // the first few blocks, whichever way they were sent, come out as they went in
+    let mut left = [0.0; BLOCK];
+    let mut right = [0.0; BLOCK];
+    let mut block = 0;
+    for _ in 0..300 {
+        if block == 4 {
+            break;
+        }
+        if !receiver.pull(&mut left, &mut right) {
+            thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+        for i in 0..BLOCK {
+            let level = (block * BLOCK + i) as f32 / 100000.0;
+            assert_eq!((left[i], right[i]), (level, -level));
+        }
+        block += 1;
+    }
+    assert_eq!(block, 4);
+    assert!(sender.wait().unwrap().success());
+}
@@ -0,0 +1,39 @@
+// Helpers shared by the integration tests.
+
+use std::env;
+use std::net::TcpListener;
+use std::ops::Range;
+use std::path::PathBuf;
+
+// Config won't take a base port any higher, since every session and channel needs one above it
+const MAX_BASE_PORT: u32 = 65536 - 64 * 256;
+
+/// A base port that puts `channels` of session 0 on ports the OS has just handed out as free,
+/// rather than on a fixed range another run of the tests may be using.
+pub fn free_base_port(channels: Range<u16>) -> u16 {
+    for _ in 0..1000 {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        if port < channels.start || (port - channels.start) as u32 > MAX_BASE_PORT {
+            continue;
+        }
+        let base = port - channels.start;
+        let free = |channel| TcpListener::bind(("127.0.0.1", base + channel)).is_ok();
+        if channels.clone().all(free) {
+            return base;
+        }
+    }
+    panic!("can't find free ports for channels {:?}", channels);
+}
+
+/// Where the plugin library was built. Tests run from target/<profile>/deps, where `cargo test`
+/// builds the library too, but only `cargo build` copies it up into target/<profile>.
+pub fn library_path() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    let deps = exe.parent().unwrap();
+    let path = deps.join("libfeedback.so");
+    if path.exists() {
+        path
+    } else {
+        deps.parent().unwrap().join("libfeedback.so")
+    }
+}
@@ -0,0 +1,190 @@
+// Runs a transmitter into a receiver through the fault-injecting proxy, and checks that they ride
+// out each kind of trouble. The plugins are loaded from the built library through the host, so
+// this is the same socket code a DAW would run. The proxy's faults are timed by the clock, so the
+// tests run in real time; the simulated network covers the same ground without waiting.
+
+extern crate feedback;
+
+mod common;
+
+use std::env;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::{Once, ONCE_INIT};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use feedback::config::Config;
+use feedback::host::{Instance, Library};
+use feedback::proxy::{Fault, Proxy};
+
+const SAMPLE_RATE: u64 = 44100;
+// 10 ms
+const BLOCK: usize = 441;
+// the channels the tests use
+const CHANNELS: Range<u16> = 40..54;
+
+static SETUP: Once = ONCE_INIT;
+
+// Every plugin in these tests runs with the same settings. Frames are signed, so any damage to
+// them is noticed.
+fn setup() -> Config {
+    SETUP.call_once(|| {
+        env::set_var("FEEDBACK_BASE_PORT", common::free_base_port(CHANNELS).to_string());
+        env::set_var("FEEDBACK_KEY", "proxy tests");
+    });
+    Config::load()
+}
+
+struct Link {
+    tx: Instance,
+    rx: Instance,
+    proxy: Proxy,
+}
+
+// A transmitter on channel `from` and a receiver on `to`, with the proxy in between.
+fn link(from: u16, to: u16) -> Link {
+    let config = setup();
+    let proxy = Proxy::start(&SocketAddr::new(config.bind, config.port(0, from)),
+                             &SocketAddr::new(config.host, config.port(0, to)))
+                    .unwrap();
+    let library = Library::open(&common::library_path()).unwrap();
+    let mut rx = library.plugin("feedback_rx").unwrap().instantiate(SAMPLE_RATE).unwrap();
+    let mut tx = library.plugin("feedback_tx").unwrap().instantiate(SAMPLE_RATE).unwrap();
+    rx.set_control("Channel", to as f32).unwrap();
+    tx.set_control("Channel", from as f32).unwrap();
+    rx.set_control("Dry", 0.0).unwrap();
+    // the first run moves the plugins onto their channels, then they start over there
+    for instance in &mut [&mut rx, &mut tx] {
+        instance.activate();
+        instance.run(BLOCK);
+        instance.deactivate();
+        instance.activate();
+    }
+    // the proxy only counts a connection once it has got through to the receiver
+    let start = Instant::now();
+    while proxy.connections() == 0 {
+        assert!(start.elapsed() < Duration::from_secs(5), "the transmitter never connected");
+        thread::sleep(Duration::from_millis(1));
+    }
+    Link {
+        tx: tx,
+        rx: rx,
+        proxy: proxy,
+    }
+}
+
+impl Link {
+    // Plays a tone through for this many blocks in real time, and returns whether each block was
+    // heard at the other end.
+    fn play(&mut self, blocks: usize) -> Vec<bool> {
+        let start = Instant::now();
+        (0..blocks)
+            .map(|i| {
+                for port in self.tx.audio_inputs() {
+                    for x in self.tx.buffer_mut(port) {
+                        *x = 0.5;
+                    }
+                }
+                self.tx.run(BLOCK);
+                self.rx.run(BLOCK);
+                let due = start + Duration::from_millis(10 * (i as u64 + 1));
+                let now = Instant::now();
+                if due > now {
+                    thread::sleep(due - now);
+                }
+                let out = self.rx.audio_outputs()[0];
+                self.rx.buffer(out)[..BLOCK].iter().any(|&x| x != 0.0)
+            })
+            .collect()
+    }
+
+    fn underruns(&self) -> f32 {
+        self.rx.control("Underruns").unwrap()
+    }
+
+    fn rejected(&self) -> f32 {
+        self.rx.control("Rejected Frames").unwrap()
+    }
+}
+
+// most of the end of a run should have got through
+fn recovered(heard: &[bool]) -> bool {
+    heard[heard.len() - 20..].iter().filter(|&&heard| heard).count() >= 15
+}
+
+#[test]
+fn test_proxy_clean() {
+    let mut link = link(40, 41);
+    let heard = link.play(100);
+    assert!(recovered(&heard));
+    assert!(link.proxy.forwarded() > 0);
+}
+
+#[test]
+fn test_proxy_delay() {
+    let mut link = link(42, 43);
+    link.proxy.inject(&Fault::Delay(Duration::from_millis(300)));
+    let heard = link.play(100);
+    // nothing arrives until the delay has passed
+    assert!(heard[..20].iter().all(|&heard| !heard));
+    assert!(recovered(&heard));
+}
+
+#[test]
+fn test_proxy_bandwidth() {
+    let mut link = link(44, 45);
+    link.play(30);
+    let before = link.underruns();
+    // a third of what a stereo stream at 44.1 kHz needs
+    link.proxy.inject(&Fault::Bandwidth(120000));
+    link.play(100);
+    assert!(link.underruns() - before >= 30.0);
+    link.proxy.inject(&Fault::Bandwidth(0));
+}
+
+#[test]
+fn test_proxy_stall() {
+    let mut link = link(46, 47);
+    link.play(30);
+    let before = link.underruns();
+    link.proxy.inject(&Fault::Stall(Duration::from_millis(300)));
+    let heard = link.play(100);
+    assert!(link.underruns() - before >= 10.0);
+    assert!(recovered(&heard));
+}
+
+#[test]
+fn test_proxy_corrupt() {
+    let mut link = link(48, 49);
+    link.play(30);
+    let connections = link.proxy.connections();
+    link.proxy.inject(&Fault::Corrupt(1));
+    let heard = link.play(100);
+    // the receiver hangs up on the damaged stream, and the transmitter comes back
+    assert!(link.rejected() >= 1.0);
+    assert!(link.proxy.connections() > connections);
+    assert!(recovered(&heard));
+}
+
+#[test]
+fn test_proxy_truncate() {
+    let mut link = link(50, 51);
+    link.play(30);
+    let connections = link.proxy.connections();
+    link.proxy.inject(&Fault::Truncate(10));
+    let heard = link.play(100);
+    assert!(link.proxy.connections() > connections);
+    assert!(recovered(&heard));
+}
+
+#[test]
+fn test_proxy_reset() {
+    let mut link = link(52, 53);
+    link.play(30);
+    let connections = link.proxy.connections();
+    link.proxy.inject(&Fault::Reset);
+    let heard = link.play(100);
+    assert!(link.proxy.connections() > connections);
+    assert!(recovered(&heard));
+}